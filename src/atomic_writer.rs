@@ -0,0 +1,256 @@
+// Copyright 2017-2018 Maskerad Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Cursor, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use filesystem_error::{FileSystemError, FileSystemResult, Operation};
+use open_options::OpenOptions;
+use backend::Backend;
+
+/*ATOMIC WRITES.
+
+Filesystem::create truncates the file in place: a crash while writing a save leaves a truncated save.
+
+An atomic write never touches the target before the new content is complete. On the disk, the content
+is written to a temporary file next to the target, synced, and renamed over the target. The rename
+replaces the file in one step, and the directory is synced to make the rename itself durable.
+A crash leaves either the old file or the new one, plus a temporary file at worst.
+The new file gets the permissions of the file it replaces: a save only readable by its owner stays so.
+
+The backends which aren't on the disk receive the whole content in one write, on commit.
+*/
+
+//Distinguishes the temporary files of the writers of a same process.
+static TEMPORARY_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+//A sibling of path, hidden on unix: "saves/slot_1.sav" gives "saves/.slot_1.sav.<pid>.<n>.tmp".
+pub(crate) fn temporary_path(path: &Path) -> PathBuf {
+    let mut file_name = OsString::from(".");
+    file_name.push(path.file_name().unwrap_or_default());
+    file_name.push(format!(
+        ".{}.{}.tmp",
+        process::id(),
+        TEMPORARY_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    path.with_file_name(file_name)
+}
+
+//Make the entries of the directory (a rename, a creation) durable.
+#[cfg(unix)]
+fn sync_directory(directory: &Path) -> io::Result<()> {
+    File::open(directory)?.sync_all()
+}
+
+//Directories can't be opened on windows, and the rename is already durable there.
+#[cfg(not(unix))]
+fn sync_directory(_directory: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+enum AtomicTarget {
+    //A temporary file on the disk, renamed over the target on commit.
+    File {
+        file: BufWriter<File>,
+        temporary_path: PathBuf,
+        path: PathBuf,
+    },
+    //The content, written to the backend in one go on commit.
+    Buffer {
+        backend: Arc<dyn Backend>,
+        path: PathBuf,
+        content: Cursor<Vec<u8>>,
+    },
+}
+
+//Write a file atomically: the target is only replaced on commit, and left untouched if the writer is dropped.
+pub struct AtomicWriter {
+    //None once committed.
+    target: Option<AtomicTarget>,
+}
+
+impl fmt::Debug for AtomicWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.target {
+            Some(AtomicTarget::File { ref temporary_path, ref path, .. }) => f.debug_struct("AtomicWriter")
+                .field("path", path)
+                .field("temporary_path", temporary_path)
+                .finish(),
+            Some(AtomicTarget::Buffer { ref path, ref content, .. }) => f.debug_struct("AtomicWriter")
+                .field("path", path)
+                .field("buffered", &content.get_ref().len())
+                .finish(),
+            None => f.debug_struct("AtomicWriter").finish(),
+        }
+    }
+}
+
+impl AtomicWriter {
+    //Write to a temporary file next to the file at path, on the disk.
+    pub fn new<P: AsRef<Path>>(path: P) -> FileSystemResult<AtomicWriter> {
+        let temporary_path = temporary_path(path.as_ref());
+        trace!("Writing {} atomically, through {}", path.as_ref().display(), temporary_path.display());
+        let file = OpenOptions::new()
+            .set_write(true)
+            .set_create_new(true)
+            .to_fs_openoptions()
+            .open(temporary_path.as_path())
+            .map_err(|error| FileSystemError::io(Operation::Create, &[temporary_path.as_path()], error))?;
+        Ok(AtomicWriter {
+            target: Some(AtomicTarget::File {
+                file: BufWriter::new(file),
+                temporary_path,
+                path: path.as_ref().to_path_buf(),
+            }),
+        })
+    }
+
+    //Buffer the content, and give it to the backend in one write on commit.
+    pub(crate) fn buffered<P: Into<PathBuf>>(backend: Arc<dyn Backend>, path: P) -> AtomicWriter {
+        AtomicWriter {
+            target: Some(AtomicTarget::Buffer {
+                backend,
+                path: path.into(),
+                content: Cursor::new(Vec::new()),
+            }),
+        }
+    }
+
+    //Replace the target with everything written so far.
+    pub fn commit(mut self) -> FileSystemResult<()> {
+        match self.target.take() {
+            Some(AtomicTarget::File { file, temporary_path, path }) => {
+                let result = AtomicWriter::commit_file(file, temporary_path.as_path(), path.as_path());
+                if result.is_err() {
+                    let _ = fs::remove_file(temporary_path.as_path());
+                }
+                result
+            },
+            Some(AtomicTarget::Buffer { backend, path, content }) => {
+                debug!("Committing the atomic write of {}", backend.display_path(path.as_path()).display());
+                backend.write(path.as_path(), content.get_ref().as_slice())
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn commit_file(file: BufWriter<File>, temporary_path: &Path, path: &Path) -> FileSystemResult<()> {
+        debug!("Committing the atomic write of {}", path.display());
+        let write_error = |error| FileSystemError::io(Operation::Write, &[temporary_path], error);
+        let file = file.into_inner().map_err(|error| write_error(error.into_error()))?;
+        match fs::metadata(path) {
+            Ok(metadata) => file.set_permissions(metadata.permissions()).map_err(write_error)?,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => {},
+            Err(error) => return Err(FileSystemError::io(Operation::Metadata, &[path], error)),
+        }
+        file.sync_all().map_err(write_error)?;
+        drop(file);
+        fs::rename(temporary_path, path)
+            .map_err(|error| FileSystemError::io(Operation::Rename, &[temporary_path, path], error))?;
+        let directory = match path.parent() {
+            Some(directory) if !directory.as_os_str().is_empty() => directory,
+            _ => Path::new("."),
+        };
+        sync_directory(directory).map_err(|error| FileSystemError::io(Operation::Write, &[directory], error))
+    }
+}
+
+impl Write for AtomicWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.target {
+            Some(AtomicTarget::File { ref mut file, .. }) => file.write(buf),
+            Some(AtomicTarget::Buffer { ref mut content, .. }) => content.write(buf),
+            None => Err(io::Error::other("the atomic write has already been committed")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.target {
+            Some(AtomicTarget::File { ref mut file, .. }) => file.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+//Seeking lets the content be patched before the commit, like the header of a pack.
+impl Seek for AtomicWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self.target {
+            Some(AtomicTarget::File { ref mut file, .. }) => file.seek(pos),
+            Some(AtomicTarget::Buffer { ref mut content, .. }) => content.seek(pos),
+            None => Err(io::Error::other("the atomic write has already been committed")),
+        }
+    }
+}
+
+//Dropping the writer without committing discards what has been written.
+impl Drop for AtomicWriter {
+    fn drop(&mut self) {
+        if let Some(AtomicTarget::File { file, temporary_path, path }) = self.target.take() {
+            debug!("Discarding the atomic write of {}", path.display());
+            drop(file);
+            if let Err(error) = fs::remove_file(temporary_path.as_path()) {
+                warn!("Couldn't remove the temporary file {}: {}", temporary_path.display(), error);
+            }
+        }
+    }
+}
+
+//Replace the content of the file at path, on the disk, atomically.
+pub fn write_atomic<P: AsRef<Path>>(path: P, content: &[u8]) -> FileSystemResult<()> {
+    let mut writer = AtomicWriter::new(path)?;
+    writer.write_all(content)?;
+    writer.commit()
+}
+
+#[cfg(test)]
+mod atomic_writer_test {
+    use super::*;
+    use tempfile;
+
+    #[test]
+    fn atomic_writer_commit_and_discard() {
+        let temp = tempfile::tempdir().unwrap();
+        let save = temp.path().join("slot_1.sav");
+        write_atomic(save.as_path(), b"first").unwrap();
+        assert_eq!(fs::read(save.as_path()).unwrap(), b"first");
+
+        let mut writer = AtomicWriter::new(save.as_path()).unwrap();
+        writer.write_all(b"second").unwrap();
+        assert_eq!(fs::read(save.as_path()).unwrap(), b"first");
+        drop(writer);
+        assert_eq!(fs::read(save.as_path()).unwrap(), b"first");
+
+        let mut writer = AtomicWriter::new(save.as_path()).unwrap();
+        writer.write_all(b"third").unwrap();
+        writer.commit().unwrap();
+        assert_eq!(fs::read(save.as_path()).unwrap(), b"third");
+
+        //Nothing is left behind.
+        assert_eq!(fs::read_dir(temp.path()).unwrap().count(), 1);
+        assert!(write_atomic(temp.path().join("missing/slot.sav"), b"save").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn atomic_writer_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let save = temp.path().join("slot_1.sav");
+        fs::write(save.as_path(), b"first").unwrap();
+        fs::set_permissions(save.as_path(), fs::Permissions::from_mode(0o600)).unwrap();
+        write_atomic(save.as_path(), b"second").unwrap();
+        assert_eq!(fs::read(save.as_path()).unwrap(), b"second");
+        assert_eq!(fs::metadata(save.as_path()).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,399 @@
+// Copyright 2017-2018 Maskerad Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use filesystem_error::{FileSystemError, FileSystemResult, Operation};
+use open_options::OpenOptions;
+use dir_entry::DirEntry;
+use file_reader::FileReader;
+use metadata::Metadata;
+use remove_dir_all;
+
+/*BACKENDS.
+
+A backend is a place where files can be stored: a directory on the disk, an archive, memory...
+The Filesystem doesn't know anything about them, it only finds the backend responsible for a path
+through the mount table, and forwards the operation to it.
+
+The paths given to a backend are relative to its root. The backends which can't be modified,
+like the archives, only implement the read operations: the write operations fail by default.
+
+Anything implementing Backend can be mounted, with MountSource::Backend.
+*/
+
+//A file opened to read, whatever backend it comes from.
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+//A file opened with OpenOptions, whatever backend it comes from (see FileHandle).
+pub trait BackendFile: Read + Write + Seek + Send {
+    fn metadata(&self) -> FileSystemResult<Metadata>;
+
+    //Flush the content and the metadata of the file to the storage.
+    fn sync_all(&mut self) -> FileSystemResult<()>;
+
+    //Flush the content of the file to the storage, the metadata may not be.
+    fn sync_data(&mut self) -> FileSystemResult<()> {
+        self.sync_all()
+    }
+
+    //Truncate or extend the file, with zeros, to len bytes.
+    fn set_len(&mut self, len: u64) -> FileSystemResult<()>;
+}
+
+impl BackendFile for fs::File {
+    fn metadata(&self) -> FileSystemResult<Metadata> {
+        fs::File::metadata(self).map(|metadata| Metadata::from(&metadata)).map_err(FileSystemError::from)
+    }
+
+    fn sync_all(&mut self) -> FileSystemResult<()> {
+        fs::File::sync_all(self).map_err(FileSystemError::from)
+    }
+
+    fn sync_data(&mut self) -> FileSystemResult<()> {
+        fs::File::sync_data(self).map_err(FileSystemError::from)
+    }
+
+    fn set_len(&mut self, len: u64) -> FileSystemResult<()> {
+        fs::File::set_len(self, len).map_err(FileSystemError::from)
+    }
+}
+
+//A file of a read-only backend, opened with OpenOptions: it can only be read.
+struct ReadOnlyFile {
+    reader: Box<dyn ReadSeek>,
+    metadata: Metadata,
+}
+
+fn read_only_file_error() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "The file is read-only")
+}
+
+impl Read for ReadOnlyFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Seek for ReadOnlyFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.reader.seek(pos)
+    }
+}
+
+impl Write for ReadOnlyFile {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(read_only_file_error())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl BackendFile for ReadOnlyFile {
+    fn metadata(&self) -> FileSystemResult<Metadata> {
+        Ok(self.metadata.clone())
+    }
+
+    fn sync_all(&mut self) -> FileSystemResult<()> {
+        Ok(())
+    }
+
+    fn set_len(&mut self, _len: u64) -> FileSystemResult<()> {
+        Err(FileSystemError::from(read_only_file_error()))
+    }
+}
+
+pub trait Backend: fmt::Debug + Send + Sync {
+    //A description of the path, for the logs and the errors (e.g. data.zip/textures/hero.png).
+    fn display_path(&self, path: &Path) -> PathBuf;
+
+    //Where the path is on the disk, if the backend stores it as a plain file or directory.
+    fn physical_path(&self, _path: &Path) -> Option<PathBuf> {
+        None
+    }
+
+    //The absolute, canonical, path of the file on the disk.
+    fn absolute_path(&self, path: &Path) -> FileSystemResult<PathBuf> {
+        error!("{} isn't on the disk, it doesn't have an absolute path !", self.display_path(path).display());
+        Err(FileSystemError::MountError(format!(
+            "{} isn't on the disk, it doesn't have an absolute path.",
+            self.display_path(path).display()
+        )))
+    }
+
+    fn exists(&self, path: &Path) -> bool;
+
+    fn is_dir(&self, path: &Path) -> bool;
+
+    fn is_file(&self, path: &Path) -> bool;
+
+    fn metadata(&self, path: &Path) -> FileSystemResult<Metadata>;
+
+    //Open the file at path to read.
+    fn open(&self, path: &Path) -> FileSystemResult<Box<dyn ReadSeek>>;
+
+    //List the entries of the directory at path. The paths of the entries start with path.
+    fn read_dir(&self, path: &Path) -> FileSystemResult<Vec<DirEntry>>;
+
+    //Open the file at path with the given options, to read, write and seek in it.
+    //By default, the file can only be opened to read, through open.
+    fn open_with_options(&self, path: &Path, options: &OpenOptions) -> FileSystemResult<Box<dyn BackendFile>> {
+        options.validate()?;
+        if options.write() || options.append() {
+            return Err(read_only_error(self, path));
+        }
+        let metadata = self.metadata(path)?;
+        Ok(Box::new(ReadOnlyFile {
+            reader: self.open(path)?,
+            metadata,
+        }))
+    }
+
+    //Open the file at path for writing, truncating it if it already exists.
+    fn create(&self, path: &Path) -> FileSystemResult<Box<dyn Write + Send>> {
+        Err(read_only_error(self, path))
+    }
+
+    //Open the file at path for appending, creating it if necessary.
+    fn append(&self, path: &Path) -> FileSystemResult<Box<dyn Write + Send>> {
+        Err(read_only_error(self, path))
+    }
+
+    //Replace the whole content of the file at path.
+    fn write(&self, path: &Path, content: &[u8]) -> FileSystemResult<()> {
+        let mut file = self.create(path)?;
+        file.write_all(content)?;
+        file.flush().map_err(FileSystemError::from)
+    }
+
+    //Create the directory at path, and its missing parents.
+    fn mkdir(&self, path: &Path) -> FileSystemResult<()> {
+        Err(read_only_error(self, path))
+    }
+
+    //Remove the file, or the empty directory, at path.
+    fn rm(&self, path: &Path) -> FileSystemResult<()> {
+        Err(read_only_error(self, path))
+    }
+
+    //Move the file or the directory at from to to, replacing the file at to if it exists.
+    fn rename(&self, from: &Path, _to: &Path) -> FileSystemResult<()> {
+        Err(read_only_error(self, from))
+    }
+
+    //Remove the file or the directory at path, and all its content.
+    fn rmrf(&self, path: &Path) -> FileSystemResult<()> {
+        Err(read_only_error(self, path))
+    }
+}
+
+fn read_only_error<B: Backend + ?Sized>(backend: &B, path: &Path) -> FileSystemError {
+    error!("{} is read-only !", backend.display_path(path).display());
+    FileSystemError::MountError(format!(
+        "{} is read-only.",
+        backend.display_path(path).display()
+    ))
+}
+
+//The files and directories under a directory of the disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhysicalFilesystem {
+    root: PathBuf,
+}
+
+impl PhysicalFilesystem {
+    //The paths are joined to root, an empty root lets the paths through unchanged.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        PhysicalFilesystem {
+            root: root.into(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        self.root.as_path()
+    }
+
+    fn full_path(&self, path: &Path) -> PathBuf {
+        self.root.join(path)
+    }
+
+    fn open_file(&self, path: &Path, open_options: &OpenOptions, operation: Operation) -> FileSystemResult<fs::File> {
+        let full_path = self.full_path(path);
+        trace!("Opening file at path {} with options {}", full_path.display(), open_options);
+        open_options.validate()?;
+        open_options
+            .to_fs_openoptions()
+            .open(full_path.as_path())
+            .map_err(|error| FileSystemError::io(operation, &[full_path], error))
+    }
+}
+
+impl Backend for PhysicalFilesystem {
+    fn display_path(&self, path: &Path) -> PathBuf {
+        self.full_path(path)
+    }
+
+    fn physical_path(&self, path: &Path) -> Option<PathBuf> {
+        Some(self.full_path(path))
+    }
+
+    fn absolute_path(&self, path: &Path) -> FileSystemResult<PathBuf> {
+        let full_path = self.full_path(path);
+        fs::canonicalize(full_path.as_path()).map_err(|error| FileSystemError::io(Operation::Canonicalize, &[full_path], error))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.full_path(path).exists()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.full_path(path).is_dir()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.full_path(path).is_file()
+    }
+
+    fn metadata(&self, path: &Path) -> FileSystemResult<Metadata> {
+        let full_path = self.full_path(path);
+        fs::metadata(full_path.as_path())
+            .map(|metadata| Metadata::from(&metadata))
+            .map_err(|error| FileSystemError::io(Operation::Metadata, &[full_path], error))
+    }
+
+    fn open(&self, path: &Path) -> FileSystemResult<Box<dyn ReadSeek>> {
+        let file = self.open_file(path, OpenOptions::new().set_read(true), Operation::Open)?;
+        Ok(Box::new(FileReader::File(file)))
+    }
+
+    fn open_with_options(&self, path: &Path, options: &OpenOptions) -> FileSystemResult<Box<dyn BackendFile>> {
+        let file = self.open_file(path, options, Operation::Open)?;
+        Ok(Box::new(file))
+    }
+
+    fn read_dir(&self, path: &Path) -> FileSystemResult<Vec<DirEntry>> {
+        let full_path = self.full_path(path);
+        let read_dir_error = |error| FileSystemError::io(Operation::ReadDir, &[full_path.as_path()], error);
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(full_path.as_path()).map_err(read_dir_error)? {
+            let entry = entry.map_err(read_dir_error)?;
+            entries.push(DirEntry::new(path, entry.file_name(), entry.path().is_dir()));
+        }
+        Ok(entries)
+    }
+
+    fn create(&self, path: &Path) -> FileSystemResult<Box<dyn Write + Send>> {
+        let file = self.open_file(
+            path,
+            OpenOptions::new()
+                .set_create(true)
+                .set_write(true)
+                .set_truncate(true),
+            Operation::Create,
+        )?;
+        Ok(Box::new(file))
+    }
+
+    fn append(&self, path: &Path) -> FileSystemResult<Box<dyn Write + Send>> {
+        let file = self.open_file(
+            path,
+            OpenOptions::new()
+                .set_create(true)
+                .set_append(true)
+                .set_write(true),
+            Operation::Append,
+        )?;
+        Ok(Box::new(file))
+    }
+
+    fn write(&self, path: &Path, content: &[u8]) -> FileSystemResult<()> {
+        let full_path = self.full_path(path);
+        fs::write(full_path.as_path(), content).map_err(|error| FileSystemError::io(Operation::Write, &[full_path], error))
+    }
+
+    fn mkdir(&self, path: &Path) -> FileSystemResult<()> {
+        let full_path = self.full_path(path);
+        fs::DirBuilder::new()
+            .recursive(true)
+            .create(full_path.as_path())
+            .map_err(|error| FileSystemError::io(Operation::Mkdir, &[full_path], error))
+    }
+
+    fn rm(&self, path: &Path) -> FileSystemResult<()> {
+        let path = self.full_path(path);
+        let result = if path.is_dir() {
+            debug!("Removing empty directory at path {}", path.display());
+            fs::remove_dir(path.as_path())
+        } else {
+            debug!("Removing file at path: {}", path.display());
+            fs::remove_file(path.as_path())
+        };
+        result.map_err(|error| FileSystemError::io(Operation::Rm, &[path], error))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> FileSystemResult<()> {
+        let (from, to) = (self.full_path(from), self.full_path(to));
+        fs::rename(from.as_path(), to.as_path()).map_err(|error| FileSystemError::io(Operation::Rename, &[from, to], error))
+    }
+
+    fn rmrf(&self, path: &Path) -> FileSystemResult<()> {
+        let full_path = self.full_path(path);
+        remove_dir_all::remove_dir_all(full_path.as_path()).map_err(|error| FileSystemError::io(Operation::Rmrf, &[full_path], error))
+    }
+}
+
+#[cfg(test)]
+mod backend_test {
+    use super::*;
+    use std::io::Read;
+    use filesystem_error::ErrorKind;
+    use tempfile;
+
+    #[test]
+    fn backend_physical_filesystem() {
+        let temp = tempfile::tempdir().unwrap();
+        let backend: Box<dyn Backend> = Box::new(PhysicalFilesystem::new(temp.path()));
+
+        backend.mkdir(Path::new("saves/slots")).unwrap();
+        backend.write(Path::new("saves/slots/1.sav"), b"save").unwrap();
+        backend.append(Path::new("saves/slots/1.sav")).unwrap().write_all(b" 1").unwrap();
+        let mut content = String::new();
+        backend.open(Path::new("saves/slots/1.sav")).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "save 1");
+        assert_eq!(backend.metadata(Path::new("saves/slots/1.sav")).unwrap().len(), 6);
+        assert_eq!(backend.physical_path(Path::new("saves")), Some(temp.path().join("saves")));
+
+        let entries = backend.read_dir(Path::new("saves")).unwrap();
+        assert_eq!(entries, vec![DirEntry::new("saves", "slots".into(), true)]);
+
+        let mut file = backend
+            .open_with_options(Path::new("saves/slots/1.sav"), OpenOptions::new().set_read(true).set_write(true))
+            .unwrap();
+        file.seek(SeekFrom::Start(5)).unwrap();
+        file.write_all(b"2").unwrap();
+        file.set_len(7).unwrap();
+        file.sync_all().unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 7);
+        drop(file);
+        assert_eq!(fs::read(temp.path().join("saves/slots/1.sav")).unwrap(), b"save 2\0");
+        assert!(backend.open_with_options(Path::new("saves/slots/1.sav"), OpenOptions::new().set_read(true).set_append(true).set_truncate(true)).is_err());
+
+        assert!(backend.rm(Path::new("saves")).is_err());
+        let error = backend.open(Path::new("missing.txt")).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        assert_eq!(error.context().unwrap().operation(), Some(Operation::Open));
+        assert_eq!(error.context().unwrap().paths(), [temp.path().join("missing.txt")]);
+        backend.rmrf(Path::new("saves")).unwrap();
+        assert!(!backend.exists(Path::new("saves")));
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright 2017-2018 Maskerad Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+extern crate maskerad_filesystem;
+
+use std::env;
+use std::fs;
+use std::path::{Component, Path};
+use std::process;
+use maskerad_filesystem::filesystem_error::{FileSystemError, FileSystemResult};
+use maskerad_filesystem::pack::{PackArchive, PackEntry, PackWriter, PACK_COMPRESSED};
+
+/*MASKERAD-PACK.
+
+Build and inspect Maskerad packs, with the same reader and writer as the engine.
+*/
+
+const USAGE: &str = "Usage:
+    maskerad-pack create <directory> <pack> [--compress]
+    maskerad-pack list <pack>
+    maskerad-pack extract <pack> <output directory> [<path in the pack>...]
+    maskerad-pack verify <pack>";
+
+fn main() {
+    let arguments: Vec<String> = env::args().skip(1).collect();
+    let arguments: Vec<&str> = arguments.iter().map(String::as_str).collect();
+
+    let result = match arguments.as_slice() {
+        ["create", directory, pack] => create(directory, pack, false),
+        ["create", directory, pack, "--compress"] => create(directory, pack, true),
+        ["list", pack] => list(pack),
+        ["extract", pack, output, paths @ ..] => extract(pack, output, paths),
+        ["verify", pack] => verify(pack),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        },
+    };
+
+    if let Err(error) = result {
+        eprintln!("maskerad-pack: {}", error);
+        process::exit(1);
+    }
+}
+
+fn create(directory: &str, pack: &str, compress: bool) -> FileSystemResult<()> {
+    let flags = if compress { PACK_COMPRESSED } else { 0 };
+    PackWriter::new()
+        .add_directory(directory, flags)?
+        .write(pack)?;
+
+    let entries = PackArchive::open(pack)?.entries().len();
+    println!("Created {} with {} files.", pack, entries);
+    Ok(())
+}
+
+fn list(pack: &str) -> FileSystemResult<()> {
+    let pack = PackArchive::open(pack)?;
+    let mut entries: Vec<&PackEntry> = pack.entries().iter().collect();
+    entries.sort_by(|first, second| first.path().cmp(second.path()));
+
+    println!("{:>12} {:>12} {:>8} {:>5} path", "size", "stored", "crc32", "flags");
+    for entry in entries {
+        let flags = format!(
+            "{}{}",
+            if entry.is_compressed() { "c" } else { "-" },
+            if entry.is_encrypted() { "e" } else { "-" }
+        );
+        println!(
+            "{:>12} {:>12} {:08x} {:>5} {}",
+            entry.size(),
+            entry.stored_size(),
+            entry.crc32(),
+            flags,
+            entry.path()
+        );
+    }
+    Ok(())
+}
+
+fn extract(pack: &str, output: &str, paths: &[&str]) -> FileSystemResult<()> {
+    let pack = PackArchive::open(pack)?;
+    let entries: Vec<&PackEntry> = if paths.is_empty() {
+        pack.entries().iter().collect()
+    } else {
+        let mut entries = Vec::with_capacity(paths.len());
+        for path in paths {
+            match pack.entry(path) {
+                Some(entry) => entries.push(entry),
+                None => {
+                    return Err(FileSystemError::ArchiveError(format!(
+                        "{} could not be found in {}.",
+                        path,
+                        pack.path().display()
+                    )))
+                },
+            }
+        }
+        entries
+    };
+
+    for entry in entries {
+        //Never trust the paths stored in a pack.
+        let relative = Path::new(entry.path());
+        if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(FileSystemError::ArchiveError(format!(
+                "{} can't be extracted, its path leaves the output directory.",
+                entry.path()
+            )));
+        }
+
+        let destination = Path::new(output).join(relative);
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(destination.as_path(), pack.read_entry(entry)?)?;
+        println!("{}", destination.display());
+    }
+    Ok(())
+}
+
+fn verify(pack: &str) -> FileSystemResult<()> {
+    let pack = PackArchive::open(pack)?;
+    let mut corrupted = 0;
+    let mut skipped = 0;
+
+    for entry in pack.entries() {
+        if entry.is_encrypted() {
+            println!("SKIPPED {} (encrypted)", entry.path());
+            skipped += 1;
+            continue;
+        }
+        if let Err(error) = pack.read_entry(entry) {
+            println!("CORRUPTED {} ({})", entry.path(), error);
+            corrupted += 1;
+        }
+    }
+
+    println!(
+        "{} files checked, {} corrupted, {} skipped.",
+        pack.entries().len() - skipped,
+        corrupted,
+        skipped
+    );
+    if corrupted > 0 {
+        return Err(FileSystemError::ArchiveError(format!(
+            "{} contains {} corrupted files.",
+            pack.path().display(),
+            corrupted
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,73 @@
+// Copyright 2017-2018 Maskerad Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::vec;
+
+//An entry of a directory listing.
+//The path is built from the path given to Filesystem::read_dir, so entries of a mounted
+//directory keep their virtual path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    path: PathBuf,
+    file_name: OsString,
+    is_dir: bool,
+}
+
+impl DirEntry {
+    pub fn new<P: AsRef<Path>>(directory: P, file_name: OsString, is_dir: bool) -> DirEntry {
+        DirEntry {
+            path: directory.as_ref().join(file_name.as_os_str()),
+            file_name,
+            is_dir,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    pub fn file_name(&self) -> &OsStr {
+        self.file_name.as_os_str()
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    pub fn is_file(&self) -> bool {
+        !self.is_dir
+    }
+}
+
+//Iterator over the entries of a directory, sorted by file name.
+#[derive(Debug)]
+pub struct ReadDir {
+    entries: vec::IntoIter<DirEntry>,
+}
+
+impl ReadDir {
+    pub fn new(mut entries: Vec<DirEntry>) -> ReadDir {
+        entries.sort_by(|first, second| first.file_name.cmp(&second.file_name));
+        ReadDir {
+            entries: entries.into_iter(),
+        }
+    }
+}
+
+impl Iterator for ReadDir {
+    type Item = DirEntry;
+
+    fn next(&mut self) -> Option<DirEntry> {
+        self.entries.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
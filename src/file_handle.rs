@@ -0,0 +1,225 @@
+// Copyright 2017-2018 Maskerad Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use filesystem_error::{FileSystemError, FileSystemResult, Operation};
+use game_directories::RootDir;
+use backend::BackendFile;
+use metadata::Metadata;
+
+/*FILE HANDLES.
+
+Filesystem::open and Filesystem::create give one side of a file. A FileHandle is a file opened with
+any OpenOptions (see Filesystem::open_with_options): it can be read, written and seeked at once,
+to patch the header of a save in place or to access a database randomly.
+
+The handle isn't buffered: each read and write goes to the backend. Wrap it in a BufReader or a
+BufWriter for small sequential accesses.
+
+Dropping the handle closes it, but the errors happening then are lost.
+close() reports them: it flushes the file and its content to the storage.
+
+A FileHandle can't be locked. To keep other processes away from the file, lock a separate file
+with Filesystem::lock (e.g. slot_1.sav.lock) before opening it, and hold the lock until it is closed:
+the files written atomically are replaced, a lock on them would stay on the old file (see FileLock).
+*/
+
+pub struct FileHandle {
+    file: Box<dyn BackendFile>,
+    //The path of the file in its backend, for the errors.
+    path: PathBuf,
+    root_dir: Option<RootDir>,
+}
+
+impl fmt::Debug for FileHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FileHandle")
+            .field("path", &self.path)
+            .field("root_dir", &self.root_dir)
+            .finish()
+    }
+}
+
+impl FileHandle {
+    pub(crate) fn new(file: Box<dyn BackendFile>, path: PathBuf, root_dir: Option<RootDir>) -> FileHandle {
+        FileHandle {
+            file,
+            path,
+            root_dir,
+        }
+    }
+
+    fn error_context(&self, error: FileSystemError, operation: Operation) -> FileSystemError {
+        error
+            .with_operation(operation)
+            .with_path(self.path.as_path())
+            .with_root_dir(self.root_dir)
+    }
+
+    //The path of the file, as displayed in the errors (e.g. data.zip/textures/hero.png).
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    pub fn metadata(&self) -> FileSystemResult<Metadata> {
+        self.file.metadata().map_err(|error| self.error_context(error, Operation::Metadata))
+    }
+
+    //Flush the content and the metadata of the file to the storage.
+    pub fn sync_all(&mut self) -> FileSystemResult<()> {
+        let result = self.file.sync_all();
+        result.map_err(|error| self.error_context(error, Operation::Sync))
+    }
+
+    //Flush the content of the file to the storage, without the metadata if possible.
+    pub fn sync_data(&mut self) -> FileSystemResult<()> {
+        let result = self.file.sync_data();
+        result.map_err(|error| self.error_context(error, Operation::Sync))
+    }
+
+    //Truncate or extend the file, with zeros, to len bytes. The position in the file doesn't change.
+    pub fn set_len(&mut self, len: u64) -> FileSystemResult<()> {
+        debug!("Setting the length of {} to {}", self.path.display(), len);
+        let result = self.file.set_len(len);
+        result.map_err(|error| self.error_context(error, Operation::SetLen))
+    }
+
+    //Close the file, reporting the errors which would be lost by dropping it.
+    pub fn close(mut self) -> FileSystemResult<()> {
+        debug!("Closing {}", self.path.display());
+        let result = self.file
+            .flush()
+            .map_err(FileSystemError::from)
+            .and_then(|()| self.file.sync_data());
+        result.map_err(|error| self.error_context(error, Operation::Close))
+    }
+}
+
+impl Read for FileHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for FileHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for FileHandle {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod file_handle_test {
+    use super::*;
+    use std::io::Cursor;
+    use tempfile;
+    use backend::Backend;
+    use filesystem_error::ErrorKind;
+    use open_options::OpenOptions;
+    use pack::{PackArchive, PackWriter};
+
+    //A file whose storage is full: the writes are buffered, flushing them fails.
+    struct FullFile(Cursor<Vec<u8>>);
+
+    impl Read for FullFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for FullFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::StorageFull, "No space left on device"))
+        }
+    }
+
+    impl Seek for FullFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    impl BackendFile for FullFile {
+        fn metadata(&self) -> FileSystemResult<Metadata> {
+            Ok(Metadata::new(self.0.get_ref().len() as u64, false, None, false))
+        }
+
+        fn sync_all(&mut self) -> FileSystemResult<()> {
+            Ok(())
+        }
+
+        fn set_len(&mut self, len: u64) -> FileSystemResult<()> {
+            self.0.get_mut().resize(len as usize, 0);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn file_handle_close_reports_errors() {
+        let file = Box::new(FullFile(Cursor::new(Vec::new())));
+        let mut handle = FileHandle::new(file, PathBuf::from("saves/slot_1.sav"), Some(RootDir::UserSaveRoot));
+        handle.write_all(b"progress").unwrap();
+        assert_eq!(handle.metadata().unwrap().len(), 8);
+
+        let error = handle.close().err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::DiskFull);
+        let context = error.context().unwrap();
+        assert_eq!(context.operation(), Some(Operation::Close));
+        assert_eq!(context.paths(), &[PathBuf::from("saves/slot_1.sav")]);
+        assert_eq!(context.root_dir(), Some(RootDir::UserSaveRoot));
+    }
+
+    #[test]
+    fn file_handle_read_only_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let pack_path = temp.path().join("data.pak");
+        PackWriter::new()
+            .add_bytes("levels/one.lvl", b"level one".to_vec(), 0)
+            .unwrap()
+            .write(pack_path.as_path())
+            .unwrap();
+        let pack = PackArchive::open(pack_path.as_path()).unwrap();
+
+        let path = Path::new("levels/one.lvl");
+        assert!(pack.open_with_options(path, OpenOptions::new().set_write(true)).is_err());
+        let file = pack.open_with_options(path, OpenOptions::new().set_read(true)).unwrap();
+        let mut handle = FileHandle::new(file, pack.display_path(path), Some(RootDir::WorkingDirectory));
+
+        let mut content = String::new();
+        handle.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "level one");
+        assert!(handle.metadata().unwrap().read_only());
+
+        //Nothing can be written, the file is left untouched.
+        let error = handle.write_all(b"mods").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        let error = handle.set_len(0).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(error.context().unwrap().operation(), Some(Operation::SetLen));
+        assert_eq!(error.context().unwrap().root_dir(), Some(RootDir::WorkingDirectory));
+        handle.seek(SeekFrom::Start(0)).unwrap();
+        content.clear();
+        handle.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "level one");
+        handle.close().unwrap();
+    }
+}
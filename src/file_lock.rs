@@ -0,0 +1,191 @@
+// Copyright 2017-2018 Maskerad Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::cmp;
+use std::fmt;
+use std::fs::{File, TryLockError};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use filesystem_error::{FileSystemError, FileSystemResult, Operation};
+use open_options::OpenOptions;
+
+/*FILE LOCKS.
+
+Two instances of the game writing the same save corrupt it. A lock is taken on a file to
+coordinate the processes: any number of shared locks, or a single exclusive lock.
+
+The locks are advisory (flock on unix, LockFileEx on windows): they only exclude the processes
+taking locks too, nothing prevents a process from ignoring them and writing the file anyway.
+A lock belongs to an opened file, two locks taken separately conflict even in the same process.
+
+The atomic writes replace the file with a new one, and the lock stays on the old file. The files
+written atomically, like the saves, are protected by locking a separate file (e.g. slot_1.sav.lock).
+
+The lock is released when the FileLock is dropped, or when the process exits, even by crashing.
+*/
+
+//The longest wait between two attempts to take a lock, with a timeout.
+const MAX_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LockMode {
+    //Many processes can hold a shared lock at the same time, to read the file.
+    Shared,
+    //A single process can hold an exclusive lock, to write the file.
+    Exclusive,
+}
+
+impl fmt::Display for LockMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LockMode::Shared => write!(f, "shared"),
+            LockMode::Exclusive => write!(f, "exclusive"),
+        }
+    }
+}
+
+//A lock held on a file, released when dropped.
+#[derive(Debug)]
+pub struct FileLock {
+    file: File,
+    path: PathBuf,
+    mode: LockMode,
+}
+
+impl FileLock {
+    //Open the lock file, creating it if necessary. Its content is left untouched.
+    fn open(path: &Path) -> FileSystemResult<File> {
+        OpenOptions::new()
+            .set_read(true)
+            .set_write(true)
+            .set_create(true)
+            .to_fs_openoptions()
+            .open(path)
+            .map_err(|error| FileSystemError::io(Operation::Lock, &[path], error))
+    }
+
+    //Try to lock the file once. Ok(false) if the lock is held by someone else.
+    fn try_lock_file(file: &File, path: &Path, mode: LockMode) -> FileSystemResult<bool> {
+        let result = match mode {
+            LockMode::Shared => file.try_lock_shared(),
+            LockMode::Exclusive => file.try_lock(),
+        };
+        match result {
+            Ok(()) => Ok(true),
+            Err(TryLockError::WouldBlock) => Ok(false),
+            Err(TryLockError::Error(error)) => Err(FileSystemError::io(Operation::Lock, &[path], error)),
+        }
+    }
+
+    fn new(file: File, path: &Path, mode: LockMode) -> FileLock {
+        debug!("Took a {} lock on {}", mode, path.display());
+        FileLock {
+            file,
+            path: path.to_path_buf(),
+            mode,
+        }
+    }
+
+    //Lock the file at path, on the disk, waiting as long as necessary for the lock to be released.
+    pub fn lock<P: AsRef<Path>>(path: P, mode: LockMode) -> FileSystemResult<FileLock> {
+        let path = path.as_ref();
+        trace!("Waiting for a {} lock on {}", mode, path.display());
+        let file = FileLock::open(path)?;
+        let result = match mode {
+            LockMode::Shared => file.lock_shared(),
+            LockMode::Exclusive => file.lock(),
+        };
+        result.map_err(|error| FileSystemError::io(Operation::Lock, &[path], error))?;
+        Ok(FileLock::new(file, path, mode))
+    }
+
+    //Lock the file at path, on the disk, if nobody else holds a conflicting lock. None otherwise.
+    pub fn try_lock<P: AsRef<Path>>(path: P, mode: LockMode) -> FileSystemResult<Option<FileLock>> {
+        FileLock::lock_timeout(path, mode, Duration::from_secs(0))
+    }
+
+    //Lock the file at path, on the disk, waiting at most timeout for the lock to be released. None if it wasn't.
+    //A timeout too long to be represented waits forever, like lock.
+    pub fn lock_timeout<P: AsRef<Path>>(path: P, mode: LockMode, timeout: Duration) -> FileSystemResult<Option<FileLock>> {
+        let path = path.as_ref();
+        let deadline = match Instant::now().checked_add(timeout) {
+            Some(deadline) => deadline,
+            None => return FileLock::lock(path, mode).map(Some),
+        };
+        trace!("Waiting at most {:?} for a {} lock on {}", timeout, mode, path.display());
+        let file = FileLock::open(path)?;
+        let mut delay = Duration::from_millis(1);
+        loop {
+            if FileLock::try_lock_file(&file, path, mode)? {
+                return Ok(Some(FileLock::new(file, path, mode)));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                debug!("The {} lock on {} is held by someone else", mode, path.display());
+                return Ok(None);
+            }
+            thread::sleep(cmp::min(delay, deadline - now));
+            delay = cmp::min(delay * 2, MAX_RETRY_DELAY);
+        }
+    }
+
+    //The path of the locked file.
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    //Release the lock, reporting the errors which would be lost by dropping it.
+    pub fn unlock(self) -> FileSystemResult<()> {
+        debug!("Releasing the {} lock on {}", self.mode, self.path.display());
+        self.file
+            .unlock()
+            .map_err(|error| FileSystemError::io(Operation::Unlock, &[self.path.as_path()], error))
+    }
+}
+
+#[cfg(test)]
+mod file_lock_test {
+    use super::*;
+    use tempfile;
+
+    #[test]
+    fn file_lock_modes() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("slot_1.sav.lock");
+
+        let reader = FileLock::try_lock(path.as_path(), LockMode::Shared).unwrap().unwrap();
+        let other_reader = FileLock::lock(path.as_path(), LockMode::Shared).unwrap();
+        assert_eq!(reader.mode(), LockMode::Shared);
+        assert_eq!(reader.path(), path.as_path());
+        assert!(FileLock::try_lock(path.as_path(), LockMode::Exclusive).unwrap().is_none());
+        drop(reader);
+        other_reader.unlock().unwrap();
+
+        let writer = FileLock::lock(path.as_path(), LockMode::Exclusive).unwrap();
+        assert!(FileLock::try_lock(path.as_path(), LockMode::Shared).unwrap().is_none());
+        let start = Instant::now();
+        assert!(FileLock::lock_timeout(path.as_path(), LockMode::Exclusive, Duration::from_millis(30)).unwrap().is_none());
+        assert!(start.elapsed() >= Duration::from_millis(30));
+
+        //The lock is taken as soon as it is released.
+        let waiting = {
+            let path = path.clone();
+            thread::spawn(move || FileLock::lock_timeout(path, LockMode::Exclusive, Duration::from_secs(10)).unwrap().is_some())
+        };
+        thread::sleep(Duration::from_millis(20));
+        drop(writer);
+        assert!(waiting.join().unwrap());
+        assert!(FileLock::lock_timeout(path.as_path(), LockMode::Shared, Duration::MAX).unwrap().is_some());
+
+        assert!(FileLock::lock(temp.path().join("missing/slot_1.sav.lock"), LockMode::Shared).is_err());
+    }
+}
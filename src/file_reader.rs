@@ -0,0 +1,130 @@
+// Copyright 2017-2018 Maskerad Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use crc32fast;
+
+//The readable side of a file opened by the Filesystem, whatever its source is.
+#[derive(Debug)]
+pub enum FileReader {
+    //A file on the disk.
+    File(File),
+    //A part of a file on the disk, like an uncompressed entry of an archive.
+    Slice(FileSlice),
+    //Some content already loaded in memory, like a decompressed entry of an archive.
+    Memory(Cursor<Vec<u8>>),
+}
+
+impl Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            FileReader::File(ref mut file) => file.read(buf),
+            FileReader::Slice(ref mut slice) => slice.read(buf),
+            FileReader::Memory(ref mut cursor) => cursor.read(buf),
+        }
+    }
+}
+
+impl Seek for FileReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match *self {
+            FileReader::File(ref mut file) => file.seek(pos),
+            FileReader::Slice(ref mut slice) => slice.seek(pos),
+            FileReader::Memory(ref mut cursor) => cursor.seek(pos),
+        }
+    }
+}
+
+//A window of len bytes, starting at start, in a file.
+#[derive(Debug)]
+pub struct FileSlice {
+    file: File,
+    start: u64,
+    len: u64,
+    position: u64,
+}
+
+impl FileSlice {
+    pub fn new(mut file: File, start: u64, len: u64) -> io::Result<FileSlice> {
+        file.seek(SeekFrom::Start(start))?;
+        Ok(FileSlice {
+            file,
+            start,
+            len,
+            position: 0,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    //The CRC-32 of the whole slice, read from its start. The slice is rewound afterwards.
+    pub fn crc32(&mut self) -> io::Result<u32> {
+        self.seek(SeekFrom::Start(0))?;
+        let mut hasher = crc32fast::Hasher::new();
+        let mut buffer = [0; 8192];
+        loop {
+            match self.read(&mut buffer)? {
+                0 => break,
+                read => hasher.update(&buffer[..read]),
+            }
+        }
+        self.seek(SeekFrom::Start(0))?;
+        Ok(hasher.finalize())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for FileSlice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.position);
+        let max = cmp::min(remaining, buf.len() as u64) as usize;
+        if max == 0 {
+            return Ok(0);
+        }
+        let read = self.file.read(&mut buf[..max])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for FileSlice {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => offset_position(self.len, offset),
+            SeekFrom::Current(offset) => offset_position(self.position, offset),
+        };
+
+        //The position in the slice must be a position in the file too.
+        match position.and_then(|position| self.start.checked_add(position).map(|absolute| (position, absolute))) {
+            Some((position, absolute)) => {
+                self.file.seek(SeekFrom::Start(absolute))?;
+                self.position = position;
+                Ok(position)
+            },
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+fn offset_position(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.unsigned_abs())
+    }
+}
@@ -5,14 +5,36 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
-use std::fs::File;
+use std::ptr;
 use std::path::{Path, PathBuf};
-use std::io::{BufReader, BufWriter};
-use game_directories::{GameDirectories, RootDir};
-use filesystem_error::{FileSystemError, FileSystemResult};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use game_directories::{DirectoriesReport, GameDirectories, RootDir};
+use filesystem_error::{ErrorKind, FileSystemError, FileSystemResult, Operation};
+use mount::{MountSource, MountTable};
+use dir_entry::{DirEntry, ReadDir};
+use backend::{Backend, PhysicalFilesystem, ReadSeek};
+use file_handle::FileHandle;
+use file_lock::{FileLock, LockMode};
 use open_options::OpenOptions;
-use remove_dir_all;
+use atomic_writer::AtomicWriter;
+use save_slots::SaveSlots;
+use integrity::{Checksum, HashAlgorithm, Manifest, VerifyReport};
+use memory_filesystem::MemoryFilesystem;
+use metadata::Metadata;
+use io_pool::{IoHandle, IoPool, IoPriority};
+use watcher::{FileWatcher, Visibility, VisibilityCheck, WatchOptions};
+use sandbox::Sandbox;
+use virtual_path::VirtualPath;
+
+//The number of threads dedicated to the asynchronous I/O operations.
+pub const IO_THREAD_COUNT: usize = 2;
+
+//The file locked by the running instance of the game, in the UserDataRoot (see Filesystem::lock_instance).
+pub const INSTANCE_LOCK_FILE: &str = "instance.lock";
 
 //Open to read file
 //Open to write to file
@@ -27,145 +49,736 @@ A filesystem must provide the following functionalities :
 - open close read write append create files and directory.
 - scan content of directory.
 _____________________________________________________________
-- asynchronous I/O (streaming music or textures...). -> just use Rayon directly.
-
-TODO: Take a look at how mio handle async io with TCP. Or future stuff.
+- asynchronous I/O (streaming music or textures...). -> the blocking operations run on a pool of
+  dedicated I/O threads (see io_pool), and return a handle which can be polled, waited for or awaited.
+  Each operation has a priority, and can be cancelled or re-prioritized while it is queued.
+- hot-reloading. -> directories can be watched, the debounced changes are sent through a channel (see watcher).
 _____________________________________________________________
 */
 
-/*
-pub fn read<T: Read>(from: &mut BufReader<T>, to: &mut [u8]) -> FileSystemResult<usize> {
-    from.read(to)
-        .map_err(|io_error| FileSystemError::from(io_error))
+//Where a path leads to, once it went through the mount table:
+//the backend storing the file, the path of the file in this backend,
+//and the root directory containing the file if it is in one.
+#[derive(Debug, Clone)]
+struct Location {
+    backend: Arc<dyn Backend>,
+    path: PathBuf,
+    root_dir: Option<RootDir>,
 }
 
-pub fn read_to_end<T: Read>(from: &mut BufReader<T>, to: &mut Vec<u8>) -> FileSystemResult<usize> {
-    from.read_to_end(to)
-        .map_err(|io_error| FileSystemError::from(io_error))
-}
+impl Location {
+    //Add the location to the errors of the backend.
+    fn error_context(&self, error: FileSystemError, operation: Operation) -> FileSystemError {
+        error
+            .with_operation(operation)
+            .with_path(self.to_path_buf())
+            .with_root_dir(self.root_dir)
+    }
 
-pub fn read_to_string<T: Read>(
-    from: &mut BufReader<T>,
-    to: &mut String,
-) -> FileSystemResult<usize> {
-    from.read_to_string(to)
-        .map_err(|io_error| FileSystemError::from(io_error))
-}
+    fn exists(&self) -> bool {
+        self.backend.exists(self.path.as_path())
+    }
 
-pub fn read_exact<T: Read>(from: &mut BufReader<T>, to: &mut [u8]) -> FileSystemResult<()> {
-    from.read_exact(to)
-        .map_err(|io_error| FileSystemError::from(io_error))
-}
+    fn is_dir(&self) -> bool {
+        self.backend.is_dir(self.path.as_path())
+    }
 
-pub fn write<T: Write>(to: &mut BufWriter<T>, from: &[u8]) -> FileSystemResult<usize> {
-    to.write(from)
-        .map_err(|io_error| FileSystemError::from(io_error))
-}
+    fn is_file(&self) -> bool {
+        self.backend.is_file(self.path.as_path())
+    }
+
+    //Check if the location is a symbolic link on the disk. The other backends don't have links.
+    fn is_symlink(&self) -> bool {
+        self.backend
+            .physical_path(self.path.as_path())
+            .and_then(|physical_path| fs::symlink_metadata(physical_path).ok())
+            .is_some_and(|metadata| metadata.file_type().is_symlink())
+    }
+
+    fn to_path_buf(&self) -> PathBuf {
+        self.backend.display_path(self.path.as_path())
+    }
+
+    //The location of the parent directory.
+    fn parent(&self) -> Option<Location> {
+        self.path.parent().map(|parent| Location {
+            backend: self.backend.clone(),
+            path: parent.to_path_buf(),
+            root_dir: self.root_dir,
+        })
+    }
+
+    fn absolute_path(&self) -> FileSystemResult<PathBuf> {
+        self.backend.absolute_path(self.path.as_path()).map_err(|error| self.error_context(error, Operation::Canonicalize))
+    }
+
+    fn metadata(&self) -> FileSystemResult<Metadata> {
+        self.backend.metadata(self.path.as_path()).map_err(|error| self.error_context(error, Operation::Metadata))
+    }
+
+    fn open(&self) -> FileSystemResult<Box<dyn ReadSeek>> {
+        self.backend.open(self.path.as_path()).map_err(|error| self.error_context(error, Operation::Open))
+    }
+
+    fn open_with_options(&self, options: &OpenOptions) -> FileSystemResult<FileHandle> {
+        let file = self.backend
+            .open_with_options(self.path.as_path(), options)
+            .map_err(|error| self.error_context(error, Operation::Open))?;
+        Ok(FileHandle::new(file, self.to_path_buf(), self.root_dir))
+    }
+
+    //Read the whole file.
+    fn read(&self) -> FileSystemResult<Vec<u8>> {
+        let mut content = Vec::new();
+        self.open()?
+            .read_to_end(&mut content)
+            .map_err(|error| self.error_context(FileSystemError::from(error), Operation::Read))?;
+        Ok(content)
+    }
+
+    fn read_dir(&self) -> FileSystemResult<Vec<DirEntry>> {
+        self.backend.read_dir(self.path.as_path()).map_err(|error| self.error_context(error, Operation::ReadDir))
+    }
+
+    fn create(&self) -> FileSystemResult<Box<dyn Write + Send>> {
+        self.backend.create(self.path.as_path()).map_err(|error| self.error_context(error, Operation::Create))
+    }
+
+    fn append(&self) -> FileSystemResult<Box<dyn Write + Send>> {
+        self.backend.append(self.path.as_path()).map_err(|error| self.error_context(error, Operation::Append))
+    }
+
+    fn write(&self, content: &[u8]) -> FileSystemResult<()> {
+        self.backend.write(self.path.as_path(), content).map_err(|error| self.error_context(error, Operation::Write))
+    }
+
+    fn mkdir(&self) -> FileSystemResult<()> {
+        self.backend.mkdir(self.path.as_path()).map_err(|error| self.error_context(error, Operation::Mkdir))
+    }
+
+    fn rm(&self) -> FileSystemResult<()> {
+        self.backend.rm(self.path.as_path()).map_err(|error| self.error_context(error, Operation::Rm))
+    }
 
-pub fn write_all<T: Write>(to: &mut BufWriter<T>, from: &[u8]) -> FileSystemResult<()> {
-    to.write_all(from)
-        .map_err(|io_error| FileSystemError::from(io_error))
+    fn rmrf(&self) -> FileSystemResult<()> {
+        self.backend.rmrf(self.path.as_path()).map_err(|error| self.error_context(error, Operation::Rmrf))
+    }
+
+    //Both locations are in the same backend.
+    fn rename(&self, to: &Location) -> FileSystemResult<()> {
+        self.backend.rename(self.path.as_path(), to.path.as_path()).map_err(|error| self.error_context(error, Operation::Rename))
+    }
 }
-*/
 
 #[derive(Debug)]
 pub struct Filesystem {
     directories: GameDirectories,
+    mounts: MountTable,
+    io_pool: OnceLock<IoPool>,
 }
 
 impl Filesystem {
     pub fn new(game_name: &str, game_author: &str) -> FileSystemResult<Self> {
         debug!("Creating a new Filesystem with the game name {}, created by {}", game_name, game_author);
         let directories = GameDirectories::new(game_name, game_author)?;
+        Ok(Filesystem::from_directories(directories))
+    }
 
-        Ok(Filesystem {
+    //Create a Filesystem using directories built beforehand (see GameDirectories::builder).
+    pub fn from_directories(directories: GameDirectories) -> Self {
+        debug!("Creating a new Filesystem from existing game directories");
+        Filesystem {
             directories,
-        })
+            mounts: MountTable::new(),
+            io_pool: OnceLock::new(),
+        }
+    }
+
+    //Create a Filesystem living entirely in memory, for the tests and the tools.
+    //The root directories are placed under /user and /working, and an empty MemoryFilesystem
+    //is mounted at /: nothing touches the disk, as long as initialize_directories isn't called.
+    pub fn in_memory(game_name: &str, game_author: &str) -> FileSystemResult<Self> {
+        debug!("Creating an in-memory Filesystem with the game name {}, created by {}", game_name, game_author);
+        let directories = GameDirectories::builder(game_name, game_author)
+            .base_directory("/user")
+            .directory(RootDir::WorkingDirectory, "/working")
+            .build()?;
+        let mut filesystem = Filesystem::from_directories(directories);
+        filesystem.mount("/", MountSource::Memory(MemoryFilesystem::new()))?;
+        Ok(filesystem)
+    }
+
+    //Check if the user directories are kept next to the executable (see GameDirectoriesBuilder::portable).
+    pub fn is_portable(&self) -> bool {
+        self.directories.is_portable()
+    }
+
+    //Create the root directories of the game and check that they are writable (see GameDirectories::initialize).
+    pub fn initialize_directories(&self) -> DirectoriesReport {
+        self.directories.initialize()
+    }
+
+    //Mount a source at a virtual path (e.g. /data).
+    pub fn mount<P: AsRef<Path>>(&mut self, point: P, source: MountSource) -> FileSystemResult<()> {
+        debug!("Mounting the {} at the virtual path {}", source, point.as_ref().display());
+        self.mounts.mount(point, source)
+    }
+
+    //Remove the source mounted at a virtual path.
+    pub fn unmount<P: AsRef<Path>>(&mut self, point: P) -> FileSystemResult<MountSource> {
+        debug!("Unmounting the virtual path {}", point.as_ref().display());
+        self.mounts.unmount(point)
+    }
+
+    //Translate a path through the mount table, to read from it.
+    //For overlays, the path in the highest priority layer containing the file is returned.
+    //For archives, the path of the archive followed by the path of the entry is returned.
+    //Paths which don't belong to a mount point are returned unchanged.
+    pub fn resolve<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<PathBuf> {
+        trace!("Resolving the path {}", path.as_ref().display());
+        let resolved = self.locate(path.as_ref())?.to_path_buf();
+        trace!("{} resolved to {}", path.as_ref().display(), resolved.display());
+        Ok(resolved)
+    }
+
+    //Find where the path leads to: the first candidate containing something, or the first
+    //candidate if nothing could be found.
+    fn locate(&self, path: &Path) -> FileSystemResult<Location> {
+        let mut candidates = self.candidates(path)?;
+        let index = candidates.iter().position(|candidate| candidate.exists()).unwrap_or(0);
+        Ok(candidates.swap_remove(index))
+    }
+
+    //Every location the given path can refer to, from the highest priority to the lowest.
+    fn candidates(&self, path: &Path) -> FileSystemResult<Vec<Location>> {
+        match self.mounts.resolve(path) {
+            Some((source, relative)) => {
+                let mut candidates = Vec::new();
+                self.source_candidates(source, relative.as_path(), &mut candidates)?;
+                if candidates.is_empty() {
+                    error!("The {} mounted for {} doesn't have any layer !", source, path.display());
+                    return Err(FileSystemError::MountError(format!(
+                        "The {} mounted for {} doesn't have any layer.",
+                        source,
+                        path.display()
+                    )));
+                }
+                Ok(candidates)
+            },
+            None => Ok(vec![self.location(Arc::new(PhysicalFilesystem::new("")), path)]),
+        }
+    }
+
+    fn source_candidates(&self, source: &MountSource, relative: &Path, candidates: &mut Vec<Location>) -> FileSystemResult<()> {
+        match *source {
+            MountSource::Overlay(ref overlay) => {
+                for layer in overlay.layers() {
+                    self.source_candidates(layer.source(), relative, candidates)?;
+                }
+            },
+            _ => candidates.push(self.location(self.source_backend(source)?, relative)),
+        }
+        Ok(())
+    }
+
+    //Where the path is on the disk, in every layer storing its files as plain files or directories.
+    pub(crate) fn physical_paths(&self, path: &Path) -> FileSystemResult<Vec<PathBuf>> {
+        Ok(self.candidates(path)?
+            .iter()
+            .filter_map(|candidate| candidate.backend.physical_path(candidate.path.as_path()))
+            .collect())
+    }
+
+    fn location<P: Into<PathBuf>>(&self, backend: Arc<dyn Backend>, path: P) -> Location {
+        let path = path.into();
+        let root_dir = backend
+            .physical_path(path.as_path())
+            .and_then(|physical_path| self.directories.root_dir_of(physical_path));
+        Location {
+            backend,
+            path,
+            root_dir,
+        }
+    }
+
+    //The backend storing the files of the source. Overlays are made of several backends, see their layers.
+    fn source_backend(&self, source: &MountSource) -> FileSystemResult<Arc<dyn Backend>> {
+        match *source {
+            MountSource::Root(root_dir) => Ok(Arc::new(PhysicalFilesystem::new(self.path(root_dir)?))),
+            MountSource::Directory(ref directory) => Ok(Arc::new(PhysicalFilesystem::new(directory.clone()))),
+            MountSource::Zip(ref archive) => Ok(Arc::new(archive.clone())),
+            MountSource::Pack(ref pack) => Ok(Arc::new(pack.clone())),
+            MountSource::Memory(ref memory) => Ok(Arc::new(memory.clone())),
+            MountSource::Backend(ref backend) => Ok(backend.clone()),
+            MountSource::Overlay(_) => {
+                error!("The {} isn't a single backend !", source);
+                Err(FileSystemError::MountError(format!(
+                    "The {} isn't a single backend.",
+                    source
+                )))
+            },
+        }
+    }
+
+    //Translate a path through the mount table, to write to it.
+    //For overlays, the location in the writable layer is returned.
+    fn resolve_for_write(&self, path: &Path) -> FileSystemResult<Location> {
+        trace!("Resolving the path {} for writing", path.display());
+        match self.mounts.resolve(path) {
+            Some((source, relative)) => self.source_write_location(source, relative.as_path()),
+            None => Ok(self.location(Arc::new(PhysicalFilesystem::new("")), path)),
+        }
+    }
+
+    fn source_write_location(&self, source: &MountSource, relative: &Path) -> FileSystemResult<Location> {
+        match *source {
+            MountSource::Overlay(ref overlay) => match overlay.write_layer() {
+                Some(layer) => self.source_write_location(layer.source(), relative),
+                None => {
+                    error!("The {} doesn't have a writable layer !", source);
+                    Err(FileSystemError::MountError(format!(
+                        "The {} doesn't have a writable layer.",
+                        source
+                    )))
+                },
+            },
+            _ => Ok(self.location(self.source_backend(source)?, relative)),
+        }
+    }
+
+    //Resolve the path for writing a file. If the parent directory only exists in
+    //the read-only layers of an overlay, it is created in the writable layer.
+    fn file_write_location(&self, path: &Path) -> FileSystemResult<Location> {
+        let target = self.resolve_for_write(path)?;
+        if let (Some(parent), Some(virtual_parent)) = (target.parent(), path.parent()) {
+            if target.to_path_buf().as_path() != path && !parent.exists() && self.locate(virtual_parent)?.is_dir() {
+                trace!("Creating the directory {} in the writable layer", parent.to_path_buf().display());
+                parent.mkdir()?;
+            }
+        }
+        Ok(target)
     }
 
     pub fn get_absolute_path<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<PathBuf> {
         debug!("Getting the absolute path of {}", path.as_ref().display());
-        fs::canonicalize(path.as_ref()).map_err(|io_error| FileSystemError::from(io_error))
+        let location = self.locate(path.as_ref())?;
+        location.absolute_path()
     }
 
-    //Open file at path with options
-    fn open_with_options<P, O>(&self, path: P, open_options: O) -> FileSystemResult<File> where
-        P: AsRef<Path>,
-        O: AsRef<OpenOptions>,
-    {
-        trace!("Opening file at path {} with options {}", path.as_ref().display(), open_options.as_ref());
-        open_options.as_ref()
-            .to_fs_openoptions()
-            .open(path.as_ref())
-            .map_err(|io_error| FileSystemError::from(io_error))
+    //Get the size, the type and the modification time of the file or directory at path.
+    pub fn metadata<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<Metadata> {
+        debug!("Getting the metadata of {}", path.as_ref().display());
+        let location = self.locate(path.as_ref())?;
+        location.metadata()
     }
 
     //Open file at path to read
-    pub fn open<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<BufReader<Box<dyn ReadSeek>>> {
         debug!("Opening file at path {}", path.as_ref().display());
-        let buf = self.open_with_options(path.as_ref(), OpenOptions::new().set_read(true))?;
+        let location = self.locate(path.as_ref())?;
+        let buf = location.open()?;
         Ok(BufReader::new(buf))
     }
 
     //Open file at path for writing, truncates if file already exist
-    pub fn create<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<BufWriter<Box<dyn Write + Send>>> {
         debug!("Creating/truncating file at path {}", path.as_ref().display());
-        let buf = self.open_with_options(
-            path.as_ref(),
-            OpenOptions::new()
-                .set_create(true)
-                .set_write(true)
-                .set_truncate(true),
-        )?;
+        let target = self.file_write_location(path.as_ref())?;
+        let buf = target.create()?;
         Ok(BufWriter::new(buf))
     }
 
     //Open the file at path for appending, creating it if necessary
-    pub fn append<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<BufWriter<File>> {
+    pub fn append<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<BufWriter<Box<dyn Write + Send>>> {
         debug!("Appending/Creating file at path {}", path.as_ref().display());
-        let buf = self.open_with_options(
-            path.as_ref(),
-            OpenOptions::new()
-                .set_create(true)
-                .set_append(true)
-                .set_write(true),
-        )?;
+        let target = self.file_write_location(path.as_ref())?;
+        if !target.exists() {
+            //The file may exist in a read-only layer of an overlay, copy it before appending to it.
+            let source = self.locate(path.as_ref())?;
+            if source.is_file() {
+                trace!("Copying {} to the writable layer before appending to it", source.to_path_buf().display());
+                target.write(source.read()?.as_slice())?;
+            }
+        }
+        let buf = target.append()?;
         Ok(BufWriter::new(buf))
     }
 
+    //Open the file at path with the given options, to read, write and seek in the same file.
+    //The read-only backends, like the archives, can only open their files to read.
+    pub fn open_with_options<P: AsRef<Path>>(&self, path: P, options: &OpenOptions) -> FileSystemResult<FileHandle> {
+        debug!("Opening file at path {} with options {}", path.as_ref().display(), options);
+        options.validate()?;
+        if !options.write() && !options.append() {
+            return self.locate(path.as_ref())?.open_with_options(options);
+        }
+
+        let target = self.file_write_location(path.as_ref())?;
+        if !target.exists() {
+            //The file may exist in a read-only layer of an overlay.
+            let source = self.locate(path.as_ref())?;
+            if source.is_file() && options.create_new() {
+                error!("{} already exists !", source.to_path_buf().display());
+                return Err(FileSystemError::io(
+                    Operation::Open,
+                    &[source.to_path_buf()],
+                    io::Error::new(io::ErrorKind::AlreadyExists, "The file already exists in a read-only layer"),
+                ).with_root_dir(source.root_dir));
+            }
+            if source.is_file() && !options.truncate() {
+                trace!("Copying {} to the writable layer before opening it", source.to_path_buf().display());
+                target.write(source.read()?.as_slice())?;
+            }
+        }
+        target.open_with_options(options)
+    }
+
+    //Open the file at path for writing atomically: the file is only replaced when the writer is
+    //committed, and left untouched if the writer is dropped (see AtomicWriter).
+    pub fn create_atomic<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<AtomicWriter> {
+        debug!("Creating file at path {} atomically", path.as_ref().display());
+        let target = self.file_write_location(path.as_ref())?;
+        match target.backend.physical_path(target.path.as_path()) {
+            Some(physical_path) => AtomicWriter::new(physical_path),
+            None => Ok(AtomicWriter::buffered(target.backend, target.path)),
+        }
+    }
+
+    //Replace the content of the file at path atomically: after a crash, the file holds either
+    //its old content or the new one, never a part of it.
+    pub fn write_atomic<P: AsRef<Path>>(&self, path: P, content: &[u8]) -> FileSystemResult<()> {
+        let mut writer = self.create_atomic(path)?;
+        writer.write_all(content)?;
+        writer.commit()
+    }
+
+    //The lock file at path, on the disk: only the files on the disk can be locked.
+    fn lock_location(&self, path: &Path) -> FileSystemResult<(Location, PathBuf)> {
+        let target = self.file_write_location(path)?;
+        match target.backend.physical_path(target.path.as_path()) {
+            Some(physical_path) => Ok((target, physical_path)),
+            None => {
+                error!("{} isn't on the disk, it can't be locked !", target.to_path_buf().display());
+                Err(FileSystemError::MountError(format!(
+                    "{} isn't on the disk, it can't be locked.",
+                    target.to_path_buf().display()
+                )))
+            },
+        }
+    }
+
+    //Lock the file at path, creating it if necessary, and wait as long as necessary for the lock (see FileLock).
+    //The files written atomically are replaced, lock a separate file to protect them (e.g. slot_1.sav.lock).
+    pub fn lock<P: AsRef<Path>>(&self, path: P, mode: LockMode) -> FileSystemResult<FileLock> {
+        debug!("Taking a {} lock on {}", mode, path.as_ref().display());
+        let (target, physical_path) = self.lock_location(path.as_ref())?;
+        FileLock::lock(physical_path, mode).map_err(|error| target.error_context(error, Operation::Lock))
+    }
+
+    //Lock the file at path, if nobody else holds a conflicting lock. None otherwise.
+    pub fn try_lock<P: AsRef<Path>>(&self, path: P, mode: LockMode) -> FileSystemResult<Option<FileLock>> {
+        debug!("Trying to take a {} lock on {}", mode, path.as_ref().display());
+        let (target, physical_path) = self.lock_location(path.as_ref())?;
+        FileLock::try_lock(physical_path, mode).map_err(|error| target.error_context(error, Operation::Lock))
+    }
+
+    //Lock the file at path, waiting at most timeout for the lock. None if it couldn't be taken in time.
+    pub fn lock_timeout<P: AsRef<Path>>(&self, path: P, mode: LockMode, timeout: Duration) -> FileSystemResult<Option<FileLock>> {
+        debug!("Taking a {} lock on {}, waiting at most {:?}", mode, path.as_ref().display(), timeout);
+        let (target, physical_path) = self.lock_location(path.as_ref())?;
+        FileLock::lock_timeout(physical_path, mode, timeout).map_err(|error| target.error_context(error, Operation::Lock))
+    }
+
+    //Detect another instance of the game, by locking the INSTANCE_LOCK_FILE of the UserDataRoot.
+    //None if another instance holds the lock. Otherwise, the lock must be kept as long as the game runs.
+    pub fn lock_instance(&self) -> FileSystemResult<Option<FileLock>> {
+        debug!("Checking that no other instance of the game is running");
+        let data_root = self.path(RootDir::UserDataRoot)?;
+        self.mkdir(data_root.as_path())?;
+        let lock = self.try_lock(data_root.join(INSTANCE_LOCK_FILE), LockMode::Exclusive)?;
+        if lock.is_none() {
+            warn!("Another instance of the game is already running !");
+        }
+        Ok(lock)
+    }
+
     //create directory at path
     pub fn mkdir<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<()> {
         debug!("Creating directory at path {}", path.as_ref().display());
-        fs::DirBuilder::new()
-            .recursive(true)
-            .create(path.as_ref())
-            .map_err(|io_error| FileSystemError::from(io_error))
+        let target = self.resolve_for_write(path.as_ref())?;
+        target.mkdir()
     }
 
     //remove a file
     pub fn rm<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<()> {
-        if path.as_ref().is_dir() {
+        let target = self.resolve_for_write(path.as_ref())?;
+        if target.is_dir() {
             debug!("Removing empty directory at path {}", path.as_ref().display());
-            fs::remove_dir(path.as_ref()).map_err(|io_error| FileSystemError::from(io_error))
         } else {
             debug!("Removing file at path: {}", path.as_ref().display());
-            fs::remove_file(path.as_ref()).map_err(|io_error| FileSystemError::from(io_error))
         }
+        target.rm()
+    }
+
+    //Move the file or the directory at from to to, replacing the file at to if it exists.
+    //Both paths must be in the same mount: a file isn't copied from a mount to another.
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&self, from: P, to: Q) -> FileSystemResult<()> {
+        debug!("Renaming {} to {}", from.as_ref().display(), to.as_ref().display());
+        let same_mount = match (self.mounts.resolve(from.as_ref()), self.mounts.resolve(to.as_ref())) {
+            (Some((from_source, _)), Some((to_source, _))) => ptr::eq(from_source, to_source),
+            (None, None) => true,
+            _ => false,
+        };
+        if !same_mount {
+            error!("{} and {} aren't in the same mount, the file can't be renamed !", from.as_ref().display(), to.as_ref().display());
+            return Err(FileSystemError::MountError(format!(
+                "{} and {} aren't in the same mount, the file can't be renamed.",
+                from.as_ref().display(),
+                to.as_ref().display()
+            )));
+        }
+        let source = self.resolve_for_write(from.as_ref())?;
+        let target = self.file_write_location(to.as_ref())?;
+        source.rename(&target)
     }
 
     //remove file or directory and all its contents
     pub fn rmrf<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<()> {
         debug!("Removing file/dir at path {}", path.as_ref().display());
-        remove_dir_all::remove_dir_all(path.as_ref()).map_err(|io_error| FileSystemError::from(io_error))
+        let target = self.resolve_for_write(path.as_ref())?;
+        target.rmrf()
     }
 
-    //Retrieve all file entries in the given directory (recursive).
-    pub fn read_dir<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<fs::ReadDir> {
+    //Retrieve all file entries in the given directory.
+    //For overlays, the listings of all the layers are merged, and the entries shadowed
+    //by a layer with a higher priority are hidden.
+    pub fn read_dir<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<ReadDir> {
         debug!("Getting all entries in the directory at path {}", path.as_ref().display());
-        fs::read_dir(path.as_ref()).map_err(|io_error| FileSystemError::from(io_error))
+        let mut file_names = HashSet::new();
+        let mut entries = Vec::new();
+        let mut listed = false;
+
+        for candidate in self.candidates(path.as_ref())? {
+            if !candidate.exists() {
+                continue;
+            }
+            if !candidate.is_dir() {
+                //A file shadows the directories of the layers below.
+                break;
+            }
+
+            trace!("Listing the entries of {}", candidate.to_path_buf().display());
+            for entry in candidate.read_dir()? {
+                if file_names.insert(entry.file_name().to_os_string()) {
+                    entries.push(DirEntry::new(path.as_ref(), entry.file_name().to_os_string(), entry.is_dir()));
+                }
+            }
+            listed = true;
+        }
+
+        if !listed {
+            //Let the source report why the directory can't be read.
+            self.locate(path.as_ref())?.read_dir()?;
+        }
+
+        Ok(ReadDir::new(entries))
+    }
+
+    //The I/O threads are only started the first time they are needed.
+    fn io_pool(&self) -> FileSystemResult<&IoPool> {
+        if let Some(io_pool) = self.io_pool.get() {
+            return Ok(io_pool);
+        }
+        let io_pool = IoPool::new(IO_THREAD_COUNT)?;
+        Ok(self.io_pool.get_or_init(|| io_pool))
+    }
+
+    //Read the whole file at path on the I/O threads.
+    //The path is resolved immediately, changing the mount points afterwards doesn't affect the operation.
+    pub fn read_async<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<IoHandle<Vec<u8>>> {
+        self.read_async_with_priority(path, IoPriority::Normal)
+    }
+
+    //Read the whole file at path on the I/O threads, before the operations with a lower priority.
+    pub fn read_async_with_priority<P: AsRef<Path>>(&self, path: P, priority: IoPriority) -> FileSystemResult<IoHandle<Vec<u8>>> {
+        debug!("Reading the file at path {} asynchronously, with the {:?} priority", path.as_ref().display(), priority);
+        let location = self.locate(path.as_ref())?;
+        Ok(self.io_pool()?.execute_with_priority(priority, move || {
+            trace!("Reading {} on an I/O thread", location.to_path_buf().display());
+            location.read()
+        }))
+    }
+
+    //Write the content to the file at path on the I/O threads, truncating the file if it already exists.
+    pub fn write_async<P: AsRef<Path>>(&self, path: P, content: Vec<u8>) -> FileSystemResult<IoHandle<()>> {
+        self.write_async_with_priority(path, content, IoPriority::Normal)
+    }
+
+    //Write the content to the file at path on the I/O threads, before the operations with a lower priority.
+    pub fn write_async_with_priority<P: AsRef<Path>>(&self, path: P, content: Vec<u8>, priority: IoPriority) -> FileSystemResult<IoHandle<()>> {
+        debug!("Writing the file at path {} asynchronously, with the {:?} priority", path.as_ref().display(), priority);
+        let target = self.file_write_location(path.as_ref())?;
+        Ok(self.io_pool()?.execute_with_priority(priority, move || {
+            trace!("Writing {} on an I/O thread", target.to_path_buf().display());
+            target.write(content.as_slice())
+        }))
+    }
+
+    //Hash the content of the file at path.
+    pub fn checksum<P: AsRef<Path>>(&self, path: P, algorithm: HashAlgorithm) -> FileSystemResult<Checksum> {
+        debug!("Computing the {} checksum of {}", algorithm, path.as_ref().display());
+        Checksum::from_reader(algorithm, self.open(path)?).map_err(FileSystemError::from)
+    }
+
+    //Every file under the directory, with its path relative to the directory ('/' separated).
+    //The links to directories aren't followed: a link to a parent directory would never end.
+    fn tree_files(&self, directory: &Path, prefix: &str, files: &mut BTreeMap<String, PathBuf>) -> FileSystemResult<()> {
+        for entry in self.read_dir(directory)? {
+            let file_name = match entry.file_name().to_str() {
+                Some(file_name) => file_name,
+                None => {
+                    warn!("Skipping {}, its name isn't valid UTF-8", entry.path().display());
+                    continue;
+                },
+            };
+            let relative = if prefix.is_empty() { String::from(file_name) } else { format!("{}/{}", prefix, file_name) };
+            if entry.is_dir() {
+                if self.locate(entry.path())?.is_symlink() {
+                    warn!("Skipping {}, it is a link to a directory", entry.path().display());
+                    continue;
+                }
+                self.tree_files(entry.path(), relative.as_str(), files)?;
+            } else {
+                files.insert(relative, entry.path().to_path_buf());
+            }
+        }
+        Ok(())
+    }
+
+    //List every file under the directory, with its size and checksum.
+    pub fn manifest<P: AsRef<Path>>(&self, directory: P, algorithm: HashAlgorithm) -> FileSystemResult<Manifest> {
+        debug!("Making the {} manifest of {}", algorithm, directory.as_ref().display());
+        let mut files = BTreeMap::new();
+        self.tree_files(directory.as_ref(), "", &mut files)?;
+        let mut manifest = Manifest::new(algorithm);
+        for (relative, path) in files {
+            let size = self.metadata(path.as_path())?.len();
+            manifest.insert(relative.as_str(), size, self.checksum(path.as_path(), algorithm)?)?;
+        }
+        Ok(manifest)
+    }
+
+    //Compare the files under the directory with the manifest.
+    //The files with the wrong size aren't hashed. The files which can't be read are reported as modified.
+    pub fn verify<P: AsRef<Path>>(&self, directory: P, manifest: &Manifest) -> FileSystemResult<VerifyReport> {
+        debug!("Verifying {} against its manifest", directory.as_ref().display());
+        let mut files = BTreeMap::new();
+        if self.metadata(directory.as_ref()).is_ok() {
+            self.tree_files(directory.as_ref(), "", &mut files)?;
+        }
+
+        let mut missing = Vec::new();
+        let mut modified = Vec::new();
+        for (relative, expected) in manifest.iter() {
+            let path = match files.remove(relative) {
+                Some(path) => path,
+                None => {
+                    missing.push(relative.clone());
+                    continue;
+                },
+            };
+            let intact = self.metadata(path.as_path()).and_then(|metadata| {
+                Ok(metadata.len() == expected.size()
+                    && &self.checksum(path.as_path(), manifest.algorithm())? == expected.checksum())
+            });
+            match intact {
+                Ok(true) => {},
+                Ok(false) => {
+                    warn!("{} doesn't match its manifest", path.display());
+                    modified.push(relative.clone());
+                },
+                //Removed since the directory has been listed.
+                Err(ref error) if error.kind() == ErrorKind::NotFound => {
+                    warn!("{} has disappeared: {}", path.display(), error);
+                    missing.push(relative.clone());
+                },
+                Err(error) => {
+                    warn!("{} couldn't be verified: {}", path.display(), error);
+                    modified.push(relative.clone());
+                },
+            }
+        }
+        Ok(VerifyReport::new(missing, files.into_keys().collect(), modified))
+    }
+
+    //The save slots stored in the UserSaveRoot.
+    pub fn save_slots(&self) -> FileSystemResult<SaveSlots<'_>> {
+        Ok(SaveSlots::new(self, self.path(RootDir::UserSaveRoot)?))
+    }
+
+    //Watch the directory at path, and its subdirectories.
+    //The events are reported under path: watching /assets reports /assets/textures/wall.png.
+    pub fn watch<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<FileWatcher> {
+        self.watch_with_options(path, &WatchOptions::new())
+    }
+
+    //Watch the directory at path, relative to the root directory.
+    //The events are reported with the paths built by construct_path_from_root.
+    pub fn watch_root(&self, root_dir: RootDir, path: &str) -> FileSystemResult<FileWatcher> {
+        self.watch(self.construct_path_from_root(root_dir, path)?)
+    }
+
+    //Watch the directory at path with the given options.
+    //For overlays, every layer on the disk is watched. Archives never change, they aren't watched.
+    pub fn watch_with_options<P: AsRef<Path>>(&self, path: P, options: &WatchOptions) -> FileSystemResult<FileWatcher> {
+        debug!("Watching the path {}", path.as_ref().display());
+        let layers = self.candidates(path.as_ref())?;
+        let directories: Vec<(PathBuf, PathBuf)> = layers
+            .iter()
+            .filter_map(|candidate| candidate.backend.physical_path(candidate.path.as_path()))
+            .filter(|directory| directory.is_dir())
+            .map(|directory| (directory, path.as_ref().to_path_buf()))
+            .collect();
+
+        if directories.is_empty() {
+            error!("{} doesn't lead to a directory on the disk, it can't be watched !", path.as_ref().display());
+            return Err(FileSystemError::WatchError(format!(
+                "{} doesn't lead to a directory on the disk, it can't be watched.",
+                path.as_ref().display()
+            )));
+        }
+        FileWatcher::new(directories, Filesystem::visibility_check(layers), options)
     }
 
-    fn path(&self, root_dir: RootDir) -> FileSystemResult<PathBuf> {
+    //Compare the layer of a change with the layer providing the file now, the first one containing it.
+    fn visibility_check(layers: Vec<Location>) -> VisibilityCheck {
+        Box::new(move |physical_path: &Path| {
+            let changed = layers.iter().enumerate().find_map(|(index, layer)| {
+                layer.backend
+                    .physical_path(layer.path.as_path())
+                    .and_then(|directory| physical_path.strip_prefix(directory).ok().map(Path::to_path_buf))
+                    .map(|relative| (index, relative))
+            });
+            let (changed, relative) = match changed {
+                Some(changed) => changed,
+                None => return Visibility::Visible,
+            };
+            let provider = layers.iter().position(|layer| {
+                if relative.as_os_str().is_empty() {
+                    layer.exists()
+                } else {
+                    layer.backend.exists(layer.path.join(relative.as_path()).as_path())
+                }
+            });
+            match provider {
+                Some(provider) if provider < changed => Visibility::Shadowed,
+                Some(provider) if provider > changed => Visibility::Uncovered,
+                _ => Visibility::Visible,
+            }
+        })
+    }
+
+    pub(crate) fn path(&self, root_dir: RootDir) -> FileSystemResult<PathBuf> {
         debug!("Getting the full path of the {}.", root_dir);
         match self.directories.get(&root_dir) {
             Some(pathbuf_ref) => {
@@ -182,6 +795,28 @@ impl Filesystem {
         }
     }
 
+    //Restrict the operations to the root directory, for the paths which can't be trusted (mods, scripts...).
+    //See the sandbox module: the paths escaping the root directory are rejected with a SandboxError.
+    pub fn sandbox(&self, root_dir: RootDir) -> FileSystemResult<Sandbox<'_>> {
+        debug!("Creating a sandbox in the {}", root_dir);
+        Sandbox::new(self, root_dir)
+    }
+
+    //Build the path of the checked relative path in the root directory.
+    //Unlike construct_path_from_root, the paths leaving the root directory are rejected.
+    pub fn sandboxed_path_from_root<P: AsRef<Path>>(&self, root_dir: RootDir, path: P) -> FileSystemResult<PathBuf> {
+        self.sandbox(root_dir)?.path(path)
+    }
+
+    //The OS path of the virtual path, in the root directory.
+    //Virtual paths are normalized and can't go above the root, the result always stays in the root directory.
+    pub fn virtual_path_from_root<P: AsRef<VirtualPath>>(&self, root_dir: RootDir, path: P) -> FileSystemResult<PathBuf> {
+        trace!("Creating the full path of the virtual path {}, according to the {}", path.as_ref(), root_dir);
+        Ok(path.as_ref().to_path_from(self.path(root_dir)?.as_path()))
+    }
+
+    //The path is trusted: it is simply appended to the root directory.
+    //Use sandbox or sandboxed_path_from_root for the paths coming from the mods or the players.
     pub fn construct_path_from_root(
         &self,
         root_dir: RootDir,
@@ -196,69 +831,39 @@ impl Filesystem {
 
 
 
-/*
-pub fn async_read<T: Read + Send>(
-    from: &mut BufReader<T>,
-    to: &mut [u8],
-    thread_pool: &ThreadPool,
-) -> FileSystemResult<usize> {
-    thread_pool.install(|| read(from, to))
-}
-
-pub fn async_read_to_end<T: Read + Send>(
-    from: &mut BufReader<T>,
-    to: &mut Vec<u8>,
-    thread_pool: &ThreadPool,
-) -> FileSystemResult<usize> {
-    thread_pool.install(|| read_to_end(from, to))
-}
-
-pub fn async_read_to_string<T: Read + Send>(
-    from: &mut BufReader<T>,
-    to: &mut String,
-    thread_pool: &ThreadPool,
-) -> FileSystemResult<usize> {
-    thread_pool.install(|| read_to_string(from, to))
-}
-
-pub fn async_read_exact<T: Read + Send>(
-    from: &mut BufReader<T>,
-    to: &mut [u8],
-    thread_pool: &ThreadPool,
-) -> FileSystemResult<()> {
-    thread_pool.install(|| read_exact(from, to))
-}
-
-pub fn async_write<T: Write + Send>(
-    to: &mut BufWriter<T>,
-    from: &[u8],
-    thread_pool: &ThreadPool,
-) -> FileSystemResult<usize> {
-    thread_pool.install(|| write(to, from))
-}
-
-pub fn async_write_all<T: Write + Send>(
-    to: &mut BufWriter<T>,
-    from: &[u8],
-    thread_pool: &ThreadPool,
-) -> FileSystemResult<()> {
-    thread_pool.install(|| write_all(to, from))
-}
-*/
 
 #[cfg(test)]
 mod filesystem_test {
     use super::*;
-    use std::io::Write;
+    use std::fs;
+    use std::io::{Read, Seek, SeekFrom};
     //use rayon::Configuration;
     //use rayon::ThreadPool;
-    use game_directories::{GameDirectories, RootDir};
+    use game_directories::{RootDir, PORTABLE_DIRECTORY, PORTABLE_MARKER};
+    use filesystem_error::ErrorKind;
+    use mount::Overlay;
+    use zip_archive::ZipArchive;
+    use zip_archive::zip_archive_test::write_zip;
+    use pack::{PackArchive, PackWriter, PACK_COMPRESSED};
+    use io_pool::io_pool_test::block_on;
+    use watcher::WatchEvent;
+    use virtual_path::VirtualPathBuf;
+    use std::time::Duration;
+    use std::thread;
+    use tempfile;
+
+    //A Filesystem keeping the user directories in home, instead of the real home directory.
+    fn test_filesystem(home: &Path) -> Filesystem {
+        let directories = GameDirectories::builder("test_filesystem", "Malkaviel")
+            .base_directory(home)
+            .build()
+            .expect("Couldn't create GameDirs");
+        Filesystem::from_directories(directories)
+    }
 
     #[test]
     fn filesystem_io_operations() {
-        let fs =
-            Filesystem::new("test_filesystem_maskerad", "Malkaviel")
-                .expect("Couldn't create FS");
+        let fs = Filesystem::in_memory("test_filesystem", "Malkaviel").unwrap();
 
         let current_dir_dir_test = fs
             .construct_path_from_root(RootDir::WorkingDirectory, "dir_test")
@@ -266,7 +871,8 @@ mod filesystem_test {
 
         fs.mkdir(current_dir_dir_test.as_path())
             .expect("Could not create dir with current_dir_dir_test as path");
-        assert!(current_dir_dir_test.exists());
+        assert!(fs.metadata(current_dir_dir_test.as_path()).unwrap().is_dir());
+        assert!(!current_dir_dir_test.exists());
 
         //user logs
         let user_log_dir_test = fs
@@ -274,92 +880,653 @@ mod filesystem_test {
             .expect("Could not create user_log_dir_test");
         fs.mkdir(user_log_dir_test.as_path())
             .expect("Could not create dir with user_log_dir_test as path");
-        assert!(user_log_dir_test.exists());
+        assert!(fs.metadata(user_log_dir_test.as_path()).is_ok());
 
         let file_test = fs
             .construct_path_from_root(RootDir::EngineLogRoot, "log_dir_test/file_test.txt")
             .expect("Could not create file_test.txt");
         let mut log_dir_bufwriter =
             fs.create(file_test.as_path()).expect("Could not create log_dir_test/file_test.txt");
-
         log_dir_bufwriter.write_all(b"text_test\n").unwrap();
+        drop(log_dir_bufwriter);
+        fs.append(file_test.as_path()).unwrap().write_all(b"appended\n").unwrap();
 
-        /*
-        let async_dir = game_dirs
-            .construct_path_from_root(RootDir::UserLogRoot, "async_dir")
-            .expect("Could not create async_dir");
-        mkdir(async_dir.as_path()).expect("Could not create dir with async_dir as path");
-        assert!(async_dir.exists());
+        let mut content = String::new();
+        fs.open(file_test.as_path()).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "text_test\nappended\n");
+        let metadata = fs.metadata(file_test.as_path()).unwrap();
+        assert!(metadata.is_file());
+        assert_eq!(metadata.len(), 19);
+        assert!(!metadata.read_only());
+        assert_eq!(fs.read_dir(user_log_dir_test.as_path()).unwrap().count(), 1);
+        assert!(fs.get_absolute_path(file_test.as_path()).is_err());
 
-        //test async functionalities.
-        let thread_pool = Configuration::new()
-            .build()
-            .expect("Could not create the thread pool.");
+        assert!(fs.rm(user_log_dir_test.as_path()).is_err());
+        fs.rm(file_test.as_path()).unwrap();
+        fs.rmrf(current_dir_dir_test.as_path()).unwrap();
+        assert!(fs.metadata(current_dir_dir_test.as_path()).is_err());
+    }
 
-        let async_log_dir_test = game_dirs
-            .construct_path_from_root(RootDir::UserLogRoot, "async_dir/async_log_dir_test.txt")
-            .expect("Could not create async_log_dir_test");
-        {
-            let mut log_bufwriter =
-                create(async_log_dir_test.as_path()).expect("Could not create the bufwriter");
+    #[test]
+    fn filesystem_memory_mount() {
+        let temp = tempfile::tempdir().unwrap();
+        fs::write(temp.path().join("base.txt"), b"base").unwrap();
+        let home = tempfile::tempdir().unwrap();
+        let mut fs = test_filesystem(home.path());
+        let memory = MemoryFilesystem::new();
+        let mut overlay = Overlay::new();
+        overlay
+            .add_layer(MountSource::Directory(temp.path().to_path_buf()), 0)
+            .add_write_layer(MountSource::Memory(memory.clone()), 10);
+        fs.mount("/data", MountSource::Overlay(overlay)).unwrap();
 
+        //The writes land in memory, the directory on the disk is left untouched.
+        fs.append("/data/base.txt").unwrap().write_all(b" + memory").unwrap();
+        assert_eq!(memory.read("base.txt").unwrap(), b"base + memory");
+        assert_eq!(fs::read(temp.path().join("base.txt")).unwrap(), b"base");
+        block_on(fs.write_async("/data/async.txt", b"async".to_vec()).unwrap()).unwrap();
+        assert_eq!(block_on(fs.read_async("/data/async.txt").unwrap()).unwrap(), b"async");
+        assert!(!temp.path().join("async.txt").exists());
+        fs.rename("/data/async.txt", "/data/renamed.txt").unwrap();
+        assert_eq!(memory.read("renamed.txt").unwrap(), b"async");
+        match fs.rename("/data/renamed.txt", temp.path().join("renamed.txt")) {
+            Err(FileSystemError::MountError(_)) => {},
+            result => panic!("A file has been renamed to another mount: {:?}", result),
+        }
 
-            thread_pool.install(|| {
-                log_bufwriter.write(b"test_async_text_1\n").unwrap()
-            });
+        let metadata = fs.metadata("/data/base.txt").unwrap();
+        assert_eq!(metadata.len(), 13);
+        assert!(metadata.modified().is_some());
+        assert!(fs.metadata("/data/missing.txt").is_err());
+    }
 
-            thread_pool.install(|| {
-                log_bufwriter.write(b"test_async_text_2\n").unwrap()
-            });
+    //A read-only backend holding a single file, readme.txt.
+    #[derive(Debug)]
+    struct ReadmeBackend;
 
-            thread_pool.install(|| {
-                log_bufwriter.write(b"test_async_text_3\n").unwrap()
-            });
+    impl Backend for ReadmeBackend {
+        fn display_path(&self, path: &Path) -> PathBuf {
+            Path::new("readme:").join(path)
+        }
 
-        } //bufwriter dropped here, all the write calls will be executed.
+        fn exists(&self, path: &Path) -> bool {
+            self.is_dir(path) || self.is_file(path)
+        }
 
+        fn is_dir(&self, path: &Path) -> bool {
+            path == Path::new("")
+        }
+
+        fn is_file(&self, path: &Path) -> bool {
+            path == Path::new("readme.txt")
+        }
+
+        fn metadata(&self, path: &Path) -> FileSystemResult<Metadata> {
+            Ok(Metadata::new(if self.is_file(path) { 6 } else { 0 }, self.is_dir(path), None, true))
+        }
+
+        fn open(&self, _path: &Path) -> FileSystemResult<Box<dyn ReadSeek>> {
+            Ok(Box::new(::std::io::Cursor::new(b"readme".to_vec())))
+        }
+
+        fn read_dir(&self, path: &Path) -> FileSystemResult<Vec<DirEntry>> {
+            Ok(vec![DirEntry::new(path, "readme.txt".into(), false)])
+        }
+    }
+
+    #[test]
+    fn filesystem_custom_backend() {
+        let mut fs = Filesystem::in_memory("test_filesystem", "Malkaviel").unwrap();
+        let backend: Arc<dyn Backend> = Arc::new(ReadmeBackend);
+        fs.mount("/docs", MountSource::Backend(backend.clone())).unwrap();
+        assert_eq!(fs.unmount("/docs").unwrap(), MountSource::Backend(backend.clone()));
+        fs.mount("/docs", MountSource::Backend(backend)).unwrap();
 
-        let mut bufreader_async =
-            open(async_log_dir_test.as_path()).expect("Could not create bufreader");
         let mut content = String::new();
+        fs.open("/docs/readme.txt").unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "readme");
+        assert_eq!(fs.resolve("/docs/readme.txt").unwrap(), PathBuf::from("readme:/readme.txt"));
+        assert!(fs.metadata("/docs/readme.txt").unwrap().read_only());
+        let entries: Vec<PathBuf> = fs.read_dir("/docs").unwrap().map(|entry| entry.path().to_path_buf()).collect();
+        assert_eq!(entries, vec![PathBuf::from("/docs/readme.txt")]);
 
+        //The write operations aren't implemented, they fail.
+        assert!(fs.create("/docs/readme.txt").is_err());
+        assert!(fs.rm("/docs/readme.txt").is_err());
+        assert!(fs.get_absolute_path("/docs/readme.txt").is_err());
+    }
 
-        thread_pool.install(|| {
-            bufreader_async.read_to_string(&mut content).unwrap()
-        });
-
-        let mut lines = content.lines();
-        assert_eq!(lines.next(), Some("test_async_text_1"));
-        assert_eq!(lines.next(), Some("test_async_text_2"));
-        assert_eq!(lines.next(), Some("test_async_text_3"));
-        assert_eq!(lines.next(), None);
-
-        //Metadata
-        let file_metadata = async_log_dir_test
-            .metadata()
-            .expect("Couldn't get metadata");
-        assert!(file_metadata.is_file());
-        assert!(!file_metadata.is_dir());
-        assert!(file_metadata.len() > 0);
-
-        //remove
-        rm(async_log_dir_test.as_path())
-            .expect("Couldn't delete the file : async_dir/async_log_dir_test.txt");
-        assert!(!async_log_dir_test.exists());
-        rmrf(current_dir_dir_test.as_path()).expect("Couldn't delete dir");
-        assert!(!current_dir_dir_test.exists());
-        */
+    #[test]
+    fn filesystem_atomic_writes() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut fs = test_filesystem(temp.path().join("home").as_path());
+        fs.mount("/temp", MountSource::Directory(temp.path().to_path_buf())).unwrap();
+        fs.mount("/memory", MountSource::Memory(MemoryFilesystem::new())).unwrap();
+
+        for directory in &["/temp", "/memory"] {
+            let save = format!("{}/slot_1.sav", directory);
+            fs.write_atomic(save.as_str(), b"first").unwrap();
+
+            let mut writer = fs.create_atomic(save.as_str()).unwrap();
+            writer.write_all(b"discarded").unwrap();
+            drop(writer);
+            let mut writer = fs.create_atomic(save.as_str()).unwrap();
+            writer.write_all(b"second").unwrap();
+            assert_eq!(block_on(fs.read_async(save.as_str()).unwrap()).unwrap(), b"first");
+            writer.commit().unwrap();
+            assert_eq!(block_on(fs.read_async(save.as_str()).unwrap()).unwrap(), b"second");
+            assert_eq!(fs.read_dir(directory).unwrap().count(), 1);
+        }
+    }
+
+    #[test]
+    fn filesystem_integrity() {
+        let mut fs = Filesystem::in_memory("test_filesystem", "Malkaviel").unwrap();
+        let archive = tempfile::tempdir().unwrap();
+        let archive_path = archive.path().join("data.zip");
+        write_zip(archive_path.as_path(), &[("levels/one.lvl", b"one", true)]);
+        fs.mount("/zip", MountSource::Zip(ZipArchive::open(archive_path.as_path()).unwrap())).unwrap();
+        assert_eq!(fs.checksum("/zip/levels/one.lvl", HashAlgorithm::Crc32).unwrap(), Checksum::from_bytes(HashAlgorithm::Crc32, b"one"));
+
+        fs.mkdir("/install/data/levels").unwrap();
+        fs.write_atomic("/install/data/levels/one.lvl", b"one").unwrap();
+        fs.write_atomic("/install/data/levels/two.lvl", b"two").unwrap();
+        fs.write_atomic("/install/game.exe", b"game").unwrap();
+        let manifest = fs.manifest("/install", HashAlgorithm::Sha256).unwrap();
+        let paths: Vec<&str> = manifest.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, vec!["data/levels/one.lvl", "data/levels/two.lvl", "game.exe"]);
+        assert_eq!(manifest.get("game.exe").unwrap().size(), 4);
+        assert!(fs.verify("/install", &manifest).unwrap().is_identical());
+
+        //Same size, different content.
+        fs.write_atomic("/install/data/levels/one.lvl", b"eno").unwrap();
+        fs.rm("/install/data/levels/two.lvl").unwrap();
+        fs.write_atomic("/install/cheat.dll", b"cheat").unwrap();
+        let report = fs.verify("/install", &manifest).unwrap();
+        assert_eq!(report.missing(), ["data/levels/two.lvl"]);
+        assert_eq!(report.modified(), ["data/levels/one.lvl"]);
+        assert_eq!(report.extra(), ["cheat.dll"]);
+        assert!(!report.is_intact());
+
+        let report = fs.verify("/missing", &manifest).unwrap();
+        assert_eq!(report.missing().len(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn filesystem_integrity_links() {
+        use std::os::unix::fs::symlink;
+
+        let temp = tempfile::tempdir().unwrap();
+        let mut fs = test_filesystem(temp.path().join("home").as_path());
+        let install = temp.path().join("install");
+        fs::create_dir_all(install.join("data")).unwrap();
+        fs::write(install.join("data/one.lvl"), b"one").unwrap();
+        fs::write(install.join("game.exe"), b"game").unwrap();
+        fs::write(install.join("readme.txt"), b"readme").unwrap();
+        //A link to a parent directory isn't followed.
+        symlink(install.as_path(), install.join("data/install")).unwrap();
+        fs.mount("/install", MountSource::Directory(install.clone())).unwrap();
+
+        let manifest = fs.manifest("/install", HashAlgorithm::Crc32).unwrap();
+        let paths: Vec<&str> = manifest.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, vec!["data/one.lvl", "game.exe", "readme.txt"]);
+        assert!(fs.verify("/install", &manifest).unwrap().is_identical());
+
+        //The files which can't be read don't stop the verification.
+        fs::remove_file(install.join("game.exe")).unwrap();
+        symlink(install.join("nowhere"), install.join("game.exe")).unwrap();
+        fs::remove_file(install.join("readme.txt")).unwrap();
+        symlink(install.join("readme.txt"), install.join("readme.txt")).unwrap();
+        let report = fs.verify("/install", &manifest).unwrap();
+        assert_eq!(report.missing(), ["game.exe"]);
+        assert_eq!(report.modified(), ["readme.txt"]);
+        assert!(report.extra().is_empty());
+    }
+
+    #[test]
+    fn filesystem_open_with_options() {
+        let temp = tempfile::tempdir().unwrap();
+        let fs = test_filesystem(temp.path().join("home").as_path());
+        assert!(fs.initialize_directories().is_ok());
+        let save = fs.construct_path_from_root(RootDir::UserSaveRoot, "slot_1.sav").unwrap();
+        fs.write_atomic(save.as_path(), b"version=1;data").unwrap();
+
+        //Patch the header of the save in place.
+        let mut options = OpenOptions::new();
+        options.set_read(true).set_write(true);
+        let mut handle = fs.open_with_options(save.as_path(), &options).unwrap();
+        let mut header = [0; 9];
+        handle.read_exact(&mut header).unwrap();
+        assert_eq!(&header, b"version=1");
+        handle.seek(SeekFrom::Current(-1)).unwrap();
+        handle.write_all(b"2").unwrap();
+        handle.set_len(14).unwrap();
+        assert_eq!(handle.metadata().unwrap().len(), 14);
+        handle.sync_all().unwrap();
+        handle.close().unwrap();
+        assert_eq!(fs::read(save.as_path()).unwrap(), b"version=2;data");
+
+        let error = fs.open_with_options(save.as_path(), options.set_create_new(true)).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(error.context().unwrap().root_dir(), Some(RootDir::UserSaveRoot));
+        let mut handle = fs.open_with_options(save.with_file_name("slot_2.sav"), options.set_mode(0o600)).unwrap();
+        handle.write_all(b"new").unwrap();
+        handle.close().unwrap();
+        match fs.open_with_options(save.as_path(), OpenOptions::new().set_append(true).set_truncate(true)) {
+            Err(FileSystemError::OpenOptionsError(_)) => {},
+            result => panic!("Contradictory options should be rejected: {:?}", result),
+        }
+
+        //The archives can only be read.
+        let mut fs = test_filesystem(temp.path().join("home").as_path());
+        let archive = temp.path().join("data.zip");
+        write_zip(archive.as_path(), &[("levels/one.lvl", b"base", false)]);
+        fs.mount("/data", MountSource::Zip(ZipArchive::open(archive.as_path()).unwrap())).unwrap();
+        let mut handle = fs.open_with_options("/data/levels/one.lvl", OpenOptions::new().set_read(true)).unwrap();
+        let mut content = String::new();
+        handle.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "base");
+        assert!(handle.metadata().unwrap().read_only());
+        assert!(handle.write_all(b"mods").is_err());
+        assert!(fs.open_with_options("/data/levels/one.lvl", OpenOptions::new().set_write(true)).is_err());
+
+        //The files of the read-only layers of an overlay are copied to the writable layer.
+        let mods = temp.path().join("mods");
+        fs::create_dir(mods.as_path()).unwrap();
+        let mut overlay = Overlay::new();
+        overlay
+            .add_layer(MountSource::Zip(ZipArchive::open(archive.as_path()).unwrap()), 0)
+            .add_write_layer(MountSource::Directory(mods.clone()), 10);
+        fs.mount("/overlay", MountSource::Overlay(overlay)).unwrap();
+        let mut handle = fs.open_with_options("/overlay/levels/one.lvl", OpenOptions::new().set_read(true).set_write(true)).unwrap();
+        handle.seek(SeekFrom::End(0)).unwrap();
+        handle.write_all(b" mod").unwrap();
+        handle.close().unwrap();
+        assert_eq!(fs::read(mods.join("levels/one.lvl")).unwrap(), b"base mod");
+    }
+
+    #[test]
+    fn filesystem_locks() {
+        let temp = tempfile::tempdir().unwrap();
+        let fs = test_filesystem(temp.path().join("home").as_path());
+        let other_instance = test_filesystem(temp.path().join("home").as_path());
+
+        let instance = fs.lock_instance().unwrap().unwrap();
+        assert_eq!(instance.path(), fs.construct_path_from_root(RootDir::UserDataRoot, INSTANCE_LOCK_FILE).unwrap());
+        assert!(other_instance.lock_instance().unwrap().is_none());
+        drop(instance);
+        assert!(other_instance.lock_instance().unwrap().is_some());
+
+        assert!(fs.initialize_directories().is_ok());
+        let save_lock = fs.construct_path_from_root(RootDir::UserSaveRoot, "slot_1.sav.lock").unwrap();
+        let writer = fs.lock(save_lock.as_path(), LockMode::Exclusive).unwrap();
+        assert!(other_instance.try_lock(save_lock.as_path(), LockMode::Shared).unwrap().is_none());
+        assert!(other_instance.lock_timeout(save_lock.as_path(), LockMode::Exclusive, Duration::from_millis(10)).unwrap().is_none());
+        writer.unlock().unwrap();
+        assert!(other_instance.try_lock(save_lock.as_path(), LockMode::Shared).unwrap().is_some());
+
+        let error = fs.lock(save_lock.with_file_name("missing/slot_1.sav.lock"), LockMode::Shared).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        assert_eq!(error.context().unwrap().operation(), Some(Operation::Lock));
+        assert_eq!(error.context().unwrap().root_dir(), Some(RootDir::UserSaveRoot));
+
+        let memory = Filesystem::in_memory("test_filesystem", "Malkaviel").unwrap();
+        assert!(memory.lock_instance().is_err());
+    }
+
+    #[test]
+    fn filesystem_error_context() {
+        let home = tempfile::tempdir().unwrap();
+        let fs = test_filesystem(home.path());
+        assert!(fs.initialize_directories().is_ok());
+
+        let save = fs.construct_path_from_root(RootDir::UserSaveRoot, "missing.sav").unwrap();
+        let error = fs.open(save.as_path()).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        let context = error.context().unwrap();
+        assert_eq!(context.operation(), Some(Operation::Open));
+        assert_eq!(context.paths(), [save.as_path()]);
+        assert_eq!(context.root_dir(), Some(RootDir::UserSaveRoot));
+        let message = error.to_string();
+        assert!(message.contains("open failed"));
+        assert!(message.contains(save.to_str().unwrap()));
+
+        fs.mkdir(save.join("slots")).unwrap();
+        let error = fs.create(save.as_path()).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::IsADirectory);
+        assert_eq!(error.context().unwrap().operation(), Some(Operation::Create));
+    }
+
+    #[test]
+    fn filesystem_portable() {
+        let temp = tempfile::tempdir().unwrap();
+        fs::write(temp.path().join(PORTABLE_MARKER), b"").unwrap();
+        let directories = GameDirectories::builder("test_filesystem", "Malkaviel")
+            .executable_directory(temp.path())
+            .build()
+            .expect("Couldn't create GameDirs");
+        let fs = Filesystem::from_directories(directories);
+        assert!(fs.is_portable());
+
+        let save = fs.construct_path_from_root(RootDir::UserSaveRoot, "slot_1.sav").unwrap();
+        assert_eq!(save, temp.path().join(PORTABLE_DIRECTORY).join("data/game_saves/slot_1.sav"));
+        assert!(fs.create(save.as_path()).is_err());
+        assert!(fs.initialize_directories().is_ok());
+        fs.create(save.as_path()).unwrap().write_all(b"save").unwrap();
+        assert!(save.is_file());
+    }
+
+    #[test]
+    fn filesystem_sandbox() {
+        let home = tempfile::tempdir().unwrap();
+        let fs = test_filesystem(home.path());
+        assert!(fs.initialize_directories().is_ok());
+
+        let saves = fs.sandbox(RootDir::UserSaveRoot).unwrap();
+        saves.create("slot_1.sav").unwrap().write_all(b"save").unwrap();
+        assert_eq!(
+            fs.sandboxed_path_from_root(RootDir::UserSaveRoot, "./slot_1.sav").unwrap(),
+            fs.construct_path_from_root(RootDir::UserSaveRoot, "slot_1.sav").unwrap()
+        );
+        match fs.sandboxed_path_from_root(RootDir::UserSaveRoot, "../../../config/maskerad_configuration") {
+            Err(FileSystemError::SandboxError(_)) => {},
+            result => panic!("The path escaped the sandbox: {:?}", result),
+        }
+        assert!(saves.open("/etc/passwd").is_err());
+    }
+
+    #[test]
+    fn filesystem_virtual_paths() {
+        let home = tempfile::tempdir().unwrap();
+        let fs = test_filesystem(home.path());
+        let save = VirtualPathBuf::new("slots\\1\\Game.sav").unwrap();
+        let path = fs.virtual_path_from_root(RootDir::UserSaveRoot, &save).unwrap();
+        assert_eq!(path, fs.construct_path_from_root(RootDir::UserSaveRoot, "slots/1/Game.sav").unwrap());
+        assert_eq!(fs.virtual_path_from_root(RootDir::UserSaveRoot, VirtualPath::root()).unwrap(), fs.path(RootDir::UserSaveRoot).unwrap());
+    }
+
+    #[test]
+    fn filesystem_async_io() {
+        let temp = tempfile::tempdir().unwrap();
+        let home = tempfile::tempdir().unwrap();
+        let mut fs = test_filesystem(home.path());
+        fs.mount("/temp", MountSource::Directory(temp.path().to_path_buf())).unwrap();
+
+        let handles: Vec<IoHandle<()>> = (0..8)
+            .map(|index| fs.write_async(format!("/temp/file_{}.txt", index), format!("content {}", index).into_bytes()).unwrap())
+            .collect();
+        for handle in handles {
+            block_on(handle).unwrap();
+        }
+
+        let mut handle = fs.read_async("/temp/file_3.txt").unwrap();
+        //the mount points can change while the operation is running.
+        fs.unmount("/temp").unwrap();
+        while !handle.is_complete() {
+            thread::yield_now();
+        }
+        assert_eq!(handle.try_take().unwrap().unwrap(), b"content 3");
+        assert!(handle.try_take().is_none());
+
+        let content = block_on(fs.read_async(temp.path().join("file_7.txt")).unwrap()).unwrap();
+        assert_eq!(content, b"content 7");
+        assert!(fs.read_async(temp.path().join("missing.txt")).unwrap().wait().is_err());
+
+        let critical = fs.write_async_with_priority(temp.path().join("critical.txt"), b"critical".to_vec(), IoPriority::Critical).unwrap();
+        critical.wait().unwrap();
+        let streamed = fs.read_async_with_priority(temp.path().join("critical.txt"), IoPriority::Background).unwrap();
+        assert_eq!(streamed.wait().unwrap(), b"critical");
     }
 
     #[test]
     fn filesystem_read_dir() {
-        let fs =
-            Filesystem::new("test_filesystem_blacksmith", "Malkaviel")
-                .expect("Couldn't create GameDirs");
+        let home = tempfile::tempdir().unwrap();
+        let fs = test_filesystem(home.path());
         let src_dir = fs
             .construct_path_from_root(RootDir::WorkingDirectory, "src")
             .unwrap();
         let mut entries = fs.read_dir(src_dir).unwrap();
         assert!(entries.next().is_some());
     }
+
+    #[test]
+    fn filesystem_mount_points() {
+        let home = tempfile::tempdir().unwrap();
+        let mut fs = test_filesystem(home.path());
+        let src_dir = fs
+            .construct_path_from_root(RootDir::WorkingDirectory, "src")
+            .unwrap();
+        fs.mount("/game", MountSource::Root(RootDir::WorkingDirectory)).unwrap();
+        fs.mount("/code", MountSource::Directory(src_dir.clone())).unwrap();
+
+        assert_eq!(fs.resolve("/code/lib.rs").unwrap(), src_dir.join("lib.rs"));
+        assert_eq!(fs.resolve("/game/src/lib.rs").unwrap(), src_dir.join("lib.rs"));
+        assert_eq!(fs.resolve("Cargo.toml").unwrap(), PathBuf::from("Cargo.toml"));
+
+        let mut content = String::new();
+        fs.open("/code/lib.rs").unwrap().read_to_string(&mut content).unwrap();
+        assert!(content.contains("pub mod mount;"));
+        assert!(fs.read_dir("/game/src").unwrap().next().is_some());
+
+        let scratch = tempfile::tempdir().unwrap();
+        fs.mount("/scratch", MountSource::Directory(scratch.path().to_path_buf())).unwrap();
+        fs.mkdir("/scratch/mount_dir_test").unwrap();
+        fs.create("/scratch/mount_dir_test/file.txt").unwrap().write_all(b"mounted").unwrap();
+        assert!(scratch.path().join("mount_dir_test/file.txt").exists());
+        fs.rmrf("/scratch/mount_dir_test").unwrap();
+        assert!(!scratch.path().join("mount_dir_test").exists());
+
+        assert_eq!(fs.unmount("/code").unwrap(), MountSource::Directory(src_dir));
+        assert!(fs.open("/code/lib.rs").is_err());
+    }
+
+    #[test]
+    fn filesystem_overlay_mounts() {
+        let temp = tempfile::tempdir().unwrap();
+        let base = temp.path().join("base");
+        let patch = temp.path().join("patch");
+        let mods = temp.path().join("mods");
+        for &(layer, file, content) in &[
+            (&base, "levels/one.lvl", "base"),
+            (&base, "levels/two.lvl", "base"),
+            (&patch, "levels/two.lvl", "patch"),
+            (&patch, "levels/three.lvl", "patch"),
+            (&mods, "readme.txt", "mods"),
+        ] {
+            let file = layer.join(file);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, content).unwrap();
+        }
+
+        let mut overlay = Overlay::new();
+        overlay
+            .add_layer(MountSource::Directory(base.clone()), 0)
+            .add_layer(MountSource::Directory(patch.clone()), 10)
+            .add_write_layer(MountSource::Directory(mods.clone()), 20);
+        let mut fs = test_filesystem(temp.path().join("home").as_path());
+        fs.mount("/data", MountSource::Overlay(overlay)).unwrap();
+
+        let read = |path: &str| {
+            let mut content = String::new();
+            fs.open(path).unwrap().read_to_string(&mut content).unwrap();
+            content
+        };
+        assert_eq!(read("/data/levels/one.lvl"), "base");
+        assert_eq!(read("/data/levels/two.lvl"), "patch");
+
+        let names: Vec<String> = fs.read_dir("/data/levels")
+            .unwrap()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, vec!["one.lvl", "three.lvl", "two.lvl"]);
+        let root: Vec<(String, bool)> = fs.read_dir("/data")
+            .unwrap()
+            .map(|entry| (entry.path().to_string_lossy().into_owned(), entry.is_dir()))
+            .collect();
+        assert_eq!(root, vec![("/data/levels".to_string(), true), ("/data/readme.txt".to_string(), false)]);
+
+        //writes go to the writable layer, the installed files are untouched.
+        fs.create("/data/levels/one.lvl").unwrap().write_all(b"mods").unwrap();
+        fs.append("/data/levels/two.lvl").unwrap().write_all(b"+mods").unwrap();
+        assert_eq!(read("/data/levels/one.lvl"), "mods");
+        assert_eq!(read("/data/levels/two.lvl"), "patch+mods");
+        assert_eq!(fs::read_to_string(base.join("levels/one.lvl")).unwrap(), "base");
+        assert_eq!(fs::read_to_string(patch.join("levels/two.lvl")).unwrap(), "patch");
+        assert!(mods.join("levels/one.lvl").exists());
+
+        fs.rm("/data/levels/one.lvl").unwrap();
+        assert_eq!(read("/data/levels/one.lvl"), "base");
+        assert!(fs.rm("/data/levels/three.lvl").is_err());
+
+        let mut read_only = Overlay::new();
+        read_only.add_layer(MountSource::Directory(base), 0);
+        fs.mount("/read_only", MountSource::Overlay(read_only)).unwrap();
+        assert!(fs.create("/read_only/file.txt").is_err());
+    }
+
+    #[test]
+    fn filesystem_zip_mounts() {
+        let temp = tempfile::tempdir().unwrap();
+        let archive_path = temp.path().join("data.zip");
+        write_zip(archive_path.as_path(), &[
+            ("textures/hero.png", b"zipped hero", true),
+            ("textures/villain.png", b"zipped villain", false),
+        ]);
+        let loose = temp.path().join("loose");
+        fs::create_dir_all(loose.join("textures")).unwrap();
+        fs::write(loose.join("textures/hero.png"), "loose hero").unwrap();
+
+        let archive = ZipArchive::open(archive_path.as_path()).unwrap();
+        let mut overlay = Overlay::new();
+        overlay
+            .add_layer(MountSource::Zip(archive.clone()), 0)
+            .add_write_layer(MountSource::Directory(loose), 10);
+        let mut fs = test_filesystem(temp.path().join("home").as_path());
+        fs.mount("/zip", MountSource::Zip(archive)).unwrap();
+        fs.mount("/data", MountSource::Overlay(overlay)).unwrap();
+
+        let read = |path: &str| {
+            let mut content = String::new();
+            fs.open(path).unwrap().read_to_string(&mut content).unwrap();
+            content
+        };
+        assert_eq!(read("/zip/textures/hero.png"), "zipped hero");
+        assert_eq!(read("/data/textures/hero.png"), "loose hero");
+        assert_eq!(read("/data/textures/villain.png"), "zipped villain");
+        assert_eq!(fs.resolve("/zip/textures/hero.png").unwrap(), archive_path.join("textures/hero.png"));
+
+        let names: Vec<String> = fs.read_dir("/zip/textures")
+            .unwrap()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, vec!["hero.png", "villain.png"]);
+        assert_eq!(fs.read_dir("/data/textures").unwrap().count(), 2);
+
+        assert!(fs.create("/zip/textures/new.png").is_err());
+        assert!(fs.open("/zip/textures/missing.png").is_err());
+        fs.append("/data/textures/villain.png").unwrap().write_all(b" modded").unwrap();
+        assert_eq!(read("/data/textures/villain.png"), "zipped villain modded");
+        assert_eq!(read("/zip/textures/villain.png"), "zipped villain");
+    }
+
+    #[test]
+    fn filesystem_pack_mounts() {
+        let temp = tempfile::tempdir().unwrap();
+        let pack_path = temp.path().join("data.mkp");
+        PackWriter::new()
+            .add_bytes("src/lib.rs", b"packed lib".to_vec(), PACK_COMPRESSED).unwrap()
+            .add_bytes("packed/only.txt", b"packed only".to_vec(), 0).unwrap()
+            .write(pack_path.as_path()).unwrap();
+
+        //loose files of the working directory shadow the content of the pack.
+        let mut overlay = Overlay::new();
+        overlay
+            .add_layer(MountSource::Pack(PackArchive::open(pack_path.as_path()).unwrap()), 0)
+            .add_layer(MountSource::Root(RootDir::WorkingDirectory), 10);
+        let mut fs = test_filesystem(temp.path().join("home").as_path());
+        fs.mount("/game", MountSource::Overlay(overlay)).unwrap();
+
+        let mut content = String::new();
+        fs.open("/game/packed/only.txt").unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "packed only");
+        content.clear();
+        fs.open("/game/src/lib.rs").unwrap().read_to_string(&mut content).unwrap();
+        assert!(content.contains("pub mod pack;"));
+
+        let root: Vec<String> = fs.read_dir("/game")
+            .unwrap()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect();
+        assert!(root.contains(&String::from("packed")));
+        assert!(root.contains(&String::from("Cargo.toml")));
+        assert_eq!(root.iter().filter(|name| name.as_str() == "src").count(), 1);
+        assert!(fs.create("/game/packed/new.txt").is_err());
+    }
+
+    //Wait for the events concerning path, ignoring the others.
+    fn next_event_for(watcher: &FileWatcher, path: &Path) -> Option<WatchEvent> {
+        while let Some(event) = watcher.recv_timeout(Duration::from_secs(10)) {
+            if event.path() == path {
+                return Some(event);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn filesystem_watch() {
+        let base = tempfile::tempdir().unwrap();
+        let patch = tempfile::tempdir().unwrap();
+        let home = tempfile::tempdir().unwrap();
+        let mut fs = test_filesystem(home.path());
+        let mut overlay = Overlay::new();
+        overlay
+            .add_layer(MountSource::Directory(base.path().to_path_buf()), 0)
+            .add_write_layer(MountSource::Directory(patch.path().to_path_buf()), 10);
+        fs.mount("/assets", MountSource::Overlay(overlay)).unwrap();
+
+        //Every layer is watched, the events are reported with the virtual paths.
+        let watcher = fs.watch("/assets").unwrap();
+        assert!(!watcher.is_polling());
+        fs::write(base.path().join("wall.png"), b"wall").unwrap();
+        assert_eq!(next_event_for(&watcher, Path::new("/assets/wall.png")), Some(WatchEvent::Created(PathBuf::from("/assets/wall.png"))));
+        fs.create("/assets/floor.png").unwrap().write_all(b"floor").unwrap();
+        assert_eq!(next_event_for(&watcher, Path::new("/assets/floor.png")), Some(WatchEvent::Created(PathBuf::from("/assets/floor.png"))));
+        fs::rename(patch.path().join("floor.png"), patch.path().join("ground.png")).unwrap();
+        assert_eq!(
+            next_event_for(&watcher, Path::new("/assets/ground.png")),
+            Some(WatchEvent::Renamed {
+                from: PathBuf::from("/assets/floor.png"),
+                to: PathBuf::from("/assets/ground.png"),
+            })
+        );
+
+        //The patched files hide the files of the base layer.
+        fs::write(patch.path().join("wall.png"), b"patched wall").unwrap();
+        assert_eq!(next_event_for(&watcher, Path::new("/assets/wall.png")), Some(WatchEvent::Created(PathBuf::from("/assets/wall.png"))));
+        fs::write(base.path().join("wall.png"), b"new wall").unwrap();
+        fs::remove_file(patch.path().join("wall.png")).unwrap();
+        assert_eq!(next_event_for(&watcher, Path::new("/assets/wall.png")), Some(WatchEvent::Modified(PathBuf::from("/assets/wall.png"))));
+        fs::remove_file(base.path().join("wall.png")).unwrap();
+        assert_eq!(next_event_for(&watcher, Path::new("/assets/wall.png")), Some(WatchEvent::Removed(PathBuf::from("/assets/wall.png"))));
+
+        //The polling fallback.
+        let mut options = WatchOptions::new();
+        options.polling(true).poll_interval(Duration::from_millis(50));
+        let watcher = fs.watch_with_options(base.path(), &options).unwrap();
+        assert!(watcher.is_polling());
+        fs::write(base.path().join("roof.png"), b"roof").unwrap();
+        let roof = base.path().join("roof.png");
+        assert_eq!(next_event_for(&watcher, roof.as_path()), Some(WatchEvent::Created(roof.clone())));
+
+        //The root directories.
+        fs.mkdir(fs.construct_path_from_root(RootDir::UserDataRoot, "watched").unwrap()).unwrap();
+        let watcher = fs.watch_root(RootDir::UserDataRoot, "watched").unwrap();
+        let save = fs.construct_path_from_root(RootDir::UserDataRoot, "watched/save.dat").unwrap();
+        fs::write(save.as_path(), b"save").unwrap();
+        assert_eq!(next_event_for(&watcher, save.as_path()), Some(WatchEvent::Created(save.clone())));
+
+        assert!(fs.watch("/assets/missing").is_err());
+    }
 }
@@ -7,78 +7,396 @@
 
 use std::error::Error;
 use std::fmt;
-use std::io::Error as IOError;
+use std::io::{self, Error as IOError};
 use std::env::VarError;
+use std::path::{Path, PathBuf};
+use game_directories::RootDir;
+
+//The operation which failed with an I/O error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Open,
+    Create,
+    Append,
+    Read,
+    Write,
+    Mkdir,
+    Rm,
+    Rmrf,
+    ReadDir,
+    Metadata,
+    Canonicalize,
+    Rename,
+    Sync,
+    SetLen,
+    Close,
+    Lock,
+    Unlock,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Operation::Open => "open",
+            Operation::Create => "create",
+            Operation::Append => "append",
+            Operation::Read => "read",
+            Operation::Write => "write",
+            Operation::Mkdir => "mkdir",
+            Operation::Rm => "rm",
+            Operation::Rmrf => "rmrf",
+            Operation::ReadDir => "read_dir",
+            Operation::Metadata => "metadata",
+            Operation::Canonicalize => "canonicalize",
+            Operation::Rename => "rename",
+            Operation::Sync => "sync",
+            Operation::SetLen => "set_len",
+            Operation::Close => "close",
+            Operation::Lock => "lock",
+            Operation::Unlock => "unlock",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+//The classified cause of an error, to react to it without parsing messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    NotFound,
+    PermissionDenied,
+    AlreadyExists,
+    DiskFull,
+    IsADirectory,
+    NotADirectory,
+    DirectoryNotEmpty,
+    Other,
+}
+
+impl<'a> From<&'a IOError> for ErrorKind {
+    fn from(error: &'a IOError) -> ErrorKind {
+        match error.kind() {
+            io::ErrorKind::NotFound => ErrorKind::NotFound,
+            io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+            io::ErrorKind::AlreadyExists => ErrorKind::AlreadyExists,
+            io::ErrorKind::StorageFull => ErrorKind::DiskFull,
+            io::ErrorKind::IsADirectory => ErrorKind::IsADirectory,
+            io::ErrorKind::NotADirectory => ErrorKind::NotADirectory,
+            io::ErrorKind::DirectoryNotEmpty => ErrorKind::DirectoryNotEmpty,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match *self {
+            ErrorKind::NotFound => "not found",
+            ErrorKind::PermissionDenied => "permission denied",
+            ErrorKind::AlreadyExists => "already exists",
+            ErrorKind::DiskFull => "disk full",
+            ErrorKind::IsADirectory => "is a directory",
+            ErrorKind::NotADirectory => "not a directory",
+            ErrorKind::DirectoryNotEmpty => "directory not empty",
+            ErrorKind::Other => "other error",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+//What was being done when an I/O error happened. Every field is optional: the context is
+//filled as the error goes up, by the code knowing it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IOErrorContext {
+    operation: Option<Operation>,
+    paths: Vec<PathBuf>,
+    root_dir: Option<RootDir>,
+}
+
+impl IOErrorContext {
+    pub fn operation(&self) -> Option<Operation> {
+        self.operation
+    }
+
+    //The paths involved: one for most operations, the source and the destination for a rename.
+    pub fn paths(&self) -> &[PathBuf] {
+        self.paths.as_slice()
+    }
+
+    //The root directory containing the paths, if they are in one.
+    pub fn root_dir(&self) -> Option<RootDir> {
+        self.root_dir
+    }
+}
+
+impl fmt::Display for IOErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.operation {
+            Some(operation) => write!(f, "{} failed", operation)?,
+            None => write!(f, "operation failed")?,
+        }
+        for (index, path) in self.paths.iter().enumerate() {
+            write!(f, "{} {}", if index == 0 { " on" } else { " ->" }, path.display())?;
+        }
+        if let Some(root_dir) = self.root_dir {
+            write!(f, " (in the {})", root_dir)?;
+        }
+        Ok(())
+    }
+}
 
 #[derive(Debug)]
 pub enum FileSystemError {
     GameDirectoryError(String),
     CreationError(String),
-    IOError(String, IOError),
+    IOError(IOErrorContext, IOError),
     EnvironmentError(String, VarError),
     ExtensionError(String),
+    MountError(String),
+    ArchiveError(String),
+    AsyncError(String),
+    WatchError(String),
+    SandboxError(String),
+    VirtualPathError(String),
+    SaveError(String),
+    IntegrityError(String),
+    OpenOptionsError(String),
 }
 
-unsafe impl Send for FileSystemError {}
-unsafe impl Sync for FileSystemError {}
-
 impl fmt::Display for FileSystemError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &FileSystemError::GameDirectoryError(ref description) => {
+        match *self {
+            FileSystemError::GameDirectoryError(ref description) => {
                 write!(f, "Game directory error: {}", description)
             }
-            &FileSystemError::CreationError(ref description) => {
+            FileSystemError::CreationError(ref description) => {
                 write!(f, "Creation error: {}", description)
             }
-            &FileSystemError::EnvironmentError(ref description, _) => {
+            FileSystemError::EnvironmentError(ref description, _) => {
                 write!(f, "Environment variable error: {}", description)
             }
-            &FileSystemError::IOError(ref description, _) => {
-                write!(f, "I/O error: {}", description)
+            FileSystemError::IOError(ref context, ref error) => {
+                write!(f, "I/O error: {}: {} ({})", context, ErrorKind::from(error), error)
             }
-            &FileSystemError::ExtensionError(ref description) => {
+            FileSystemError::ExtensionError(ref description) => {
                 write!(f, "file extension error: {}", description)
             }
+            FileSystemError::MountError(ref description) => {
+                write!(f, "Mount error: {}", description)
+            }
+            FileSystemError::ArchiveError(ref description) => {
+                write!(f, "Archive error: {}", description)
+            }
+            FileSystemError::AsyncError(ref description) => {
+                write!(f, "Asynchronous I/O error: {}", description)
+            }
+            FileSystemError::WatchError(ref description) => {
+                write!(f, "File watching error: {}", description)
+            }
+            FileSystemError::SandboxError(ref description) => {
+                write!(f, "Sandbox error: {}", description)
+            }
+            FileSystemError::VirtualPathError(ref description) => {
+                write!(f, "Virtual path error: {}", description)
+            }
+            FileSystemError::SaveError(ref description) => {
+                write!(f, "Save slot error: {}", description)
+            }
+            FileSystemError::IntegrityError(ref description) => {
+                write!(f, "Integrity error: {}", description)
+            }
+            FileSystemError::OpenOptionsError(ref description) => {
+                write!(f, "Invalid open options: {}", description)
+            }
         }
     }
 }
 
 impl Error for FileSystemError {
-    fn description(&self) -> &str {
-        match self {
-            &FileSystemError::GameDirectoryError(_) => "GameDirectoryError",
-            &FileSystemError::CreationError(_) => "CreationError",
-            &FileSystemError::EnvironmentError(_, _) => "EnvironmentError",
-            &FileSystemError::IOError(_, _) => "IOError",
-            &FileSystemError::ExtensionError(_) => "ExtensionError",
-        }
-    }
-
-    fn cause(&self) -> Option<&Error> {
-        match self {
-            &FileSystemError::GameDirectoryError(_) => None,
-            &FileSystemError::CreationError(_) => None,
-            &FileSystemError::IOError(_, ref cause) => Some(cause),
-            &FileSystemError::EnvironmentError(_, ref cause) => Some(cause),
-            &FileSystemError::ExtensionError(_) => None,
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            FileSystemError::IOError(_, ref source) => Some(source),
+            FileSystemError::EnvironmentError(_, ref source) => Some(source),
+            _ => None,
         }
     }
 }
 
 pub type FileSystemResult<T> = Result<T, FileSystemError>;
 
+//An io::Error created from a FileSystemError gives the FileSystemError back, untouched.
 impl From<IOError> for FileSystemError {
     fn from(error: IOError) -> Self {
-        FileSystemError::IOError(format!("Error while doing I/O operations"), error)
+        if error.get_ref().is_some_and(|inner| inner.is::<FileSystemError>()) {
+            let inner = error.into_inner().expect("The io::Error has an inner error.");
+            return *inner.downcast::<FileSystemError>().expect("The inner error is a FileSystemError.");
+        }
+        FileSystemError::IOError(IOErrorContext::default(), error)
+    }
+}
+
+//Wrap the FileSystemError in an io::Error, for the Read and Write implementations.
+//The kind of the io::Error is the closest to the FileSystemError, and the FileSystemError
+//can be recovered with From<io::Error>, or by downcasting the inner error.
+impl From<FileSystemError> for IOError {
+    fn from(error: FileSystemError) -> Self {
+        let kind = match error {
+            FileSystemError::IOError(_, ref error) => error.kind(),
+            FileSystemError::SandboxError(_) => io::ErrorKind::PermissionDenied,
+            FileSystemError::ExtensionError(_)
+            | FileSystemError::VirtualPathError(_)
+            | FileSystemError::OpenOptionsError(_) => io::ErrorKind::InvalidInput,
+            FileSystemError::ArchiveError(_) | FileSystemError::SaveError(_) | FileSystemError::IntegrityError(_) => {
+                io::ErrorKind::InvalidData
+            }
+            _ => io::ErrorKind::Other,
+        };
+        IOError::new(kind, error)
+    }
+}
+
+impl FileSystemError {
+    //An I/O error, with the operation which failed and the paths involved.
+    pub fn io<P: AsRef<Path>>(operation: Operation, paths: &[P], error: IOError) -> Self {
+        FileSystemError::IOError(IOErrorContext {
+            operation: Some(operation),
+            paths: paths.iter().map(|path| path.as_ref().to_path_buf()).collect(),
+            root_dir: None,
+        }, error)
+    }
+
+    //The context of an I/O error.
+    pub fn context(&self) -> Option<&IOErrorContext> {
+        match *self {
+            FileSystemError::IOError(ref context, _) => Some(context),
+            _ => None,
+        }
+    }
+
+    //The classified cause of an I/O error. The other errors are classified as ErrorKind::Other.
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            FileSystemError::IOError(_, ref error) => ErrorKind::from(error),
+            _ => ErrorKind::Other,
+        }
+    }
+
+    //Record the operation of an I/O error, if it isn't known yet.
+    pub fn with_operation(mut self, operation: Operation) -> Self {
+        if let FileSystemError::IOError(ref mut context, _) = self {
+            context.operation.get_or_insert(operation);
+        }
+        self
+    }
+
+    //Record the path of an I/O error, if no path is known yet.
+    pub fn with_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        if let FileSystemError::IOError(ref mut context, _) = self {
+            if context.paths.is_empty() {
+                context.paths.push(path.as_ref().to_path_buf());
+            }
+        }
+        self
+    }
+
+    //Record the root directory of an I/O error, if it isn't known yet.
+    pub fn with_root_dir(mut self, root_dir: Option<RootDir>) -> Self {
+        if let FileSystemError::IOError(ref mut context, _) = self {
+            if context.root_dir.is_none() {
+                context.root_dir = root_dir;
+            }
+        }
+        self
     }
 }
 
 impl From<VarError> for FileSystemError {
     fn from(error: VarError) -> Self {
         FileSystemError::EnvironmentError(
-            format!("Error while dealing with environment variable"),
+            String::from("Error while dealing with environment variable"),
             error,
         )
     }
 }
+
+#[cfg(test)]
+mod filesystem_error_test {
+    use super::*;
+    use std::thread;
+
+    fn assert_error<T: Error + Send + Sync + 'static>() {}
+
+    #[test]
+    fn filesystem_error_context() {
+        let error = FileSystemError::io(
+            Operation::Open,
+            &["/home/player/.local/share/game/saves/slot_1.sav"],
+            IOError::new(io::ErrorKind::NotFound, "No such file or directory"),
+        ).with_root_dir(Some(RootDir::UserSaveRoot)).with_path("/ignored");
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        assert_eq!(error.context().unwrap().operation(), Some(Operation::Open));
+        assert_eq!(error.context().unwrap().paths().len(), 1);
+        assert_eq!(
+            error.to_string(),
+            "I/O error: open failed on /home/player/.local/share/game/saves/slot_1.sav (in the user save root): not found (No such file or directory)"
+        );
+
+        let error = FileSystemError::from(IOError::new(io::ErrorKind::StorageFull, "No space left on device"))
+            .with_operation(Operation::Rename)
+            .with_operation(Operation::Write);
+        assert_eq!(error.kind(), ErrorKind::DiskFull);
+        assert_eq!(error.to_string(), "I/O error: rename failed: disk full (No space left on device)");
+        assert_eq!(FileSystemError::MountError(String::from("read-only")).with_operation(Operation::Rm).kind(), ErrorKind::Other);
+    }
+
+    #[test]
+    fn filesystem_error_bounds() {
+        assert_error::<FileSystemError>();
+
+        //? converts the error to a boxed error which can cross threads.
+        fn load() -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+            Err(FileSystemError::io(
+                Operation::Read,
+                &["data/textures/hero.png"],
+                IOError::new(io::ErrorKind::PermissionDenied, "Permission denied"),
+            ))?;
+            Ok(Vec::new())
+        }
+        let error = thread::spawn(load).join().unwrap().unwrap_err();
+        let error = error.downcast::<FileSystemError>().unwrap();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        let source = error.source().unwrap().downcast_ref::<IOError>().unwrap();
+        assert_eq!(source.kind(), io::ErrorKind::PermissionDenied);
+        assert!(FileSystemError::SaveError(String::from("corrupted")).source().is_none());
+    }
+
+    #[test]
+    fn filesystem_error_io_round_trip() {
+        let error = FileSystemError::io(
+            Operation::Rename,
+            &["saves/.slot_1.sav.tmp", "saves/slot_1.sav"],
+            IOError::new(io::ErrorKind::StorageFull, "No space left on device"),
+        ).with_root_dir(Some(RootDir::UserSaveRoot));
+        let message = error.to_string();
+        let io_error = IOError::from(error);
+        assert_eq!(io_error.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(io_error.to_string(), message);
+
+        let error = FileSystemError::from(io_error);
+        assert_eq!(error.to_string(), message);
+        let context = error.context().unwrap();
+        assert_eq!(context.operation(), Some(Operation::Rename));
+        assert_eq!(context.paths().len(), 2);
+        assert_eq!(context.root_dir(), Some(RootDir::UserSaveRoot));
+
+        let io_error = IOError::from(FileSystemError::SandboxError(String::from("../config escapes the sandbox")));
+        assert_eq!(io_error.kind(), io::ErrorKind::PermissionDenied);
+        match FileSystemError::from(io_error) {
+            FileSystemError::SandboxError(ref description) => assert_eq!(description, "../config escapes the sandbox"),
+            error => panic!("The error changed during the round trip: {:?}", error),
+        }
+        match FileSystemError::from(IOError::other("plain")) {
+            FileSystemError::IOError(ref context, _) => assert_eq!(*context, IOErrorContext::default()),
+            error => panic!("A plain io::Error should give an IOError: {:?}", error),
+        }
+    }
+}
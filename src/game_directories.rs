@@ -6,10 +6,14 @@
 // copied, modified, or distributed except according to those terms.
 
 use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
 
-use std::path::PathBuf;
-use std::env;
+use std::path::{Path, PathBuf};
+use std::env::{self, VarError};
+use std::ffi::OsString;
 use filesystem_error::{FileSystemError, FileSystemResult};
+use atomic_writer::temporary_path;
 use std::fmt;
 
 //Enum used to specify the 'root' directory from where to write/delete/open dir/files
@@ -21,97 +25,642 @@ pub enum RootDir {
     EngineConfigRoot,
     EngineLogRoot,
     UserSaveRoot,
+    UserCacheRoot,
+    UserStateRoot,
 }
 
 impl fmt::Display for RootDir {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &RootDir::WorkingDirectory => {
+        match *self {
+            RootDir::WorkingDirectory => {
                 write!(f, "current directory")
             },
-            &RootDir::UserDataRoot => {
+            RootDir::UserDataRoot => {
                 write!(f, "user data root")
             },
-            &RootDir::UserConfigRoot => {
+            RootDir::UserConfigRoot => {
                 write!(f, "user config root")
             },
-            &RootDir::EngineConfigRoot => {
+            RootDir::EngineConfigRoot => {
                 write!(f, "engine config root")
             },
-            &RootDir::EngineLogRoot => {
+            RootDir::EngineLogRoot => {
                 write!(f, "engine log root")
             },
-            &RootDir::UserSaveRoot => {
+            RootDir::UserSaveRoot => {
                 write!(f, "user save root")
             },
+            RootDir::UserCacheRoot => {
+                write!(f, "user cache root")
+            },
+            RootDir::UserStateRoot => {
+                write!(f, "user state root")
+            },
         }
     }
 }
 
+//The name of the file which, next to the executable, enables the portable mode.
+pub const PORTABLE_MARKER: &str = "portable.txt";
+//The directory, next to the executable, holding the user directories in portable mode.
+pub const PORTABLE_DIRECTORY: &str = "user_data";
+
 #[derive(Debug)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-pub struct GameDirectories(HashMap<RootDir, PathBuf>);
+pub struct GameDirectories {
+    directories: HashMap<RootDir, PathBuf>,
+    portable: bool,
+}
 
 impl GameDirectories {
     pub fn new(game_name: &str, game_author: &str) -> FileSystemResult<Self> {
+        GameDirectoriesBuilder::new(game_name, game_author).build()
+    }
+
+    //Create a builder, to replace some of the directories (CI, dedicated servers, tests...).
+    pub fn builder(game_name: &str, game_author: &str) -> GameDirectoriesBuilder {
+        GameDirectoriesBuilder::new(game_name, game_author)
+    }
+
+    //Create the directories of the game, the overridden ones taking precedence.
+    //The environment variables are read with var, and only if they are needed.
+    fn from_env<F>(game_name: &str, game_author: &str, overrides: &HashMap<RootDir, PathBuf>, portable: bool, var: F) -> FileSystemResult<Self> where
+        F: Fn(&str) -> Option<OsString>
+    {
         debug!("Creating a new GameDirectories with a game name of {}, created by {}", game_name, game_author);
-        trace!("Creating the user config path...");
-        let mut user_config = PathBuf::new();
-        trace!("Creating the user data path...");
-        let mut user_data = PathBuf::new();
-
-        if cfg!(target_os = "windows") {
-            trace!("OS: Windows.");
-            trace!("Trying to get the value of the APPDATA environment variable.");
-            let appdata = env::var("APPDATA")?;
-
-            user_config = PathBuf::from(format!("{}\'{}\'{}", appdata.as_str(), game_author, game_name));
-            user_data = PathBuf::from(format!("{}\'{}\'{}", appdata.as_str(), game_author, game_name));
-        } else if cfg!(target_os = "macos") {
-            trace!("OS: MacOS.");
-            unimplemented!();
-        } else {
-            trace!("OS: Unix/Linux/BSD.");
-            trace!("Trying to get the value of the HOME environment variable.");
-            let home = env::var("HOME")?;
-
-            user_config = PathBuf::from(format!("{}/.config/{}/{}", home.as_str(), game_author, game_name));
-            user_data = PathBuf::from(format!("{}/.local/share/{}/{}", home.as_str(), game_author, game_name));
-        }
+        let directory = |root_dir: RootDir, derived: &dyn Fn() -> FileSystemResult<PathBuf>| -> FileSystemResult<PathBuf> {
+            match overrides.get(&root_dir) {
+                Some(path) => {
+                    trace!("The {} is overridden.", root_dir);
+                    Ok(path.clone())
+                },
+                None => derived(),
+            }
+        };
+
+        trace!("Creating the user config, data, cache and state paths...");
+        let user_directory = |root_dir: RootDir| -> FileSystemResult<PathBuf> {
+            directory(root_dir, &|| platform_directory(&var, root_dir, game_name, game_author))
+        };
+        let user_config = user_directory(RootDir::UserConfigRoot)?;
+        let user_data = user_directory(RootDir::UserDataRoot)?;
+        let user_cache = user_directory(RootDir::UserCacheRoot)?;
+        let user_state = user_directory(RootDir::UserStateRoot)?;
 
         trace!("User config path: {}", user_config.display());
         trace!("User data path: {}", user_data.display());
+        trace!("User cache path: {}", user_cache.display());
+        trace!("User state path: {}", user_state.display());
 
 
-        let mut logs = user_config.clone();
-        logs.push("maskerad_logs");
+        let logs = directory(RootDir::EngineLogRoot, &|| Ok(user_state.join("maskerad_logs")))?;
         trace!("engine logs path: {}", logs.display());
 
-        let mut engine_config = user_config.clone();
-        engine_config.push("maskerad_configuration");
+        let engine_config = directory(RootDir::EngineConfigRoot, &|| Ok(user_config.join("maskerad_configuration")))?;
         trace!("engine configuration path: {}", engine_config.display());
 
-        let mut saves = user_data.clone();
-        saves.push("game_saves");
+        let saves = directory(RootDir::UserSaveRoot, &|| Ok(user_data.join("game_saves")))?;
         trace!("game saves path: {}", saves.display());
 
         trace!("Trying to get the path of the current directory...");
-        let current = env::current_dir()?;
+        let current = directory(RootDir::WorkingDirectory, &|| Ok(env::current_dir()?))?;
         trace!("Current directory: {}", current.display());
 
         trace!("Creating the hashmap associating the RootDir enumeration to those paths.");
-        let mut directories = HashMap::with_capacity(6);
+        let mut directories = HashMap::with_capacity(8);
         directories.insert(RootDir::WorkingDirectory, current);
         directories.insert(RootDir::UserDataRoot, user_data);
         directories.insert(RootDir::UserConfigRoot, user_config);
         directories.insert(RootDir::EngineConfigRoot, engine_config);
         directories.insert(RootDir::EngineLogRoot, logs);
         directories.insert(RootDir::UserSaveRoot, saves);
+        directories.insert(RootDir::UserCacheRoot, user_cache);
+        directories.insert(RootDir::UserStateRoot, user_state);
         trace!("GameDirectories structure successfully created.");
-        Ok(GameDirectories(directories))
+        Ok(GameDirectories {
+            directories,
+            portable,
+        })
     }
 
     pub fn get(&self, k: &RootDir) -> Option<&PathBuf> {
-        self.0.get(k)
+        self.directories.get(k)
+    }
+
+    //The deepest root directory containing path: the user save root for a save,
+    //even though the user data root contains it too.
+    pub fn root_dir_of<P: AsRef<Path>>(&self, path: P) -> Option<RootDir> {
+        self.directories
+            .iter()
+            .filter(|(_, directory)| path.as_ref().starts_with(directory))
+            .max_by_key(|(_, directory)| directory.components().count())
+            .map(|(root_dir, _)| *root_dir)
+    }
+
+    //Check if the user directories are kept next to the executable.
+    pub fn is_portable(&self) -> bool {
+        self.portable
+    }
+
+    //Create every root directory owned by the game (all of them except the working directory),
+    //and check that they are writable.
+    //Nothing stops at the first failure: the report tells the status of every root directory.
+    pub fn initialize(&self) -> DirectoriesReport {
+        debug!("Creating and checking the game directories.");
+        let statuses = GAME_ROOTS
+            .iter()
+            .filter_map(|root_dir| self.get(root_dir).map(|path| (*root_dir, path.clone())))
+            .map(|(root_dir, path)| {
+                let status = initialize_directory(path.as_path());
+                trace!("The {} at {}: {}", root_dir, path.display(), status);
+                (root_dir, path, status)
+            })
+            .collect();
+        DirectoriesReport { statuses }
+    }
+}
+
+//Build a GameDirectories where some directories are replaced.
+//The directories derived from an overridden one follow it: overriding the user data root
+//moves the saves too, unless the user save root is overridden as well.
+#[derive(Debug, Clone)]
+pub struct GameDirectoriesBuilder {
+    game_name: String,
+    game_author: String,
+    overrides: HashMap<RootDir, PathBuf>,
+    portable: Option<bool>,
+    executable_directory: Option<PathBuf>,
+}
+
+impl GameDirectoriesBuilder {
+    pub fn new(game_name: &str, game_author: &str) -> Self {
+        GameDirectoriesBuilder {
+            game_name: String::from(game_name),
+            game_author: String::from(game_author),
+            overrides: HashMap::new(),
+            portable: None,
+            executable_directory: None,
+        }
+    }
+
+    //Replace the path of the root directory.
+    pub fn directory<P: Into<PathBuf>>(&mut self, root_dir: RootDir, path: P) -> &mut Self {
+        self.overrides.insert(root_dir, path.into());
+        self
+    }
+
+    //Put all the user directories (config, data, cache and state) in base. The environment
+    //isn't used to find them anymore.
+    pub fn base_directory<P: Into<PathBuf>>(&mut self, base: P) -> &mut Self {
+        for (root_dir, path) in user_directories(base.into().as_path()) {
+            self.directory(root_dir, path);
+        }
+        self
+    }
+
+    //Force the portable mode on or off. By default, the game is portable if a PORTABLE_MARKER
+    //file lies next to the executable.
+    //In portable mode, the user directories are in the PORTABLE_DIRECTORY next to the executable,
+    //unless they are overridden.
+    pub fn portable(&mut self, portable: bool) -> &mut Self {
+        self.portable = Some(portable);
+        self
+    }
+
+    //Replace the directory of the executable, where the portable mode looks for its marker and its directory.
+    pub fn executable_directory<P: Into<PathBuf>>(&mut self, directory: P) -> &mut Self {
+        self.executable_directory = Some(directory.into());
+        self
+    }
+
+    pub fn build(&self) -> FileSystemResult<GameDirectories> {
+        self.build_with_env(|name| env::var_os(name))
+    }
+
+    fn build_with_env<F>(&self, var: F) -> FileSystemResult<GameDirectories> where
+        F: Fn(&str) -> Option<OsString>
+    {
+        let mut overrides = self.overrides.clone();
+        let portable = match self.portable_directory()? {
+            Some(portable_directory) => {
+                debug!("Portable mode: the user directories are in {}", portable_directory.display());
+                for (root_dir, path) in user_directories(portable_directory.as_path()) {
+                    overrides.entry(root_dir).or_insert(path);
+                }
+                true
+            },
+            None => false,
+        };
+        GameDirectories::from_env(self.game_name.as_str(), self.game_author.as_str(), &overrides, portable, var)
+    }
+
+    //The directory holding the user directories, if the game is portable.
+    fn portable_directory(&self) -> FileSystemResult<Option<PathBuf>> {
+        if self.portable == Some(false) {
+            return Ok(None);
+        }
+
+        let executable_directory = match self.executable_directory {
+            Some(ref directory) => directory.clone(),
+            None => match env::current_exe() {
+                Ok(executable) => executable.parent().map(PathBuf::from).unwrap_or_default(),
+                //Without marker to look for, we can't be portable.
+                Err(error) if self.portable.is_none() => {
+                    warn!("The path of the executable could not be found ({}), the portable mode is disabled.", error);
+                    return Ok(None);
+                },
+                Err(error) => return Err(FileSystemError::GameDirectoryError(format!(
+                    "The portable mode needs the path of the executable, which could not be found: {}",
+                    error
+                ))),
+            },
+        };
+
+        if self.portable.is_none() && !executable_directory.join(PORTABLE_MARKER).is_file() {
+            return Ok(None);
+        }
+        Ok(Some(executable_directory.join(PORTABLE_DIRECTORY)))
+    }
+}
+
+//The root directories created by GameDirectories::initialize, parents first.
+const GAME_ROOTS: [RootDir; 7] = [
+    RootDir::UserConfigRoot,
+    RootDir::EngineConfigRoot,
+    RootDir::UserDataRoot,
+    RootDir::UserSaveRoot,
+    RootDir::UserCacheRoot,
+    RootDir::UserStateRoot,
+    RootDir::EngineLogRoot,
+];
+
+//The status of a root directory, after GameDirectories::initialize.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirectoryStatus {
+    //The directory didn't exist, and has been created.
+    Created,
+    //The directory already existed.
+    Existed,
+    //The directory couldn't be created, or nothing can be written in it. The reason is given.
+    NotWritable(String),
+}
+
+impl fmt::Display for DirectoryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DirectoryStatus::Created => write!(f, "created"),
+            DirectoryStatus::Existed => write!(f, "existed"),
+            DirectoryStatus::NotWritable(ref reason) => write!(f, "not writable ({})", reason),
+        }
+    }
+}
+
+//The status of every root directory, after GameDirectories::initialize.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoriesReport {
+    statuses: Vec<(RootDir, PathBuf, DirectoryStatus)>,
+}
+
+impl DirectoriesReport {
+    //The status of the root directory, if it has been initialized.
+    pub fn status(&self, root_dir: RootDir) -> Option<&DirectoryStatus> {
+        self.statuses
+            .iter()
+            .find(|&&(root, _, _)| root == root_dir)
+            .map(|(_, _, status)| status)
+    }
+
+    //Every initialized root directory, with its path and its status.
+    pub fn iter(&self) -> ::std::slice::Iter<'_, (RootDir, PathBuf, DirectoryStatus)> {
+        self.statuses.iter()
+    }
+
+    //Check if every root directory can be used.
+    pub fn is_ok(&self) -> bool {
+        self.not_writable().next().is_none()
+    }
+
+    //The root directories which can't be used.
+    pub fn not_writable(&self) -> impl Iterator<Item = &(RootDir, PathBuf, DirectoryStatus)> {
+        self.statuses
+            .iter()
+            .filter(|&(_, _, status)| matches!(*status, DirectoryStatus::NotWritable(_)))
+    }
+
+    //Turn the report into an error if a root directory can't be used.
+    pub fn into_result(self) -> FileSystemResult<DirectoriesReport> {
+        if self.is_ok() {
+            return Ok(self);
+        }
+        error!("Some game directories can't be used:\n{}", self);
+        Err(FileSystemError::GameDirectoryError(format!(
+            "Some game directories can't be used:\n{}",
+            self
+        )))
+    }
+}
+
+impl fmt::Display for DirectoriesReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(root_dir, ref path, ref status) in self.statuses.iter() {
+            writeln!(f, "{} ({}): {}", root_dir, path.display(), status)?;
+        }
+        Ok(())
+    }
+}
+
+fn initialize_directory(path: &Path) -> DirectoryStatus {
+    let existed = path.is_dir();
+    if !existed {
+        if let Err(error) = fs::create_dir_all(path) {
+            return DirectoryStatus::NotWritable(error.to_string());
+        }
+    }
+
+    //Only writing something proves that the directory is writable.
+    //The probe has a unique name: one left behind by a crash, or written by another instance, can't be in the way.
+    let probe = temporary_path(path.join("maskerad_write_test").as_path());
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(probe.as_path())
+        .and_then(|mut file| file.write_all(b"maskerad"));
+    let removed = fs::remove_file(probe.as_path());
+
+    match written.and(removed) {
+        Ok(()) if existed => DirectoryStatus::Existed,
+        Ok(()) => DirectoryStatus::Created,
+        Err(error) => DirectoryStatus::NotWritable(error.to_string()),
+    }
+}
+
+//The user directories, when they are all kept in base.
+fn user_directories(base: &Path) -> Vec<(RootDir, PathBuf)> {
+    vec![
+        (RootDir::UserConfigRoot, base.join("config")),
+        (RootDir::UserDataRoot, base.join("data")),
+        (RootDir::UserCacheRoot, base.join("cache")),
+        (RootDir::UserStateRoot, base.join("state")),
+    ]
+}
+
+//The default path of a user root directory, on the current platform.
+fn platform_directory<F>(var: &F, root_dir: RootDir, game_name: &str, game_author: &str) -> FileSystemResult<PathBuf> where
+    F: Fn(&str) -> Option<OsString>
+{
+    if cfg!(target_os = "windows") {
+        trace!("OS: Windows.");
+        trace!("Trying to get the value of the APPDATA environment variable.");
+        let appdata = var("APPDATA")
+            .ok_or(VarError::NotPresent)?
+            .into_string()
+            .map_err(VarError::NotUnicode)?;
+        let user_data = PathBuf::from(appdata).join(game_author).join(game_name);
+
+        //The cache and the state (the logs) have their own directories, next to the configuration and the data.
+        match root_dir {
+            RootDir::UserCacheRoot => Ok(user_data.join("cache")),
+            RootDir::UserStateRoot => Ok(user_data.join("state")),
+            _ => Ok(user_data),
+        }
+    } else if cfg!(target_os = "macos") {
+        trace!("OS: MacOS.");
+        trace!("Trying to get the value of the HOME environment variable.");
+        let library = match var("HOME") {
+            Some(home) => PathBuf::from(home).join("Library"),
+            None => return Err(FileSystemError::EnvironmentError(String::from("HOME isn't set"), VarError::NotPresent)),
+        };
+        let application_support = library.join("Application Support").join(game_author).join(game_name);
+
+        match root_dir {
+            RootDir::UserCacheRoot => Ok(library.join("Caches").join(game_author).join(game_name)),
+            RootDir::UserStateRoot => Ok(application_support.join("state")),
+            _ => Ok(application_support),
+        }
+    } else {
+        trace!("OS: Unix/Linux/BSD.");
+        //See the XDG Base Directory specification.
+        let (name, default) = match root_dir {
+            RootDir::UserConfigRoot => ("XDG_CONFIG_HOME", ".config"),
+            RootDir::UserDataRoot => ("XDG_DATA_HOME", ".local/share"),
+            RootDir::UserCacheRoot => ("XDG_CACHE_HOME", ".cache"),
+            _ => ("XDG_STATE_HOME", ".local/state"),
+        };
+        Ok(xdg_directory(var, name, default)?.join(game_author).join(game_name))
+    }
+}
+
+//The value of the XDG environment variable name if it is set to an absolute path,
+//$HOME/default otherwise. Relative paths are invalid, and must be ignored.
+fn xdg_directory<F>(var: &F, name: &str, default: &str) -> FileSystemResult<PathBuf> where
+    F: Fn(&str) -> Option<OsString>
+{
+    trace!("Trying to get the value of the {} environment variable.", name);
+    if let Some(value) = var(name) {
+        let path = PathBuf::from(value);
+        if path.is_absolute() {
+            return Ok(path);
+        }
+        if !path.as_os_str().is_empty() {
+            warn!("{} is set to the relative path {}, it is ignored.", name, path.display());
+        }
+    }
+
+    trace!("Trying to get the value of the HOME environment variable.");
+    match var("HOME") {
+        Some(home) => Ok(PathBuf::from(home).join(default)),
+        None => Err(FileSystemError::EnvironmentError(
+            format!("{} isn't set, and neither is HOME", name),
+            VarError::NotPresent,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod game_directories_test {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs;
+    use tempfile;
+
+    fn from_variables(variables: &[(&str, &str)]) -> FileSystemResult<GameDirectories> {
+        let variables: HashMap<String, OsString> = variables
+            .iter()
+            .map(|&(name, value)| (String::from(name), OsString::from(value)))
+            .collect();
+        GameDirectories::builder("game", "author").build_with_env(move |name| variables.get(name).cloned())
+    }
+
+    fn path(directories: &GameDirectories, root_dir: RootDir) -> &str {
+        directories.get(&root_dir).unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    #[cfg(target_os = "windows")]
+    fn game_directories_windows() {
+        let directories = from_variables(&[("APPDATA", "C:\\Users\\player\\AppData\\Roaming")]).unwrap();
+        assert_eq!(path(&directories, RootDir::UserConfigRoot), "C:\\Users\\player\\AppData\\Roaming\\author\\game");
+        assert_eq!(path(&directories, RootDir::UserDataRoot), "C:\\Users\\player\\AppData\\Roaming\\author\\game");
+        assert_eq!(path(&directories, RootDir::UserCacheRoot), "C:\\Users\\player\\AppData\\Roaming\\author\\game\\cache");
+        assert_eq!(path(&directories, RootDir::EngineLogRoot), "C:\\Users\\player\\AppData\\Roaming\\author\\game\\state\\maskerad_logs");
+
+        assert!(from_variables(&[]).is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn game_directories_macos() {
+        let directories = from_variables(&[("HOME", "/Users/player")]).unwrap();
+        assert_eq!(path(&directories, RootDir::UserConfigRoot), "/Users/player/Library/Application Support/author/game");
+        assert_eq!(path(&directories, RootDir::UserDataRoot), "/Users/player/Library/Application Support/author/game");
+        assert_eq!(path(&directories, RootDir::UserCacheRoot), "/Users/player/Library/Caches/author/game");
+        assert_eq!(path(&directories, RootDir::EngineLogRoot), "/Users/player/Library/Application Support/author/game/state/maskerad_logs");
+
+        assert!(from_variables(&[]).is_err());
+    }
+
+    #[test]
+    #[cfg(all(unix, not(target_os = "macos")))]
+    fn game_directories_xdg_defaults() {
+        let directories = from_variables(&[("HOME", "/home/player")]).unwrap();
+        assert_eq!(path(&directories, RootDir::UserConfigRoot), "/home/player/.config/author/game");
+        assert_eq!(path(&directories, RootDir::EngineConfigRoot), "/home/player/.config/author/game/maskerad_configuration");
+        assert_eq!(path(&directories, RootDir::UserDataRoot), "/home/player/.local/share/author/game");
+        assert_eq!(path(&directories, RootDir::UserSaveRoot), "/home/player/.local/share/author/game/game_saves");
+        assert_eq!(path(&directories, RootDir::UserCacheRoot), "/home/player/.cache/author/game");
+        assert_eq!(path(&directories, RootDir::UserStateRoot), "/home/player/.local/state/author/game");
+        assert_eq!(path(&directories, RootDir::EngineLogRoot), "/home/player/.local/state/author/game/maskerad_logs");
+
+        assert!(from_variables(&[]).is_err());
+    }
+
+    #[test]
+    #[cfg(all(unix, not(target_os = "macos")))]
+    fn game_directories_xdg_variables() {
+        let directories = from_variables(&[
+            ("HOME", "/home/player"),
+            ("XDG_CONFIG_HOME", "/xdg/config"),
+            ("XDG_DATA_HOME", "/xdg/data"),
+            ("XDG_CACHE_HOME", "relative/cache"),
+            ("XDG_STATE_HOME", ""),
+        ]).unwrap();
+        assert_eq!(path(&directories, RootDir::UserConfigRoot), "/xdg/config/author/game");
+        assert_eq!(path(&directories, RootDir::UserDataRoot), "/xdg/data/author/game");
+        //The relative and empty values are ignored.
+        assert_eq!(path(&directories, RootDir::UserCacheRoot), "/home/player/.cache/author/game");
+        assert_eq!(path(&directories, RootDir::EngineLogRoot), "/home/player/.local/state/author/game/maskerad_logs");
+
+        //HOME isn't needed when every directory is given.
+        let directories = from_variables(&[
+            ("XDG_CONFIG_HOME", "/xdg/config"),
+            ("XDG_DATA_HOME", "/xdg/data"),
+            ("XDG_CACHE_HOME", "/xdg/cache"),
+            ("XDG_STATE_HOME", "/xdg/state"),
+        ]).unwrap();
+        assert_eq!(path(&directories, RootDir::UserStateRoot), "/xdg/state/author/game");
+    }
+
+    #[test]
+    fn game_directories_builder() {
+        //Nothing comes from the environment.
+        let directories = GameDirectories::builder("game", "author")
+            .base_directory("/srv/game")
+            .directory(RootDir::UserSaveRoot, "/mnt/saves")
+            .directory(RootDir::WorkingDirectory, "/opt/game")
+            .build_with_env(|_| None)
+            .unwrap();
+        assert_eq!(path(&directories, RootDir::WorkingDirectory), "/opt/game");
+        assert_eq!(path(&directories, RootDir::UserConfigRoot), "/srv/game/config");
+        assert_eq!(path(&directories, RootDir::EngineConfigRoot), "/srv/game/config/maskerad_configuration");
+        assert_eq!(path(&directories, RootDir::UserDataRoot), "/srv/game/data");
+        assert_eq!(path(&directories, RootDir::UserSaveRoot), "/mnt/saves");
+        assert_eq!(path(&directories, RootDir::UserCacheRoot), "/srv/game/cache");
+        assert_eq!(path(&directories, RootDir::EngineLogRoot), "/srv/game/state/maskerad_logs");
+    }
+
+    #[test]
+    #[cfg(all(unix, not(target_os = "macos")))]
+    fn game_directories_builder_partial_overrides() {
+        let directories = GameDirectories::builder("game", "author")
+            .directory(RootDir::EngineLogRoot, "/var/log/game")
+            .build_with_env(|name| if name == "HOME" { Some(OsString::from("/home/server")) } else { None })
+            .unwrap();
+        assert_eq!(path(&directories, RootDir::EngineLogRoot), "/var/log/game");
+        assert_eq!(path(&directories, RootDir::UserStateRoot), "/home/server/.local/state/author/game");
+        assert_eq!(path(&directories, RootDir::UserSaveRoot), "/home/server/.local/share/author/game/game_saves");
+    }
+
+    #[test]
+    fn game_directories_portable() {
+        let temp = tempfile::tempdir().unwrap();
+        let portable = temp.path().join(PORTABLE_DIRECTORY);
+        let home = |name: &str| if name == "HOME" { Some(OsString::from("/home/player")) } else { None };
+        let mut builder = GameDirectories::builder("game", "author");
+        builder.executable_directory(temp.path());
+
+        //Without marker.
+        let directories = builder.build_with_env(home).unwrap();
+        assert!(!directories.is_portable());
+
+        fs::write(temp.path().join(PORTABLE_MARKER), b"").unwrap();
+        let directories = builder.build_with_env(home).unwrap();
+        assert!(directories.is_portable());
+        assert_eq!(directories.get(&RootDir::UserConfigRoot), Some(&portable.join("config")));
+        assert_eq!(directories.get(&RootDir::UserSaveRoot), Some(&portable.join("data").join("game_saves")));
+        assert_eq!(directories.get(&RootDir::EngineLogRoot), Some(&portable.join("state").join("maskerad_logs")));
+
+        //The overrides still win.
+        let directories = builder.clone()
+            .directory(RootDir::UserSaveRoot, "/mnt/saves")
+            .build_with_env(home)
+            .unwrap();
+        assert_eq!(directories.get(&RootDir::UserSaveRoot), Some(&PathBuf::from("/mnt/saves")));
+
+        //The explicit option wins over the marker.
+        assert!(!builder.clone().portable(false).build_with_env(home).unwrap().is_portable());
+        fs::remove_file(temp.path().join(PORTABLE_MARKER)).unwrap();
+        let directories = builder.portable(true).build_with_env(|_| None).unwrap();
+        assert!(directories.is_portable());
+        assert_eq!(directories.get(&RootDir::UserCacheRoot), Some(&portable.join("cache")));
+    }
+
+    #[test]
+    fn game_directories_initialize() {
+        let temp = tempfile::tempdir().unwrap();
+        let directories = GameDirectories::builder("game", "author")
+            .base_directory(temp.path())
+            .build_with_env(|_| None)
+            .unwrap();
+
+        let report = directories.initialize();
+        assert!(report.is_ok());
+        assert_eq!(report.iter().count(), 7);
+        assert_eq!(report.status(RootDir::UserSaveRoot), Some(&DirectoryStatus::Created));
+        assert_eq!(report.status(RootDir::WorkingDirectory), None);
+        assert!(directories.get(&RootDir::EngineLogRoot).unwrap().is_dir());
+        assert!(report.into_result().is_ok());
+
+        let report = directories.initialize();
+        assert_eq!(report.status(RootDir::UserSaveRoot), Some(&DirectoryStatus::Existed));
+        assert_eq!(fs::read_dir(directories.get(&RootDir::UserSaveRoot).unwrap()).unwrap().count(), 0);
+
+        //The probe of the old versions, left behind by a crash.
+        fs::write(directories.get(&RootDir::UserSaveRoot).unwrap().join(".maskerad_write_test"), b"maskerad").unwrap();
+        assert!(directories.initialize().is_ok());
+
+        //A file is in the way of the state directory.
+        let blocked = temp.path().join("blocked");
+        fs::write(blocked.as_path(), b"").unwrap();
+        let directories = GameDirectories::builder("game", "author")
+            .base_directory(temp.path())
+            .directory(RootDir::UserStateRoot, blocked)
+            .build_with_env(|_| None)
+            .unwrap();
+        let report = directories.initialize();
+        assert!(!report.is_ok());
+        assert_eq!(report.status(RootDir::UserDataRoot), Some(&DirectoryStatus::Existed));
+        let not_writable: Vec<RootDir> = report.not_writable().map(|&(root_dir, _, _)| root_dir).collect();
+        assert_eq!(not_writable, vec![RootDir::UserStateRoot, RootDir::EngineLogRoot]);
+        assert!(report.into_result().is_err());
     }
 }
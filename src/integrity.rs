@@ -0,0 +1,351 @@
+// Copyright 2017-2018 Maskerad Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+use std::fmt;
+use std::hash::Hasher;
+use std::io::{self, Read};
+use std::str::FromStr;
+use crc32fast;
+use twox_hash::XxHash64;
+use sha2::{Digest, Sha256};
+use filesystem_error::{FileSystemError, FileSystemResult};
+
+/*INTEGRITY.
+
+A damaged install (a file truncated by a failed update, a file deleted by an antivirus...) is detected
+by comparing the files with a manifest, made when the game was packaged.
+
+A manifest lists every file of a directory tree, with its size and its checksum. The paths are
+relative to the root of the tree, '/' separated. Its text form starts with the version and the hash
+algorithm, followed by one line per file: the checksum, the size and the path.
+
+maskerad-manifest 1 sha256
+9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08 4 data/levels/one.lvl
+*/
+
+const MANIFEST_HEADER: &str = "maskerad-manifest 1";
+
+fn integrity_error(reason: &str) -> FileSystemError {
+    error!("{} !", reason);
+    FileSystemError::IntegrityError(format!("{}.", reason))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    //Fast, but only meant to detect accidental damage.
+    Crc32,
+    //xxHash64, with a seed of 0. Fast, and fewer collisions than CRC-32.
+    XxHash64,
+    //Slow, but resists deliberate tampering.
+    Sha256,
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HashAlgorithm::Crc32 => write!(f, "crc32"),
+            HashAlgorithm::XxHash64 => write!(f, "xxh64"),
+            HashAlgorithm::Sha256 => write!(f, "sha256"),
+        }
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = FileSystemError;
+
+    fn from_str(name: &str) -> FileSystemResult<HashAlgorithm> {
+        match name {
+            "crc32" => Ok(HashAlgorithm::Crc32),
+            "xxh64" => Ok(HashAlgorithm::XxHash64),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            _ => Err(integrity_error(&format!("{} isn't a supported hash algorithm", name))),
+        }
+    }
+}
+
+//The hash of some content, and the algorithm which produced it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Checksum {
+    algorithm: HashAlgorithm,
+    bytes: Vec<u8>,
+}
+
+impl Checksum {
+    //Hash everything the reader gives.
+    pub fn from_reader<R: Read>(algorithm: HashAlgorithm, mut reader: R) -> io::Result<Checksum> {
+        let mut crc32 = crc32fast::Hasher::new();
+        let mut xxhash = XxHash64::with_seed(0);
+        let mut sha256 = Sha256::new();
+        let mut buffer = [0; 64 * 1024];
+        loop {
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(ref error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            };
+            match algorithm {
+                HashAlgorithm::Crc32 => crc32.update(&buffer[..read]),
+                HashAlgorithm::XxHash64 => xxhash.write(&buffer[..read]),
+                HashAlgorithm::Sha256 => sha256.update(&buffer[..read]),
+            }
+        }
+
+        let bytes = match algorithm {
+            HashAlgorithm::Crc32 => crc32.finalize().to_be_bytes().to_vec(),
+            HashAlgorithm::XxHash64 => xxhash.finish().to_be_bytes().to_vec(),
+            HashAlgorithm::Sha256 => sha256.finalize().to_vec(),
+        };
+        Ok(Checksum {
+            algorithm,
+            bytes,
+        })
+    }
+
+    pub fn from_bytes(algorithm: HashAlgorithm, content: &[u8]) -> Checksum {
+        Checksum::from_reader(algorithm, content).expect("Reading a slice can't fail")
+    }
+
+    //Parse the hexadecimal form of a checksum made with the algorithm.
+    pub fn from_hex(algorithm: HashAlgorithm, hex: &str) -> FileSystemResult<Checksum> {
+        let expected_len = match algorithm {
+            HashAlgorithm::Crc32 => 4,
+            HashAlgorithm::XxHash64 => 8,
+            HashAlgorithm::Sha256 => 32,
+        };
+        if hex.len() != expected_len * 2 || !hex.is_ascii() {
+            return Err(integrity_error(&format!("{} isn't a valid {} checksum", hex, algorithm)));
+        }
+        let bytes = (0..expected_len)
+            .map(|index| u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| integrity_error(&format!("{} isn't a valid {} checksum", hex, algorithm)))?;
+        Ok(Checksum {
+            algorithm,
+            bytes,
+        })
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.bytes.as_slice()
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.to_hex())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    size: u64,
+    checksum: Checksum,
+}
+
+impl ManifestEntry {
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn checksum(&self) -> &Checksum {
+        &self.checksum
+    }
+}
+
+//The expected files of a directory tree. See Filesystem::manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    algorithm: HashAlgorithm,
+    entries: BTreeMap<String, ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        Manifest {
+            algorithm,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    //Add the file at path, relative to the root of the tree. The checksum must use the algorithm of the manifest.
+    pub fn insert(&mut self, path: &str, size: u64, checksum: Checksum) -> FileSystemResult<()> {
+        if checksum.algorithm() != self.algorithm {
+            return Err(integrity_error(&format!(
+                "the checksum of {} uses {}, but the manifest uses {}",
+                path,
+                checksum.algorithm(),
+                self.algorithm
+            )));
+        }
+        if path.is_empty() || path.contains(['\n', '\r']) {
+            return Err(integrity_error(&format!("{:?} can't be stored in a manifest", path)));
+        }
+        self.entries.insert(String::from(path), ManifestEntry {
+            size,
+            checksum,
+        });
+        Ok(())
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    pub fn get(&self, path: &str) -> Option<&ManifestEntry> {
+        self.entries.get(path)
+    }
+
+    //The files, sorted by path.
+    pub fn iter(&self) -> btree_map::Iter<'_, String, ManifestEntry> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} {}", MANIFEST_HEADER, self.algorithm)?;
+        for (path, entry) in self.entries.iter() {
+            writeln!(f, "{} {} {}", entry.checksum.to_hex(), entry.size, path)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Manifest {
+    type Err = FileSystemError;
+
+    fn from_str(text: &str) -> FileSystemResult<Manifest> {
+        let mut lines = text.lines();
+        let algorithm = match lines.next().and_then(|header| header.strip_prefix(MANIFEST_HEADER)) {
+            Some(algorithm) => algorithm.trim().parse()?,
+            None => return Err(integrity_error("the manifest doesn't start with a supported header")),
+        };
+
+        let mut manifest = Manifest::new(algorithm);
+        for line in lines.filter(|line| !line.is_empty()) {
+            let mut fields = line.splitn(3, ' ');
+            match (fields.next(), fields.next().map(u64::from_str), fields.next()) {
+                (Some(checksum), Some(Ok(size)), Some(path)) => {
+                    manifest.insert(path, size, Checksum::from_hex(algorithm, checksum)?)?;
+                },
+                _ => return Err(integrity_error(&format!("the line {} of the manifest is corrupted", line))),
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+//The differences between a directory tree and its manifest. See Filesystem::verify.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    missing: Vec<String>,
+    extra: Vec<String>,
+    modified: Vec<String>,
+}
+
+impl VerifyReport {
+    pub(crate) fn new(missing: Vec<String>, extra: Vec<String>, modified: Vec<String>) -> Self {
+        VerifyReport {
+            missing,
+            extra,
+            modified,
+        }
+    }
+
+    //The files of the manifest which can't be found.
+    pub fn missing(&self) -> &[String] {
+        self.missing.as_slice()
+    }
+
+    //The files which aren't in the manifest.
+    pub fn extra(&self) -> &[String] {
+        self.extra.as_slice()
+    }
+
+    //The files whose size or checksum doesn't match the manifest.
+    pub fn modified(&self) -> &[String] {
+        self.modified.as_slice()
+    }
+
+    //Check if every file of the manifest is intact. The extra files don't damage an install.
+    pub fn is_intact(&self) -> bool {
+        self.missing.is_empty() && self.modified.is_empty()
+    }
+
+    //Check if the tree is exactly the one described by the manifest.
+    pub fn is_identical(&self) -> bool {
+        self.is_intact() && self.extra.is_empty()
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for path in self.missing.iter() {
+            writeln!(f, "missing: {}", path)?;
+        }
+        for path in self.modified.iter() {
+            writeln!(f, "modified: {}", path)?;
+        }
+        for path in self.extra.iter() {
+            writeln!(f, "extra: {}", path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod integrity_test {
+    use super::*;
+
+    #[test]
+    fn integrity_checksums() {
+        assert_eq!(Checksum::from_bytes(HashAlgorithm::Crc32, b"123456789").to_hex(), "cbf43926");
+        assert_eq!(Checksum::from_bytes(HashAlgorithm::XxHash64, b"").to_hex(), "ef46db3751d8e999");
+        assert_eq!(
+            Checksum::from_bytes(HashAlgorithm::Sha256, b"test").to_hex(),
+            "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+        );
+
+        let checksum = Checksum::from_bytes(HashAlgorithm::XxHash64, b"maskerad");
+        assert_eq!(Checksum::from_hex(HashAlgorithm::XxHash64, checksum.to_hex().as_str()).unwrap(), checksum);
+        assert!(Checksum::from_hex(HashAlgorithm::Sha256, checksum.to_hex().as_str()).is_err());
+        assert!(Checksum::from_hex(HashAlgorithm::Crc32, "zzzzzzzz").is_err());
+    }
+
+    #[test]
+    fn integrity_manifest_text() {
+        let mut manifest = Manifest::new(HashAlgorithm::Sha256);
+        manifest.insert("data/levels/level one.lvl", 4, Checksum::from_bytes(HashAlgorithm::Sha256, b"test")).unwrap();
+        manifest.insert("readme.txt", 0, Checksum::from_bytes(HashAlgorithm::Sha256, b"")).unwrap();
+        assert!(manifest.insert("crc.txt", 0, Checksum::from_bytes(HashAlgorithm::Crc32, b"")).is_err());
+
+        let text = manifest.to_string();
+        assert!(text.starts_with("maskerad-manifest 1 sha256\n9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08 4 data/levels/level one.lvl\n"));
+        assert_eq!(text.parse::<Manifest>().unwrap(), manifest);
+        assert!("maskerad-manifest 1 md5\n".parse::<Manifest>().is_err());
+        assert!("maskerad-manifest 1 crc32\ncbf43926 data.txt\n".parse::<Manifest>().is_err());
+    }
+}
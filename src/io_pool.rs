@@ -0,0 +1,479 @@
+// Copyright 2017-2018 Maskerad Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+use filesystem_error::{FileSystemError, FileSystemResult};
+
+/*I/O WORKER POOL.
+
+The blocking I/O operations are sent to a small pool of dedicated threads, so the game loop
+never waits for the disk. Every operation returns an IoHandle, which can be polled from the
+game loop, waited for, or awaited as a future.
+
+The operations are queued by priority: a level load must not wait behind a hundred streamed
+textures. While an operation is still queued, its handle can cancel it (the player turned around,
+the texture isn't needed anymore) or change its priority.
+*/
+
+//The priority of an I/O operation. The I/O threads always pick the oldest operation of the highest priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum IoPriority {
+    //Needed right now, the game is waiting for it.
+    Critical,
+    //Needed very soon, like the assets of a level being loaded.
+    High,
+    #[default]
+    Normal,
+    //Streaming, prefetching... Only done when nothing else is waiting.
+    Background,
+}
+
+impl IoPriority {
+    const COUNT: usize = 4;
+
+    fn index(self) -> usize {
+        match self {
+            IoPriority::Critical => 0,
+            IoPriority::High => 1,
+            IoPriority::Normal => 2,
+            IoPriority::Background => 3,
+        }
+    }
+}
+
+//The job is called with true if the operation has been cancelled before being run.
+type Job = Box<dyn FnOnce(bool) + Send>;
+
+struct QueuedJob {
+    id: u64,
+    job: Job,
+}
+
+#[derive(Default)]
+struct PoolState {
+    queues: [VecDeque<QueuedJob>; IoPriority::COUNT],
+    next_id: u64,
+    shutdown: bool,
+}
+
+impl PoolState {
+    fn pop(&mut self) -> Option<QueuedJob> {
+        self.queues.iter_mut().filter_map(VecDeque::pop_front).next()
+    }
+
+    fn remove(&mut self, id: u64) -> Option<QueuedJob> {
+        for queue in self.queues.iter_mut() {
+            if let Some(position) = queue.iter().position(|queued| queued.id == id) {
+                return queue.remove(position);
+            }
+        }
+        None
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+}
+
+#[derive(Default)]
+struct PoolShared {
+    state: Mutex<PoolState>,
+    condvar: Condvar,
+}
+
+fn lock<'a, T>(mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+    //A job panicking can't leave the state of the pool inconsistent.
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.as_str()
+    } else {
+        "unknown panic"
+    }
+}
+
+pub struct IoPool {
+    shared: Arc<PoolShared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl fmt::Debug for IoPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IoPool")
+            .field("workers", &self.workers.len())
+            .field("queued", &lock(&self.shared.state).len())
+            .finish()
+    }
+}
+
+impl IoPool {
+    // Create a pool of thread_count I/O threads
+    pub fn new(thread_count: usize) -> FileSystemResult<IoPool> {
+        debug!("Creating an IoPool with {} threads.", thread_count);
+        let mut pool = IoPool {
+            shared: Arc::new(PoolShared::default()),
+            workers: Vec::with_capacity(thread_count.max(1)),
+        };
+
+        for index in 0..thread_count.max(1) {
+            let shared = pool.shared.clone();
+            //If a thread can't be spawned, dropping the pool shuts down and joins the threads already started.
+            let worker = thread::Builder::new()
+                .name(format!("maskerad-io-{}", index))
+                .spawn(move || IoPool::work(shared.as_ref()))?;
+            pool.workers.push(worker);
+        }
+
+        Ok(pool)
+    }
+
+    fn work(shared: &PoolShared) {
+        loop {
+            let queued = {
+                let mut state = lock(&shared.state);
+                loop {
+                    if let Some(queued) = state.pop() {
+                        break queued;
+                    }
+                    if state.shutdown {
+                        return;
+                    }
+                    state = shared.condvar.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner());
+                }
+            };
+            //The operations are run under catch_unwind by the job, this only guards the completion of the handle.
+            if panic::catch_unwind(AssertUnwindSafe(|| (queued.job)(false))).is_err() {
+                error!("An I/O job panicked !");
+            }
+        }
+    }
+
+    // Run the operation on one of the I/O threads with the normal priority, and return a handle to its result
+    pub fn execute<T, F>(&self, operation: F) -> IoHandle<T> where
+        T: Send + 'static,
+        F: FnOnce() -> FileSystemResult<T> + Send + 'static,
+    {
+        self.execute_with_priority(IoPriority::Normal, operation)
+    }
+
+    // Queue the operation with the given priority, and return a handle to its result
+    pub fn execute_with_priority<T, F>(&self, priority: IoPriority, operation: F) -> IoHandle<T> where
+        T: Send + 'static,
+        F: FnOnce() -> FileSystemResult<T> + Send + 'static,
+    {
+        let mut state = lock(&self.shared.state);
+        let id = state.next_id;
+        state.next_id += 1;
+
+        let handle = IoHandle::new(id, Arc::downgrade(&self.shared));
+        let completion = handle.shared.clone();
+        let job: Job = Box::new(move |cancelled| {
+            let result = if cancelled {
+                Err(FileSystemError::AsyncError(String::from("The I/O operation has been cancelled.")))
+            } else {
+                panic::catch_unwind(AssertUnwindSafe(operation)).unwrap_or_else(|payload| {
+                    Err(FileSystemError::AsyncError(format!("The I/O operation panicked: {}", panic_message(payload.as_ref()))))
+                })
+            };
+            completion.complete(result, cancelled);
+        });
+
+        state.queues[priority.index()].push_back(QueuedJob { id, job });
+        drop(state);
+        self.shared.condvar.notify_one();
+        handle
+    }
+}
+
+impl Drop for IoPool {
+    fn drop(&mut self) {
+        debug!("Shutting down the IoPool, the queued operations will be completed.");
+        lock(&self.shared.state).shutdown = true;
+        self.shared.condvar.notify_all();
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                error!("An I/O thread panicked !");
+            }
+        }
+    }
+}
+
+struct HandleState<T> {
+    result: Option<FileSystemResult<T>>,
+    waker: Option<Waker>,
+    completed: bool,
+    cancelled: bool,
+}
+
+struct HandleShared<T> {
+    state: Mutex<HandleState<T>>,
+    condvar: Condvar,
+}
+
+impl<T> HandleShared<T> {
+    fn complete(&self, result: FileSystemResult<T>, cancelled: bool) {
+        let waker = {
+            let mut state = lock(&self.state);
+            state.result = Some(result);
+            state.completed = true;
+            state.cancelled = cancelled;
+            state.waker.take()
+        };
+        self.condvar.notify_all();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+//The result of an operation running on the I/O threads.
+pub struct IoHandle<T> {
+    shared: Arc<HandleShared<T>>,
+    id: u64,
+    pool: Weak<PoolShared>,
+}
+
+impl<T> fmt::Debug for IoHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = lock(&self.shared.state);
+        f.debug_struct("IoHandle")
+            .field("id", &self.id)
+            .field("completed", &state.completed)
+            .field("cancelled", &state.cancelled)
+            .finish()
+    }
+}
+
+impl<T> IoHandle<T> {
+    fn new(id: u64, pool: Weak<PoolShared>) -> IoHandle<T> {
+        IoHandle {
+            shared: Arc::new(HandleShared {
+                state: Mutex::new(HandleState {
+                    result: None,
+                    waker: None,
+                    completed: false,
+                    cancelled: false,
+                }),
+                condvar: Condvar::new(),
+            }),
+            id,
+            pool,
+        }
+    }
+
+    // Check if the operation is finished
+    pub fn is_complete(&self) -> bool {
+        lock(&self.shared.state).completed
+    }
+
+    // Check if the operation has been cancelled before being run
+    pub fn is_cancelled(&self) -> bool {
+        lock(&self.shared.state).cancelled
+    }
+
+    // Cancel the operation if it is still queued. Its result will be an AsyncError.
+    // Return false if the operation is already running or finished: it can't be stopped anymore.
+    pub fn cancel(&self) -> bool {
+        let queued = match self.pool.upgrade() {
+            Some(pool) => lock(&pool.state).remove(self.id),
+            None => None,
+        };
+        match queued {
+            Some(queued) => {
+                trace!("Cancelling the I/O operation {}", self.id);
+                (queued.job)(true);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Move the operation to the back of the queue of the given priority, if it is still queued.
+    // Return false if the operation is already running or finished.
+    pub fn set_priority(&self, priority: IoPriority) -> bool {
+        let pool = match self.pool.upgrade() {
+            Some(pool) => pool,
+            None => return false,
+        };
+        let mut state = lock(&pool.state);
+        match state.remove(self.id) {
+            Some(queued) => {
+                trace!("Moving the I/O operation {} to the {:?} priority", self.id, priority);
+                state.queues[priority.index()].push_back(queued);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Take the result of the operation if it is finished, without blocking.
+    // The result can be taken only once.
+    pub fn try_take(&mut self) -> Option<FileSystemResult<T>> {
+        lock(&self.shared.state).result.take()
+    }
+
+    // Block the current thread until the operation is finished, and return its result
+    pub fn wait(self) -> FileSystemResult<T> {
+        let mut state = lock(&self.shared.state);
+        while !state.completed {
+            state = self.shared.condvar.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        state.result.take().unwrap_or_else(|| Err(FileSystemError::AsyncError(String::from(
+            "The result of the I/O operation has already been taken."
+        ))))
+    }
+}
+
+impl<T> Future for IoHandle<T> {
+    type Output = FileSystemResult<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<FileSystemResult<T>> {
+        let mut state = lock(&self.shared.state);
+        if !state.completed {
+            state.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(state.result.take().unwrap_or_else(|| Err(FileSystemError::AsyncError(String::from(
+            "The result of the I/O operation has already been taken."
+        )))))
+    }
+}
+
+#[cfg(test)]
+pub mod io_pool_test {
+    use super::*;
+    use std::sync::mpsc;
+    use std::task::Wake;
+    use std::thread::Thread;
+    use std::time::Duration;
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    //A minimal executor, to await the I/O handles in the tests.
+    pub fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut context = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn io_pool_execute() {
+        let pool = IoPool::new(2).unwrap();
+        let (sender, receiver) = mpsc::channel::<()>();
+
+        let mut blocked = pool.execute(move || {
+            receiver.recv_timeout(Duration::from_secs(5)).ok();
+            Ok(1)
+        });
+        assert!(!blocked.is_complete());
+        assert!(blocked.try_take().is_none());
+
+        let failing = pool.execute(|| -> FileSystemResult<u32> {
+            Err(FileSystemError::AsyncError(String::from("failure")))
+        });
+        assert!(failing.wait().is_err());
+        assert_eq!(block_on(pool.execute(|| Ok(2))).unwrap(), 2);
+
+        sender.send(()).unwrap();
+        assert_eq!(blocked.wait().unwrap(), 1);
+    }
+
+    #[test]
+    fn io_pool_panicking_operation() {
+        let pool = IoPool::new(1).unwrap();
+        let panicking = pool.execute(|| -> FileSystemResult<u32> { panic!("corrupted asset") });
+        match panicking.wait() {
+            Err(FileSystemError::AsyncError(message)) => assert!(message.contains("corrupted asset")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        //The only I/O thread survived the panic.
+        assert_eq!(pool.execute(|| Ok(3)).wait().unwrap(), 3);
+    }
+
+    #[test]
+    fn io_pool_drop_completes_queued_jobs() {
+        let pool = IoPool::new(1).unwrap();
+        let handles: Vec<IoHandle<usize>> = (0..10).map(|index| pool.execute(move || Ok(index))).collect();
+        drop(pool);
+        for (index, mut handle) in handles.into_iter().enumerate() {
+            assert!(handle.is_complete());
+            assert_eq!(handle.try_take().unwrap().unwrap(), index);
+        }
+    }
+
+    #[test]
+    fn io_pool_priorities_and_cancellation() {
+        let pool = IoPool::new(1).unwrap();
+        let (sender, receiver) = mpsc::channel::<()>();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let blocked = pool.execute_with_priority(IoPriority::Critical, move || {
+            receiver.recv_timeout(Duration::from_secs(5)).ok();
+            Ok(())
+        });
+        //Wait for the only I/O thread to be busy, so the next operations stay queued.
+        while lock(&pool.shared.state).len() > 0 {
+            thread::yield_now();
+        }
+
+        let record = |name: &'static str| {
+            let order = order.clone();
+            move || {
+                lock(&order).push(name);
+                Ok(name)
+            }
+        };
+        let texture = pool.execute_with_priority(IoPriority::Background, record("texture"));
+        let music = pool.execute_with_priority(IoPriority::Background, record("music"));
+        let save = pool.execute_with_priority(IoPriority::Normal, record("save"));
+        let level = pool.execute_with_priority(IoPriority::High, record("level"));
+        let stale = pool.execute_with_priority(IoPriority::Background, record("stale"));
+
+        assert!(stale.cancel());
+        assert!(!stale.cancel());
+        assert!(stale.is_complete());
+        assert!(stale.is_cancelled());
+        assert!(stale.wait().is_err());
+        assert!(music.set_priority(IoPriority::Critical));
+
+        sender.send(()).unwrap();
+        blocked.wait().unwrap();
+        assert_eq!(texture.wait().unwrap(), "texture");
+        assert!(!level.cancel());
+        assert!(!level.set_priority(IoPriority::Background));
+        assert!(!level.is_cancelled());
+        assert_eq!(level.wait().unwrap(), "level");
+        assert_eq!(save.wait().unwrap(), "save");
+        assert_eq!(music.wait().unwrap(), "music");
+        assert_eq!(*lock(&order), vec!["music", "level", "save", "texture"]);
+    }
+}
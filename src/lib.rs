@@ -12,8 +12,34 @@ extern crate serde;
 extern crate log;
 
 extern crate remove_dir_all;
+extern crate flate2;
+extern crate crc32fast;
+extern crate notify;
+extern crate twox_hash;
+extern crate sha2;
+#[cfg(test)]
+extern crate tempfile;
+#[cfg(all(test, unix))]
+extern crate libc;
 
 pub mod filesystem_error;
 pub mod game_directories;
 pub mod filesystem;
 pub mod open_options;
+pub mod mount;
+pub mod dir_entry;
+pub mod backend;
+pub mod file_handle;
+pub mod file_lock;
+pub mod atomic_writer;
+pub mod save_slots;
+pub mod integrity;
+pub mod file_reader;
+pub mod metadata;
+pub mod memory_filesystem;
+pub mod zip_archive;
+pub mod pack;
+pub mod io_pool;
+pub mod watcher;
+pub mod sandbox;
+pub mod virtual_path;
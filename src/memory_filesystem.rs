@@ -0,0 +1,701 @@
+// Copyright 2017-2018 Maskerad Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fmt;
+use std::cmp;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::SystemTime;
+use filesystem_error::{FileSystemError, FileSystemResult, Operation};
+use backend::{Backend, BackendFile, ReadSeek};
+use dir_entry::DirEntry;
+use file_reader::FileReader;
+use metadata::Metadata;
+use open_options::OpenOptions;
+use mount::{archive_parent, to_archive_path};
+
+/*IN-MEMORY FILESYSTEM.
+
+A tree of files and directories living in memory, with the same operations as the disk.
+Mounted in a Filesystem (see Filesystem::in_memory), the tests and the tools never touch the
+disk: nothing is left behind, and the tests can run in parallel.
+
+A MemoryFilesystem is a handle: its clones share the same tree.
+The paths are relative to the root of the tree, and stored in the '/' separated form of the archives.
+*/
+
+#[derive(Debug, Clone)]
+enum MemoryNode {
+    Directory(SystemTime),
+    File(Vec<u8>, SystemTime),
+}
+
+#[derive(Debug, Default)]
+struct MemoryTree {
+    //The root directory is implicit, its path is the empty string.
+    nodes: BTreeMap<String, MemoryNode>,
+}
+
+impl MemoryTree {
+    fn node(&self, path: &str) -> Option<&MemoryNode> {
+        self.nodes.get(path)
+    }
+
+    fn is_dir(&self, path: &str) -> bool {
+        path.is_empty() || matches!(self.node(path), Some(&MemoryNode::Directory(_)))
+    }
+
+    //The paths of the descendants of the directory.
+    fn descendants(&self, path: &str) -> Vec<String> {
+        let prefix = if path.is_empty() { String::new() } else { format!("{}/", path) };
+        self.nodes
+            .range(prefix.clone()..)
+            .take_while(|&(descendant, _)| descendant.starts_with(prefix.as_str()))
+            .map(|(descendant, _)| descendant.clone())
+            .collect()
+    }
+
+    //Check that the parent directory exists, to create the file or directory at path.
+    fn check_parent(&self, path: &str) -> io::Result<()> {
+        if self.is_dir(archive_parent(path)) {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("The parent directory of {} doesn't exist in memory", path),
+        ))
+    }
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} doesn't exist in memory", path))
+}
+
+fn too_large(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{} would be too large to be stored in memory", path))
+}
+
+//Truncate or extend the content, with zeros, without aborting if the memory can't be allocated.
+fn resize(content: &mut Vec<u8>, len: u64, path: &str) -> io::Result<()> {
+    let len = usize::try_from(len).map_err(|_| too_large(path))?;
+    if let Some(additional) = len.checked_sub(content.len()) {
+        content.try_reserve(additional).map_err(|_| {
+            io::Error::new(io::ErrorKind::StorageFull, format!("Not enough memory to extend {}", path))
+        })?;
+    }
+    content.resize(len, 0);
+    Ok(())
+}
+
+//A filesystem in memory.
+#[derive(Default, Clone)]
+pub struct MemoryFilesystem {
+    tree: Arc<RwLock<MemoryTree>>,
+}
+
+impl fmt::Debug for MemoryFilesystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MemoryFilesystem")
+            .field("nodes", &self.read_tree().nodes.len())
+            .finish()
+    }
+}
+
+//Two handles are equal if they share the same tree.
+impl PartialEq for MemoryFilesystem {
+    fn eq(&self, other: &MemoryFilesystem) -> bool {
+        Arc::ptr_eq(&self.tree, &other.tree)
+    }
+}
+
+impl MemoryFilesystem {
+    pub fn new() -> Self {
+        MemoryFilesystem::default()
+    }
+
+    //A writer panicking can't leave the tree inconsistent: every modification is done in one step.
+    fn read_tree(&self) -> RwLockReadGuard<'_, MemoryTree> {
+        self.tree.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write_tree(&self) -> RwLockWriteGuard<'_, MemoryTree> {
+        self.tree.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn error_context(&self, error: FileSystemError, operation: Operation, path: &Path) -> FileSystemError {
+        error.with_operation(operation).with_path(Backend::display_path(self, path))
+    }
+
+    fn key<P: AsRef<Path>>(path: P) -> FileSystemResult<String> {
+        to_archive_path(path.as_ref()).ok_or_else(|| FileSystemError::MountError(format!(
+            "{} isn't a valid path in an in-memory filesystem.",
+            path.as_ref().display()
+        )))
+    }
+
+    pub fn exists<P: AsRef<Path>>(&self, path: P) -> bool {
+        match MemoryFilesystem::key(path) {
+            Ok(ref key) => key.is_empty() || self.read_tree().node(key).is_some(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn is_dir<P: AsRef<Path>>(&self, path: P) -> bool {
+        match MemoryFilesystem::key(path) {
+            Ok(ref key) => self.read_tree().is_dir(key),
+            Err(_) => false,
+        }
+    }
+
+    pub fn is_file<P: AsRef<Path>>(&self, path: P) -> bool {
+        match MemoryFilesystem::key(path) {
+            Ok(ref key) => matches!(self.read_tree().node(key), Some(&MemoryNode::File(_, _))),
+            Err(_) => false,
+        }
+    }
+
+    pub fn metadata<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<Metadata> {
+        let key = MemoryFilesystem::key(path)?;
+        if key.is_empty() {
+            return Ok(Metadata::new(0, true, None, false));
+        }
+        match self.read_tree().node(key.as_str()) {
+            Some(&MemoryNode::Directory(modified)) => Ok(Metadata::new(0, true, Some(modified), false)),
+            Some(&MemoryNode::File(ref content, modified)) => Ok(Metadata::new(content.len() as u64, false, Some(modified), false)),
+            None => Err(FileSystemError::from(not_found(key.as_str()))),
+        }
+    }
+
+    //The whole content of the file.
+    pub fn read<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<Vec<u8>> {
+        let key = MemoryFilesystem::key(path)?;
+        match self.read_tree().node(key.as_str()) {
+            Some(MemoryNode::File(content, _)) => Ok(content.clone()),
+            Some(&MemoryNode::Directory(_)) => Err(FileSystemError::from(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is a directory", key),
+            ))),
+            None => Err(FileSystemError::from(not_found(key.as_str()))),
+        }
+    }
+
+    //Open the file to read it. The reader works on a copy: the file can be modified meanwhile.
+    pub fn open_file<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<FileReader> {
+        Ok(FileReader::Memory(Cursor::new(self.read(path)?)))
+    }
+
+    //Replace the content of the file, creating it if necessary. Its parent directory must exist.
+    pub fn write_file<P: AsRef<Path>>(&self, path: P, content: Vec<u8>) -> FileSystemResult<()> {
+        let key = MemoryFilesystem::key(path)?;
+        let mut tree = self.write_tree();
+        if tree.is_dir(key.as_str()) {
+            return Err(FileSystemError::from(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is a directory", key),
+            )));
+        }
+        tree.check_parent(key.as_str())?;
+        tree.nodes.insert(key, MemoryNode::File(content, SystemTime::now()));
+        Ok(())
+    }
+
+    //Open the file for writing, truncating it if it already exists.
+    pub fn create<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<MemoryFileWriter> {
+        self.write_file(path.as_ref(), Vec::new())?;
+        Ok(MemoryFileWriter {
+            filesystem: self.clone(),
+            path: MemoryFilesystem::key(path)?,
+        })
+    }
+
+    //Open the file for appending, creating it if necessary.
+    pub fn append<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<MemoryFileWriter> {
+        if !self.is_file(path.as_ref()) {
+            self.write_file(path.as_ref(), Vec::new())?;
+        }
+        Ok(MemoryFileWriter {
+            filesystem: self.clone(),
+            path: MemoryFilesystem::key(path)?,
+        })
+    }
+
+    //Open the file to read, write and seek in it, following the options.
+    //Unlike the readers, the handle works on the file itself: its modifications are seen immediately.
+    pub fn open_with_options<P: AsRef<Path>>(&self, path: P, options: &OpenOptions) -> FileSystemResult<MemoryFileHandle> {
+        options.validate()?;
+        let key = MemoryFilesystem::key(path)?;
+        let mut tree = self.write_tree();
+        match tree.nodes.get_mut(key.as_str()) {
+            Some(&mut MemoryNode::Directory(_)) => return Err(FileSystemError::from(io::Error::new(
+                io::ErrorKind::IsADirectory,
+                format!("{} is a directory", key),
+            ))),
+            Some(&mut MemoryNode::File(_, _)) if options.create_new() => return Err(FileSystemError::from(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", key),
+            ))),
+            Some(&mut MemoryNode::File(ref mut content, ref mut modified)) => {
+                if options.truncate() {
+                    content.clear();
+                    *modified = SystemTime::now();
+                }
+            },
+            None if options.create() || options.create_new() => {
+                tree.check_parent(key.as_str())?;
+                tree.nodes.insert(key.clone(), MemoryNode::File(Vec::new(), SystemTime::now()));
+            },
+            None => return Err(FileSystemError::from(not_found(key.as_str()))),
+        }
+        Ok(MemoryFileHandle {
+            filesystem: self.clone(),
+            path: key,
+            position: 0,
+            read: options.read(),
+            write: options.write() || options.append(),
+            append: options.append(),
+        })
+    }
+
+    //Create the directory and its missing parents.
+    pub fn mkdir<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<()> {
+        let key = MemoryFilesystem::key(path)?;
+        let mut tree = self.write_tree();
+        let mut directory = String::new();
+        for component in key.split('/').filter(|component| !component.is_empty()) {
+            if !directory.is_empty() {
+                directory.push('/');
+            }
+            directory.push_str(component);
+            match tree.node(directory.as_str()) {
+                Some(&MemoryNode::Directory(_)) => {},
+                Some(&MemoryNode::File(_, _)) => return Err(FileSystemError::from(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} is a file", directory),
+                ))),
+                None => {
+                    tree.nodes.insert(directory.clone(), MemoryNode::Directory(SystemTime::now()));
+                },
+            }
+        }
+        Ok(())
+    }
+
+    //Remove the file, or the empty directory.
+    pub fn rm<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<()> {
+        let key = MemoryFilesystem::key(path)?;
+        let mut tree = self.write_tree();
+        if tree.node(key.as_str()).is_none() {
+            return Err(FileSystemError::from(not_found(key.as_str())));
+        }
+        if !tree.descendants(key.as_str()).is_empty() {
+            return Err(FileSystemError::from(io::Error::other(
+                format!("The directory {} isn't empty", key),
+            )));
+        }
+        tree.nodes.remove(key.as_str());
+        Ok(())
+    }
+
+    //Move the file or the directory, replacing the file at to if it exists.
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&self, from: P, to: Q) -> FileSystemResult<()> {
+        let from = MemoryFilesystem::key(from)?;
+        let to = MemoryFilesystem::key(to)?;
+        let mut tree = self.write_tree();
+        let node = match tree.node(from.as_str()) {
+            Some(node) if !from.is_empty() => node.clone(),
+            _ => return Err(FileSystemError::from(not_found(from.as_str()))),
+        };
+        if from == to {
+            return Ok(());
+        }
+        tree.check_parent(to.as_str())?;
+        let replaced = tree.node(to.as_str()).cloned();
+        match (&node, replaced) {
+            (_, None) | (&MemoryNode::File(_, _), Some(MemoryNode::File(_, _))) => {},
+            _ => return Err(FileSystemError::from(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} can't replace {}", from, to),
+            ))),
+        }
+        if to.starts_with(format!("{}/", from).as_str()) {
+            return Err(FileSystemError::from(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} can't be moved inside itself", from),
+            )));
+        }
+        for descendant in tree.descendants(from.as_str()) {
+            if let Some(descendant_node) = tree.nodes.remove(descendant.as_str()) {
+                let moved = format!("{}{}", to, &descendant[from.len()..]);
+                tree.nodes.insert(moved, descendant_node);
+            }
+        }
+        tree.nodes.remove(from.as_str());
+        tree.nodes.insert(to, node);
+        Ok(())
+    }
+
+    //Remove the file, or the directory and all its content.
+    pub fn rmrf<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<()> {
+        let key = MemoryFilesystem::key(path)?;
+        let mut tree = self.write_tree();
+        if !key.is_empty() && tree.node(key.as_str()).is_none() {
+            return Err(FileSystemError::from(not_found(key.as_str())));
+        }
+        for descendant in tree.descendants(key.as_str()) {
+            tree.nodes.remove(descendant.as_str());
+        }
+        tree.nodes.remove(key.as_str());
+        Ok(())
+    }
+
+    //The file names in the directory, and whether they are directories.
+    pub fn read_dir<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<Vec<(OsString, bool)>> {
+        let key = MemoryFilesystem::key(path)?;
+        let tree = self.read_tree();
+        if !tree.is_dir(key.as_str()) {
+            return Err(FileSystemError::from(not_found(key.as_str())));
+        }
+
+        Ok(tree.descendants(key.as_str())
+            .into_iter()
+            .filter(|descendant| archive_parent(descendant) == key)
+            .map(|descendant| {
+                let is_dir = tree.is_dir(descendant.as_str());
+                let file_name = descendant.rsplit('/').next().unwrap_or_default();
+                (OsString::from(file_name), is_dir)
+            })
+            .collect())
+    }
+}
+
+impl Backend for MemoryFilesystem {
+    fn display_path(&self, path: &Path) -> PathBuf {
+        Path::new("memory:").join(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        MemoryFilesystem::exists(self, path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        MemoryFilesystem::is_dir(self, path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        MemoryFilesystem::is_file(self, path)
+    }
+
+    fn metadata(&self, path: &Path) -> FileSystemResult<Metadata> {
+        MemoryFilesystem::metadata(self, path).map_err(|error| self.error_context(error, Operation::Metadata, path))
+    }
+
+    fn open(&self, path: &Path) -> FileSystemResult<Box<dyn ReadSeek>> {
+        match self.open_file(path) {
+            Ok(reader) => Ok(Box::new(reader)),
+            Err(error) => Err(self.error_context(error, Operation::Open, path)),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> FileSystemResult<Vec<DirEntry>> {
+        Ok(MemoryFilesystem::read_dir(self, path)
+            .map_err(|error| self.error_context(error, Operation::ReadDir, path))?
+            .into_iter()
+            .map(|(file_name, is_dir)| DirEntry::new(path, file_name, is_dir))
+            .collect())
+    }
+
+    fn open_with_options(&self, path: &Path, options: &OpenOptions) -> FileSystemResult<Box<dyn BackendFile>> {
+        match MemoryFilesystem::open_with_options(self, path, options) {
+            Ok(handle) => Ok(Box::new(handle)),
+            Err(error) => Err(self.error_context(error, Operation::Open, path)),
+        }
+    }
+
+    fn create(&self, path: &Path) -> FileSystemResult<Box<dyn Write + Send>> {
+        match MemoryFilesystem::create(self, path) {
+            Ok(writer) => Ok(Box::new(writer)),
+            Err(error) => Err(self.error_context(error, Operation::Create, path)),
+        }
+    }
+
+    fn append(&self, path: &Path) -> FileSystemResult<Box<dyn Write + Send>> {
+        match MemoryFilesystem::append(self, path) {
+            Ok(writer) => Ok(Box::new(writer)),
+            Err(error) => Err(self.error_context(error, Operation::Append, path)),
+        }
+    }
+
+    fn write(&self, path: &Path, content: &[u8]) -> FileSystemResult<()> {
+        self.write_file(path, content.to_vec()).map_err(|error| self.error_context(error, Operation::Write, path))
+    }
+
+    fn mkdir(&self, path: &Path) -> FileSystemResult<()> {
+        MemoryFilesystem::mkdir(self, path).map_err(|error| self.error_context(error, Operation::Mkdir, path))
+    }
+
+    fn rm(&self, path: &Path) -> FileSystemResult<()> {
+        MemoryFilesystem::rm(self, path).map_err(|error| self.error_context(error, Operation::Rm, path))
+    }
+
+    fn rmrf(&self, path: &Path) -> FileSystemResult<()> {
+        MemoryFilesystem::rmrf(self, path).map_err(|error| self.error_context(error, Operation::Rmrf, path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> FileSystemResult<()> {
+        MemoryFilesystem::rename(self, from, to).map_err(|error| self.error_context(error, Operation::Rename, from))
+    }
+}
+
+//Write to a file of a MemoryFilesystem. Every write is appended to the file.
+#[derive(Debug)]
+pub struct MemoryFileWriter {
+    filesystem: MemoryFilesystem,
+    path: String,
+}
+
+impl Write for MemoryFileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut tree = self.filesystem.write_tree();
+        match tree.nodes.get_mut(self.path.as_str()) {
+            Some(&mut MemoryNode::File(ref mut content, ref mut modified)) => {
+                content.extend_from_slice(buf);
+                *modified = SystemTime::now();
+                Ok(buf.len())
+            },
+            _ => Err(not_found(self.path.as_str())),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+//A file of a MemoryFilesystem, opened with OpenOptions.
+#[derive(Debug)]
+pub struct MemoryFileHandle {
+    filesystem: MemoryFilesystem,
+    path: String,
+    position: u64,
+    read: bool,
+    write: bool,
+    append: bool,
+}
+
+impl MemoryFileHandle {
+    //Give the content of the file to f, if the file still exists.
+    fn with_file<T, F: FnOnce(&mut Vec<u8>, &mut SystemTime) -> T>(&self, f: F) -> io::Result<T> {
+        let mut tree = self.filesystem.write_tree();
+        match tree.nodes.get_mut(self.path.as_str()) {
+            Some(&mut MemoryNode::File(ref mut content, ref mut modified)) => Ok(f(content, modified)),
+            _ => Err(not_found(self.path.as_str())),
+        }
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.write {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} hasn't been opened to write", self.path),
+        ))
+    }
+}
+
+impl Read for MemoryFileHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.read {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} hasn't been opened to read", self.path),
+            ));
+        }
+        let position = self.position;
+        let read = self.with_file(|content, _| {
+            let start = cmp::min(position, content.len() as u64) as usize;
+            let read = cmp::min(buf.len(), content.len() - start);
+            buf[..read].copy_from_slice(&content[start..start + read]);
+            read
+        })?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for MemoryFileHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_writable()?;
+        let (position, append, path) = (self.position, self.append, self.path.as_str());
+        self.position = self.with_file(|content, modified| -> io::Result<u64> {
+            let start = if append { Some(content.len()) } else { usize::try_from(position).ok() };
+            let end = start
+                .and_then(|start| start.checked_add(buf.len()))
+                .ok_or_else(|| too_large(path))?;
+            if content.len() < end {
+                resize(content, end as u64, path)?;
+            }
+            content[end - buf.len()..end].copy_from_slice(buf);
+            *modified = SystemTime::now();
+            Ok(end as u64)
+        })??;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFileHandle {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.with_file(|content, _| content.len() as u64)?;
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            },
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+impl BackendFile for MemoryFileHandle {
+    fn metadata(&self) -> FileSystemResult<Metadata> {
+        let (len, modified) = self.with_file(|content, modified| (content.len() as u64, *modified))?;
+        Ok(Metadata::new(len, false, Some(modified), false))
+    }
+
+    //The modifications are already in the tree.
+    fn sync_all(&mut self) -> FileSystemResult<()> {
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> FileSystemResult<()> {
+        self.check_writable()?;
+        let path = self.path.as_str();
+        self.with_file(|content, modified| -> io::Result<()> {
+            resize(content, len, path)?;
+            *modified = SystemTime::now();
+            Ok(())
+        })?
+        .map_err(FileSystemError::from)
+    }
+}
+
+#[cfg(test)]
+mod memory_filesystem_test {
+    use super::*;
+    use std::io::Read;
+    use filesystem_error::ErrorKind;
+
+    #[test]
+    fn memory_filesystem_operations() {
+        let memory = MemoryFilesystem::new();
+        assert!(memory.is_dir(""));
+        assert!(memory.create("textures/hero.png").is_err());
+
+        memory.mkdir("textures/characters").unwrap();
+        memory.create("textures/characters/hero.png").unwrap().write_all(b"hero").unwrap();
+        let mut writer = memory.append("textures/characters/hero.png").unwrap();
+        writer.write_all(b" and").unwrap();
+        writer.write_all(b" villain").unwrap();
+        memory.write_file("textures/wall.png", b"wall".to_vec()).unwrap();
+
+        let mut content = String::new();
+        memory.open_file("textures/characters/hero.png").unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hero and villain");
+        assert_eq!(memory.metadata("textures/wall.png").unwrap().len(), 4);
+        assert!(memory.metadata("textures").unwrap().is_dir());
+        assert!(memory.metadata("missing").is_err());
+        assert!(memory.mkdir("textures/wall.png/inside").is_err());
+
+        //The clones share the same tree.
+        let clone = memory.clone();
+        assert_eq!(clone, memory);
+        assert_ne!(MemoryFilesystem::new(), memory);
+        assert_eq!(clone.read_dir("textures").unwrap(), vec![
+            (OsString::from("characters"), true),
+            (OsString::from("wall.png"), false),
+        ]);
+        assert_eq!(clone.read_dir("").unwrap(), vec![(OsString::from("textures"), true)]);
+
+        //A file replaces the file at its new path, a directory is moved with its content.
+        memory.write_file("textures/floor.png", b"floor".to_vec()).unwrap();
+        memory.rename("textures/floor.png", "textures/wall.png").unwrap();
+        assert_eq!(memory.read("textures/wall.png").unwrap(), b"floor");
+        assert!(!memory.exists("textures/floor.png"));
+        assert!(memory.rename("textures/wall.png", "textures/characters").is_err());
+        assert!(memory.rename("textures/characters", "textures/characters/inside").is_err());
+        memory.rename("textures/characters", "textures/heroes").unwrap();
+        assert!(memory.is_file("textures/heroes/hero.png"));
+        memory.rename("textures/heroes", "textures/characters").unwrap();
+
+        assert!(memory.rm("textures").is_err());
+        memory.rm("textures/wall.png").unwrap();
+        assert!(!memory.exists("textures/wall.png"));
+        memory.rmrf("textures").unwrap();
+        assert!(!memory.exists("textures/characters/hero.png"));
+        assert!(writer.write_all(b"!").is_err());
+        assert!(memory.read_dir("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn memory_filesystem_file_handle() {
+        let memory = MemoryFilesystem::new();
+        memory.write_file("slot_1.sav", b"version=1;data".to_vec()).unwrap();
+
+        let mut options = OpenOptions::new();
+        options.set_read(true).set_write(true);
+        let mut handle = memory.open_with_options("slot_1.sav", &options).unwrap();
+        handle.seek(SeekFrom::Start(8)).unwrap();
+        handle.write_all(b"2").unwrap();
+        assert_eq!(memory.read("slot_1.sav").unwrap(), b"version=2;data");
+        let mut data = String::new();
+        handle.read_to_string(&mut data).unwrap();
+        assert_eq!(data, ";data");
+        handle.set_len(9).unwrap();
+        assert_eq!(handle.metadata().unwrap().len(), 9);
+        assert_eq!(handle.seek(SeekFrom::End(-1)).unwrap(), 8);
+        assert!(handle.seek(SeekFrom::Current(-10)).is_err());
+
+        assert!(memory.open_with_options("slot_2.sav", &options).is_err());
+        options.set_create_new(true);
+        assert!(memory.open_with_options("slot_1.sav", &options).is_err());
+        let mut handle = memory.open_with_options("slot_2.sav", &options).unwrap();
+        handle.write_all(b"new").unwrap();
+        assert_eq!(memory.read("slot_2.sav").unwrap(), b"new");
+
+        //The sizes which can't be stored in memory are rejected, the file is left untouched.
+        handle.seek(SeekFrom::Start(u64::MAX)).unwrap();
+        assert_eq!(handle.write(b"overflow").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(handle.set_len(u64::MAX).is_err());
+        assert_eq!(handle.set_len(u64::MAX >> 1).err().unwrap().kind(), ErrorKind::DiskFull);
+        assert_eq!(memory.read("slot_2.sav").unwrap(), b"new");
+
+        let mut reader = memory.open_with_options("slot_2.sav", OpenOptions::new().set_read(true)).unwrap();
+        assert!(reader.write_all(b"!").is_err());
+        let mut appender = memory.open_with_options("slot_2.sav", OpenOptions::new().set_append(true)).unwrap();
+        appender.write_all(b" save").unwrap();
+        assert!(appender.read(&mut [0; 4]).is_err());
+        assert_eq!(memory.read("slot_2.sav").unwrap(), b"new save");
+        memory.rm("slot_2.sav").unwrap();
+        assert!(appender.write_all(b"!").is_err());
+    }
+}
@@ -0,0 +1,67 @@
+// Copyright 2017-2018 Maskerad Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::fs;
+use std::time::SystemTime;
+
+//Information about a file or a directory, whatever its source is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    len: u64,
+    is_dir: bool,
+    modified: Option<SystemTime>,
+    read_only: bool,
+}
+
+impl Metadata {
+    pub(crate) fn new(len: u64, is_dir: bool, modified: Option<SystemTime>, read_only: bool) -> Metadata {
+        Metadata {
+            len,
+            is_dir,
+            modified,
+            read_only,
+        }
+    }
+
+    //The size of the file in bytes, once decompressed.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    pub fn is_file(&self) -> bool {
+        !self.is_dir
+    }
+
+    //The last modification time, if the source records it (archives don't).
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    //Check if the file can't be modified, like the content of an archive.
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+impl<'a> From<&'a fs::Metadata> for Metadata {
+    fn from(metadata: &'a fs::Metadata) -> Metadata {
+        Metadata::new(
+            metadata.len(),
+            metadata.is_dir(),
+            metadata.modified().ok(),
+            metadata.permissions().readonly(),
+        )
+    }
+}
@@ -0,0 +1,376 @@
+// Copyright 2017-2018 Maskerad Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use game_directories::RootDir;
+use zip_archive::ZipArchive;
+use pack::PackArchive;
+use memory_filesystem::MemoryFilesystem;
+use backend::Backend;
+use filesystem_error::{FileSystemError, FileSystemResult};
+
+/*MOUNT TABLE.
+
+The mount table maps virtual paths (like /data/textures/hero.png) to the place where
+the files really live. A virtual path is made of a root (/) followed by normal components only.
+
+When resolving a virtual path, the mount point with the longest matching prefix wins:
+if /data and /data/textures are both mounted, /data/textures/hero.png is resolved through /data/textures.
+
+An overlay stacks several sources at the same mount point (mods over patches over the base game data).
+A lookup returns the file of the highest priority layer containing it, and writes go to the
+layer designated as writable.
+*/
+
+//Where the files mounted at a virtual path come from.
+#[derive(Debug, Clone)]
+pub enum MountSource {
+    //One of the game directories, see RootDir.
+    Root(RootDir),
+    //An arbitrary directory on the disk.
+    Directory(PathBuf),
+    //A read-only ZIP archive.
+    Zip(ZipArchive),
+    //A read-only Maskerad pack.
+    Pack(PackArchive),
+    //Several sources stacked on top of each other.
+    Overlay(Overlay),
+    //A filesystem living in memory.
+    Memory(MemoryFilesystem),
+    //Any other place where files can be stored.
+    Backend(Arc<dyn Backend>),
+}
+
+//The custom backends are compared by identity.
+impl PartialEq for MountSource {
+    fn eq(&self, other: &MountSource) -> bool {
+        match (self, other) {
+            (MountSource::Root(first), MountSource::Root(second)) => first == second,
+            (MountSource::Directory(first), MountSource::Directory(second)) => first == second,
+            (MountSource::Zip(first), MountSource::Zip(second)) => first == second,
+            (MountSource::Pack(first), MountSource::Pack(second)) => first == second,
+            (MountSource::Overlay(first), MountSource::Overlay(second)) => first == second,
+            (MountSource::Memory(first), MountSource::Memory(second)) => first == second,
+            (MountSource::Backend(first), MountSource::Backend(second)) => Arc::ptr_eq(first, second),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for MountSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MountSource::Root(ref root_dir) => {
+                write!(f, "{}", root_dir)
+            },
+            MountSource::Directory(ref path) => {
+                write!(f, "directory {}", path.display())
+            },
+            MountSource::Zip(ref archive) => {
+                write!(f, "ZIP archive {}", archive.path().display())
+            },
+            MountSource::Pack(ref pack) => {
+                write!(f, "pack {}", pack.path().display())
+            },
+            MountSource::Overlay(ref overlay) => {
+                write!(f, "overlay of {} layers", overlay.layers.len())
+            },
+            MountSource::Memory(_) => {
+                write!(f, "in-memory filesystem")
+            },
+            MountSource::Backend(ref backend) => {
+                write!(f, "backend {:?}", backend)
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverlayLayer {
+    source: MountSource,
+    priority: i32,
+    writable: bool,
+}
+
+impl OverlayLayer {
+    pub fn source(&self) -> &MountSource {
+        &self.source
+    }
+
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Overlay {
+    //Sorted from the highest priority to the lowest.
+    layers: Vec<OverlayLayer>,
+}
+
+impl Overlay {
+    // Create a new overlay, without any layer
+    pub fn new() -> Overlay {
+        debug!("Creating an empty Overlay.");
+        Default::default()
+    }
+
+    // Add a read-only layer. Layers with a higher priority shadow the layers with a lower one,
+    // layers with the same priority are looked up in insertion order.
+    pub fn add_layer(&mut self, source: MountSource, priority: i32) -> &mut Overlay {
+        debug!("Adding the {} to the overlay, with a priority of {}", source, priority);
+        self.insert_layer(OverlayLayer {
+            source,
+            priority,
+            writable: false,
+        });
+        self
+    }
+
+    // Add a layer receiving all the writes made through the overlay.
+    // Only one layer can be writable, the previous writable layer becomes read-only.
+    pub fn add_write_layer(&mut self, source: MountSource, priority: i32) -> &mut Overlay {
+        debug!("Adding the {} to the overlay as the writable layer, with a priority of {}", source, priority);
+        for layer in self.layers.iter_mut() {
+            layer.writable = false;
+        }
+        self.insert_layer(OverlayLayer {
+            source,
+            priority,
+            writable: true,
+        });
+        self
+    }
+
+    fn insert_layer(&mut self, layer: OverlayLayer) {
+        let index = self.layers
+            .iter()
+            .position(|other| other.priority < layer.priority)
+            .unwrap_or(self.layers.len());
+        self.layers.insert(index, layer);
+    }
+
+    // The layers, from the highest priority to the lowest
+    pub fn layers(&self) -> &[OverlayLayer] {
+        self.layers.as_slice()
+    }
+
+    // The layer receiving the writes, if any
+    pub fn write_layer(&self) -> Option<&OverlayLayer> {
+        self.layers.iter().find(|layer| layer.writable)
+    }
+}
+
+#[derive(Debug)]
+struct Mount {
+    point: PathBuf,
+    source: MountSource,
+}
+
+#[derive(Debug, Default)]
+pub struct MountTable {
+    mounts: Vec<Mount>,
+}
+
+//Lexically normalize a virtual path: remove the '.' components and resolve the '..' components.
+//Returns None if the path is not a virtual path (no root, windows prefix) or goes above the root.
+pub fn normalize_virtual_path<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
+    let mut components = path.as_ref().components();
+    match components.next() {
+        Some(Component::RootDir) => {},
+        _ => return None,
+    }
+
+    let mut normalized = PathBuf::from("/");
+    for component in components {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir => {},
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            },
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(normalized)
+}
+
+//Convert a path relative to the root of an archive to the '/' separated form used by the archives.
+//Returns None if the path isn't valid UTF-8 or goes above the root of the archive.
+pub(crate) fn to_archive_path<P: AsRef<Path>>(path: P) -> Option<String> {
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.as_ref().components() {
+        match component {
+            Component::Normal(name) => components.push(name.to_str()?),
+            Component::ParentDir => {
+                components.pop()?;
+            },
+            Component::RootDir | Component::CurDir => {},
+            Component::Prefix(_) => return None,
+        }
+    }
+    Some(components.join("/"))
+}
+
+//The parent directory of a '/' separated archive path, the root being "".
+pub(crate) fn archive_parent(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(index) => &path[..index],
+        None => "",
+    }
+}
+
+impl MountTable {
+    // Create a new, empty, mount table
+    pub fn new() -> MountTable {
+        debug!("Creating an empty MountTable.");
+        Default::default()
+    }
+
+    // Mount the source at the given virtual path
+    pub fn mount<P: AsRef<Path>>(&mut self, point: P, source: MountSource) -> FileSystemResult<()> {
+        debug!("Mounting the {} at {}", source, point.as_ref().display());
+        let point = match normalize_virtual_path(point.as_ref()) {
+            Some(point) => point,
+            None => {
+                error!("{} is not a valid mount point !", point.as_ref().display());
+                return Err(FileSystemError::MountError(format!(
+                    "{} is not a valid mount point, it must be an absolute virtual path (e.g. /data).",
+                    point.as_ref().display()
+                )));
+            },
+        };
+
+        if self.is_mounted(point.as_path()) {
+            error!("{} is already mounted !", point.display());
+            return Err(FileSystemError::MountError(format!(
+                "A source is already mounted at {}.",
+                point.display()
+            )));
+        }
+
+        self.mounts.push(Mount {
+            point,
+            source,
+        });
+        Ok(())
+    }
+
+    // Remove the source mounted at the given virtual path, and return it
+    pub fn unmount<P: AsRef<Path>>(&mut self, point: P) -> FileSystemResult<MountSource> {
+        debug!("Unmounting {}", point.as_ref().display());
+        let position = normalize_virtual_path(point.as_ref()).and_then(|point| {
+            self.mounts.iter().position(|mount| mount.point == point)
+        });
+
+        match position {
+            Some(index) => Ok(self.mounts.remove(index).source),
+            None => {
+                error!("Nothing is mounted at {} !", point.as_ref().display());
+                Err(FileSystemError::MountError(format!(
+                    "Nothing is mounted at {}.",
+                    point.as_ref().display()
+                )))
+            },
+        }
+    }
+
+    // Check if a source is mounted exactly at the given virtual path
+    pub fn is_mounted<P: AsRef<Path>>(&self, point: P) -> bool {
+        match normalize_virtual_path(point.as_ref()) {
+            Some(point) => self.mounts.iter().any(|mount| mount.point == point),
+            None => false,
+        }
+    }
+
+    // Find the mount responsible for the given virtual path.
+    // Returns the source and the path relative to the mount point, or None if no mount point matches.
+    pub fn resolve<P: AsRef<Path>>(&self, path: P) -> Option<(&MountSource, PathBuf)> {
+        trace!("Resolving {} through the mount table", path.as_ref().display());
+        let path = normalize_virtual_path(path.as_ref())?;
+
+        self.mounts
+            .iter()
+            .filter(|mount| path.starts_with(mount.point.as_path()))
+            .max_by_key(|mount| mount.point.components().count())
+            .and_then(|mount| {
+                path.strip_prefix(mount.point.as_path())
+                    .ok()
+                    .map(|relative| (&mount.source, relative.to_path_buf()))
+            })
+    }
+}
+
+#[cfg(test)]
+mod mount_test {
+    use super::*;
+
+    #[test]
+    fn mount_normalize_virtual_path() {
+        assert_eq!(normalize_virtual_path("/data/./textures/../hero.png"), Some(PathBuf::from("/data/hero.png")));
+        assert_eq!(normalize_virtual_path("/data/../.."), None);
+        assert_eq!(normalize_virtual_path("data/hero.png"), None);
+        assert_eq!(to_archive_path("textures/./hero.png"), Some(String::from("textures/hero.png")));
+        assert_eq!(to_archive_path(""), Some(String::new()));
+        assert_eq!(to_archive_path("../hero.png"), None);
+    }
+
+    #[test]
+    fn mount_resolve_longest_prefix() {
+        let mut table = MountTable::new();
+        table.mount("/data", MountSource::Root(RootDir::WorkingDirectory)).unwrap();
+        table.mount("/data/textures", MountSource::Directory(PathBuf::from("/opt/textures"))).unwrap();
+        assert!(table.mount("/data/", MountSource::Root(RootDir::UserDataRoot)).is_err());
+        assert!(table.mount("relative", MountSource::Root(RootDir::UserDataRoot)).is_err());
+
+        let (source, relative) = table.resolve("/data/textures/hero.png").unwrap();
+        assert_eq!(source, &MountSource::Directory(PathBuf::from("/opt/textures")));
+        assert_eq!(relative, PathBuf::from("hero.png"));
+
+        let (source, relative) = table.resolve("/data/sounds/step.ogg").unwrap();
+        assert_eq!(source, &MountSource::Root(RootDir::WorkingDirectory));
+        assert_eq!(relative, PathBuf::from("sounds/step.ogg"));
+
+        assert!(table.resolve("/database/file").is_none());
+
+        table.unmount("/data/textures").unwrap();
+        assert!(!table.is_mounted("/data/textures"));
+        assert!(table.unmount("/data/textures").is_err());
+    }
+
+    #[test]
+    fn mount_overlay_priorities() {
+        let mut overlay = Overlay::new();
+        overlay
+            .add_layer(MountSource::Directory(PathBuf::from("/base")), 0)
+            .add_write_layer(MountSource::Directory(PathBuf::from("/mods")), 20)
+            .add_layer(MountSource::Directory(PathBuf::from("/patch")), 10)
+            .add_layer(MountSource::Directory(PathBuf::from("/patch_2")), 10);
+
+        let order: Vec<&MountSource> = overlay.layers().iter().map(|layer| layer.source()).collect();
+        assert_eq!(order, vec![
+            &MountSource::Directory(PathBuf::from("/mods")),
+            &MountSource::Directory(PathBuf::from("/patch")),
+            &MountSource::Directory(PathBuf::from("/patch_2")),
+            &MountSource::Directory(PathBuf::from("/base")),
+        ]);
+        assert_eq!(overlay.write_layer().unwrap().source(), &MountSource::Directory(PathBuf::from("/mods")));
+
+        overlay.add_write_layer(MountSource::Directory(PathBuf::from("/saves")), -1);
+        assert_eq!(overlay.layers().iter().filter(|layer| layer.is_writable()).count(), 1);
+        assert_eq!(overlay.write_layer().unwrap().priority(), -1);
+    }
+}
@@ -7,6 +7,9 @@
 
 use std::fs;
 use std::fmt;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use filesystem_error::{FileSystemError, FileSystemResult};
 
 // We need our own version of this structure because the one in
 // std annoyingly doesn't let you get data out of it.
@@ -17,6 +20,11 @@ pub struct OpenOptions {
     create: bool,
     append: bool,
     truncate: bool,
+    create_new: bool,
+    //The permissions of the created file, before the umask. Unix only.
+    mode: Option<u32>,
+    //Flags given to open(2), like O_NOFOLLOW. Unix only.
+    custom_flags: i32,
 }
 
 impl AsRef<OpenOptions> for OpenOptions {
@@ -27,24 +35,33 @@ impl AsRef<OpenOptions> for OpenOptions {
 
 impl fmt::Display for OpenOptions {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut rights = String::new();
+        let mut rights = Vec::new();
         if self.read {
-            rights.push_str("read, ");
+            rights.push(String::from("read"));
         }
         if self.write {
-            rights.push_str("write, ");
+            rights.push(String::from("write"));
         }
         if self.create {
-            rights.push_str("create, ");
+            rights.push(String::from("create"));
         }
         if self.append {
-            rights.push_str("append, ");
+            rights.push(String::from("append"));
         }
         if self.truncate {
-            rights.push_str("truncate");
+            rights.push(String::from("truncate"));
+        }
+        if self.create_new {
+            rights.push(String::from("create_new"));
+        }
+        if let Some(mode) = self.mode {
+            rights.push(format!("mode {:o}", mode));
+        }
+        if self.custom_flags != 0 {
+            rights.push(format!("custom flags {:#x}", self.custom_flags));
         }
 
-        write!(f, "[{}]", rights)
+        write!(f, "[{}]", rights.join(", "))
     }
 }
 
@@ -90,6 +107,89 @@ impl OpenOptions {
         self
     }
 
+    // Create the file, failing if it already exists.
+    // The check and the creation are a single step: only one of the processes racing to create the file succeeds.
+    pub fn set_create_new(&mut self, create_new: bool) -> &mut OpenOptions {
+        debug!("Setting the create_new option of the OpenOptions to {}", create_new);
+        self.create_new = create_new;
+        self
+    }
+
+    // The permissions of the file, if it is created (e.g. 0o600). The umask of the process still applies.
+    // Ignored outside of unix.
+    pub fn set_mode(&mut self, mode: u32) -> &mut OpenOptions {
+        debug!("Setting the mode of the OpenOptions to {:o}", mode);
+        self.mode = Some(mode);
+        self
+    }
+
+    // Flags passed to open(2), like libc::O_NOFOLLOW or libc::O_DIRECT.
+    // The access mode flags (O_RDONLY, O_WRONLY, O_RDWR) are ignored. Ignored outside of unix.
+    pub fn set_custom_flags(&mut self, flags: i32) -> &mut OpenOptions {
+        debug!("Setting the custom flags of the OpenOptions to {:#x}", flags);
+        self.custom_flags = flags;
+        self
+    }
+
+    pub fn read(&self) -> bool {
+        self.read
+    }
+
+    pub fn write(&self) -> bool {
+        self.write
+    }
+
+    pub fn create(&self) -> bool {
+        self.create
+    }
+
+    pub fn append(&self) -> bool {
+        self.append
+    }
+
+    pub fn truncate(&self) -> bool {
+        self.truncate
+    }
+
+    pub fn create_new(&self) -> bool {
+        self.create_new
+    }
+
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    pub fn custom_flags(&self) -> i32 {
+        self.custom_flags
+    }
+
+    // Check that the options make sense together, before opening anything.
+    pub fn validate(&self) -> FileSystemResult<()> {
+        let contradiction = if !self.read && !self.write && !self.append {
+            Some("the file must be opened to read, write or append")
+        } else if self.truncate && self.append {
+            Some("a file can't be truncated and appended to")
+        } else if self.truncate && !self.write {
+            Some("a file can't be truncated without being opened to write")
+        } else if (self.create || self.create_new) && !self.write && !self.append {
+            Some("a file can't be created without being opened to write or append")
+        } else if self.mode.is_some() && !self.create && !self.create_new {
+            Some("a mode only applies to the created files, create or create_new must be set")
+        } else if self.mode.is_some_and(|mode| mode > 0o7777) {
+            Some("the mode can only contain permission bits (0o7777 at most)")
+        } else {
+            None
+        };
+
+        match contradiction {
+            Some(description) => {
+                error!("Invalid open options {}: {} !", self, description);
+                Err(FileSystemError::OpenOptionsError(format!("{}: {}.", self, description)))
+            },
+            None => Ok(()),
+        }
+    }
+
     pub fn to_fs_openoptions(&self) -> fs::OpenOptions {
         debug!("Creating an fs::OpenOptions from this OpenOptions.");
         let mut opt = fs::OpenOptions::new();
@@ -98,7 +198,89 @@ impl OpenOptions {
             .create(self.create)
             .append(self.append)
             .truncate(self.truncate)
-            .create(self.create);
+            .create_new(self.create_new);
+        self.add_platform_options(&mut opt);
         opt
     }
+
+    #[cfg(unix)]
+    fn add_platform_options(&self, opt: &mut fs::OpenOptions) {
+        if let Some(mode) = self.mode {
+            opt.mode(mode);
+        }
+        opt.custom_flags(self.custom_flags);
+    }
+
+    #[cfg(not(unix))]
+    fn add_platform_options(&self, _opt: &mut fs::OpenOptions) {
+        if self.mode.is_some() || self.custom_flags != 0 {
+            warn!("The mode and the custom flags of the open options {} are ignored on this platform.", self);
+        }
+    }
+}
+
+#[cfg(test)]
+mod open_options_test {
+    use super::*;
+    use std::io::{ErrorKind, Write};
+    use tempfile;
+
+    #[test]
+    fn open_options_validate() {
+        assert!(OpenOptions::new().set_read(true).validate().is_ok());
+        assert!(OpenOptions::new().set_write(true).set_create(true).set_truncate(true).validate().is_ok());
+        assert!(OpenOptions::new().set_append(true).set_create_new(true).set_mode(0o600).validate().is_ok());
+
+        assert!(OpenOptions::new().validate().is_err());
+        assert!(OpenOptions::new().set_read(true).set_create(true).validate().is_err());
+        assert!(OpenOptions::new().set_read(true).set_mode(0o600).validate().is_err());
+        assert!(OpenOptions::new().set_write(true).set_create(true).set_mode(0o100644).validate().is_err());
+        match OpenOptions::new().set_write(true).set_append(true).set_truncate(true).validate() {
+            Err(FileSystemError::OpenOptionsError(ref description)) => {
+                assert_eq!(description, "[write, append, truncate]: a file can't be truncated and appended to.")
+            },
+            result => panic!("Truncate and append should be rejected: {:?}", result),
+        }
+        assert_eq!(
+            OpenOptions::new().set_write(true).set_create_new(true).set_mode(0o640).set_custom_flags(0x20000).to_string(),
+            "[write, create_new, mode 640, custom flags 0x20000]"
+        );
+    }
+
+    #[test]
+    fn open_options_create_new() {
+        let temp = tempfile::tempdir().unwrap();
+        let lock = temp.path().join("game.lock");
+        let mut options = OpenOptions::new();
+        options.set_write(true).set_create_new(true);
+        options.to_fs_openoptions().open(lock.as_path()).unwrap().write_all(b"1").unwrap();
+        let error = options.to_fs_openoptions().open(lock.as_path()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn open_options_unix() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+        use libc;
+
+        let temp = tempfile::tempdir().unwrap();
+        let save = temp.path().join("slot_1.sav");
+        OpenOptions::new()
+            .set_write(true)
+            .set_create_new(true)
+            .set_mode(0o600)
+            .to_fs_openoptions()
+            .open(save.as_path())
+            .unwrap();
+        assert_eq!(fs::metadata(save.as_path()).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let link = temp.path().join("link.sav");
+        symlink(save.as_path(), link.as_path()).unwrap();
+        let mut options = OpenOptions::new();
+        options.set_read(true);
+        assert!(options.to_fs_openoptions().open(link.as_path()).is_ok());
+        options.set_custom_flags(libc::O_NOFOLLOW);
+        assert!(options.to_fs_openoptions().open(link.as_path()).is_err());
+    }
 }
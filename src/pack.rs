@@ -0,0 +1,883 @@
+// Copyright 2017-2018 Maskerad Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use crc32fast;
+use filesystem_error::{FileSystemError, FileSystemResult, Operation};
+use file_reader::{FileReader, FileSlice};
+use backend::{Backend, ReadSeek};
+use dir_entry::DirEntry;
+use metadata::Metadata;
+use atomic_writer::AtomicWriter;
+use mount::{archive_parent, to_archive_path};
+
+/*MASKERAD PACK FILES.
+
+The native archive format of the engine. All the integers are little endian.
+
+HEADER (64 bytes)
+- magic: b"MKPK"
+- version: u32
+- entry count: u32
+- padding: u32
+- table of contents offset: u64
+- table of contents size: u64
+- reserved: 32 bytes
+
+ENTRIES
+Every entry starts on a 4096 bytes boundary, so uncompressed entries can be memory mapped.
+
+TABLE OF CONTENTS
+One record per entry, sorted by path hash then by path:
+- path hash: u64 (FNV-1a 64 of the '/' separated path)
+- offset: u64
+- stored size: u64 (size in the pack, after compression and encryption)
+- size: u64 (size of the original content)
+- crc32: u32 (of the original content)
+- flags: u32 (PACK_COMPRESSED, PACK_ENCRYPTED)
+- path length: u16
+- path: UTF-8 bytes
+
+Compressed entries are deflated. The pack only records that an entry is encrypted, the
+cipher itself is provided by the game through the PackCipher trait. Entries are compressed
+before being encrypted.
+
+The offsets and sizes of the header and of the table of contents are checked against the size
+of the pack when it is opened: a corrupted pack is rejected before anything is allocated.
+Every entry is checked against its CRC-32 when opened, like the entries of the ZIP archives: the
+plain entries are read once to be checked, then streamed from the pack.
+
+A pack is written atomically: a failed build leaves the previous pack untouched.
+*/
+
+pub const PACK_MAGIC: &[u8; 4] = b"MKPK";
+pub const PACK_VERSION: u32 = 1;
+pub const PACK_ALIGNMENT: u64 = 4096;
+
+pub const PACK_COMPRESSED: u32 = 0x1;
+pub const PACK_ENCRYPTED: u32 = 0x2;
+
+const HEADER_SIZE: usize = 64;
+const TOC_RECORD_SIZE: usize = 42;
+
+//Deflate can't compress data more than ~1032 times, a bigger size is corrupted.
+const MAX_DEFLATE_RATIO: u64 = 1032;
+
+//Encryption of the pack entries, provided by the game.
+pub trait PackCipher: Send + Sync {
+    fn encrypt(&self, path: &str, data: Vec<u8>) -> Vec<u8>;
+    fn decrypt(&self, path: &str, data: Vec<u8>) -> Vec<u8>;
+}
+
+//The FNV-1a 64 bits hash of a pack path.
+pub fn pack_path_hash(path: &str) -> u64 {
+    path.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+fn align(offset: u64) -> u64 {
+    offset.div_ceil(PACK_ALIGNMENT) * PACK_ALIGNMENT
+}
+
+fn pack_error<P: AsRef<Path>>(path: P, description: &str) -> FileSystemError {
+    error!("Pack error with {}: {} !", path.as_ref().display(), description);
+    FileSystemError::ArchiveError(format!("{}: {}.", path.as_ref().display(), description))
+}
+
+fn compare_entries(first: (u64, &str), second: (u64, &str)) -> Ordering {
+    first.0.cmp(&second.0).then_with(|| first.1.cmp(second.1))
+}
+
+//An entry of the table of contents.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackEntry {
+    path: String,
+    hash: u64,
+    offset: u64,
+    stored_size: u64,
+    size: u64,
+    crc32: u32,
+    flags: u32,
+}
+
+impl PackEntry {
+    pub fn path(&self) -> &str {
+        self.path.as_str()
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn stored_size(&self) -> u64 {
+        self.stored_size
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn crc32(&self) -> u32 {
+        self.crc32
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.flags & PACK_COMPRESSED != 0
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & PACK_ENCRYPTED != 0
+    }
+}
+
+#[derive(Debug, Default)]
+struct PackIndex {
+    //Sorted by hash, then by path.
+    entries: Vec<PackEntry>,
+    //Every directory implied by the entries, the root being "".
+    directories: HashSet<String>,
+}
+
+//A pack opened for reading.
+#[derive(Clone)]
+pub struct PackArchive {
+    path: PathBuf,
+    index: Arc<PackIndex>,
+    cipher: Option<Arc<dyn PackCipher>>,
+}
+
+impl fmt::Debug for PackArchive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PackArchive")
+            .field("path", &self.path)
+            .field("entries", &self.index.entries.len())
+            .field("cipher", &self.cipher.is_some())
+            .finish()
+    }
+}
+
+impl PartialEq for PackArchive {
+    fn eq(&self, other: &PackArchive) -> bool {
+        self.path == other.path
+    }
+}
+
+impl PackArchive {
+    // Open the pack at path and read its table of contents
+    pub fn open<P: AsRef<Path>>(path: P) -> FileSystemResult<PackArchive> {
+        PackArchive::open_with(path, None)
+    }
+
+    // Open the pack at path, with the cipher used to decrypt its encrypted entries
+    pub fn open_with_cipher<P: AsRef<Path>>(path: P, cipher: Arc<dyn PackCipher>) -> FileSystemResult<PackArchive> {
+        PackArchive::open_with(path, Some(cipher))
+    }
+
+    fn open_with<P: AsRef<Path>>(path: P, cipher: Option<Arc<dyn PackCipher>>) -> FileSystemResult<PackArchive> {
+        debug!("Opening the pack {}", path.as_ref().display());
+        let mut file = File::open(path.as_ref()).map_err(|error| FileSystemError::io(Operation::Open, &[path.as_ref()], error))?;
+        let pack_size = file.metadata()
+            .map_err(|error| FileSystemError::io(Operation::Metadata, &[path.as_ref()], error))?
+            .len();
+        let mut header = [0; HEADER_SIZE];
+        file.read_exact(&mut header).map_err(|_| pack_error(path.as_ref(), "the header is truncated"))?;
+        if &header[0..4] != PACK_MAGIC {
+            return Err(pack_error(path.as_ref(), "this is not a Maskerad pack"));
+        }
+        let version = read_u32(&header, 4);
+        if version != PACK_VERSION {
+            return Err(pack_error(path.as_ref(), &format!("the version {} of the format is not supported", version)));
+        }
+        let entry_count = read_u32(&header, 8) as usize;
+        let toc_offset = read_u64(&header, 16);
+        let toc_size = read_u64(&header, 24);
+
+        if toc_offset.checked_add(toc_size).is_none_or(|end| end > pack_size) {
+            return Err(pack_error(path.as_ref(), "the table of contents is outside of the pack"));
+        }
+
+        trace!("Reading the {} entries of the table of contents.", entry_count);
+        let mut toc = vec![0; toc_size as usize];
+        file.seek(SeekFrom::Start(toc_offset))?;
+        file.read_exact(toc.as_mut_slice()).map_err(|_| pack_error(path.as_ref(), "the table of contents is truncated"))?;
+
+        let mut index = PackIndex::default();
+        index.directories.insert(String::new());
+        let mut offset = 0;
+        for _ in 0..entry_count {
+            if offset + TOC_RECORD_SIZE > toc.len() {
+                return Err(pack_error(path.as_ref(), "the table of contents is corrupted"));
+            }
+            let record = &toc[offset..];
+            let path_length = read_u16(record, 40) as usize;
+            if TOC_RECORD_SIZE + path_length > record.len() {
+                return Err(pack_error(path.as_ref(), "the table of contents is corrupted"));
+            }
+            let entry_path = String::from_utf8(record[TOC_RECORD_SIZE..TOC_RECORD_SIZE + path_length].to_vec())
+                .map_err(|_| pack_error(path.as_ref(), "an entry path is not valid UTF-8"))?;
+            offset += TOC_RECORD_SIZE + path_length;
+
+            let entry = PackEntry {
+                hash: read_u64(record, 0),
+                offset: read_u64(record, 8),
+                stored_size: read_u64(record, 16),
+                size: read_u64(record, 24),
+                crc32: read_u32(record, 32),
+                flags: read_u32(record, 36),
+                path: entry_path,
+            };
+            PackArchive::check_entry(path.as_ref(), &entry, pack_size)?;
+            let mut parent = archive_parent(entry.path());
+            while index.directories.insert(parent.to_string()) {
+                parent = archive_parent(parent);
+            }
+            index.entries.push(entry);
+        }
+
+        let sorted = index.entries.windows(2).all(|pair| {
+            compare_entries((pair[0].hash, pair[0].path()), (pair[1].hash, pair[1].path())) == Ordering::Less
+        });
+        if !sorted {
+            return Err(pack_error(path.as_ref(), "the table of contents is not sorted"));
+        }
+
+        debug!("The pack {} contains {} files.", path.as_ref().display(), index.entries.len());
+        Ok(PackArchive {
+            path: path.as_ref().to_path_buf(),
+            index: Arc::new(index),
+            cipher,
+        })
+    }
+
+    //Check a record of the table of contents, before its sizes and offset are used to read the entry.
+    fn check_entry(path: &Path, entry: &PackEntry, pack_size: u64) -> FileSystemResult<()> {
+        if entry.path.is_empty() {
+            return Err(pack_error(path, "an entry path is empty"));
+        }
+        if entry.hash != pack_path_hash(entry.path()) {
+            return Err(pack_error(path, &format!("the hash of {} doesn't match its path", entry.path())));
+        }
+        if entry.offset.checked_add(entry.stored_size).is_none_or(|end| end > pack_size) {
+            return Err(pack_error(path, &format!("the entry {} is outside of the pack", entry.path())));
+        }
+        let plain = !entry.is_compressed() && !entry.is_encrypted();
+        if plain && entry.stored_size != entry.size {
+            return Err(pack_error(path, &format!("the sizes of the entry {} don't match", entry.path())));
+        }
+        if entry.is_compressed() && !entry.is_encrypted() && entry.size > entry.stored_size.saturating_mul(MAX_DEFLATE_RATIO) {
+            return Err(pack_error(path, &format!("the size of the entry {} is corrupted", entry.path())));
+        }
+        Ok(())
+    }
+
+    // The path of the pack on the disk
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    // The entries of the table of contents
+    pub fn entries(&self) -> &[PackEntry] {
+        self.index.entries.as_slice()
+    }
+
+    // Find the entry of the file at path, relative to the root of the pack
+    pub fn entry<P: AsRef<Path>>(&self, path: P) -> Option<&PackEntry> {
+        let name = to_archive_path(path)?;
+        let hash = pack_path_hash(name.as_str());
+        self.index.entries
+            .binary_search_by(|entry| compare_entries((entry.hash, entry.path()), (hash, name.as_str())))
+            .ok()
+            .map(|index| &self.index.entries[index])
+    }
+
+    pub fn exists<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.is_file(path.as_ref()) || self.is_dir(path.as_ref())
+    }
+
+    pub fn is_file<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.entry(path).is_some()
+    }
+
+    pub fn is_dir<P: AsRef<Path>>(&self, path: P) -> bool {
+        match to_archive_path(path) {
+            Some(name) => self.index.directories.contains(name.as_str()),
+            None => false,
+        }
+    }
+
+    // Open the file at path, relative to the root of the pack
+    pub fn open_file<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<FileReader> {
+        debug!("Opening {} in the pack {}", path.as_ref().display(), self.path.display());
+        let entry = self.entry(path.as_ref())
+            .ok_or_else(|| pack_error(self.path.as_path(), &format!("{} could not be found in the pack", path.as_ref().display())))?;
+
+        let file = File::open(self.path.as_path())
+            .map_err(|error| FileSystemError::io(Operation::Open, &[self.path.as_path()], error))?;
+        if entry.flags & (PACK_COMPRESSED | PACK_ENCRYPTED) == 0 {
+            trace!("{} is stored as is, reading it from the pack.", entry.path());
+            let mut slice = FileSlice::new(file, entry.offset, entry.size)?;
+            if slice.crc32()? != entry.crc32 {
+                return Err(pack_error(self.path.as_path(), &format!("the content of {} is corrupted", entry.path())));
+            }
+            return Ok(FileReader::Slice(slice));
+        }
+
+        let content = self.read_entry_from(file, entry)?;
+        Ok(FileReader::Memory(Cursor::new(content)))
+    }
+
+    // Read the whole content of an entry, and check it against its CRC-32
+    pub fn read_entry(&self, entry: &PackEntry) -> FileSystemResult<Vec<u8>> {
+        trace!("Reading the entry {} of the pack {}", entry.path(), self.path.display());
+        let file = File::open(self.path.as_path())
+            .map_err(|error| FileSystemError::io(Operation::Open, &[self.path.as_path()], error))?;
+        self.read_entry_from(file, entry)
+    }
+
+    //Read, decrypt and decompress the content of the entry, and check its CRC-32.
+    fn read_entry_from(&self, file: File, entry: &PackEntry) -> FileSystemResult<Vec<u8>> {
+        let mut content = Vec::with_capacity(entry.stored_size as usize);
+        FileSlice::new(file, entry.offset, entry.stored_size)?.read_to_end(&mut content)?;
+
+        if entry.is_encrypted() {
+            trace!("Decrypting {}.", entry.path());
+            content = match self.cipher {
+                Some(ref cipher) => cipher.decrypt(entry.path(), content),
+                None => return Err(pack_error(self.path.as_path(), &format!("{} is encrypted, but no cipher was given", entry.path()))),
+            };
+        }
+
+        if entry.is_compressed() {
+            trace!("Decompressing {}.", entry.path());
+            let mut decompressed = Vec::new();
+            usize::try_from(entry.size)
+                .ok()
+                .and_then(|size| decompressed.try_reserve_exact(size).ok())
+                .ok_or_else(|| pack_error(self.path.as_path(), &format!("{} is too big to be loaded in memory", entry.path())))?;
+            DeflateDecoder::new(content.as_slice()).take(entry.size).read_to_end(&mut decompressed)?;
+            content = decompressed;
+        }
+
+        if content.len() as u64 != entry.size || crc32fast::hash(content.as_slice()) != entry.crc32 {
+            return Err(pack_error(self.path.as_path(), &format!("the content of {} is corrupted", entry.path())));
+        }
+        Ok(content)
+    }
+
+    // List the files and directories in the directory at path, relative to the root of the pack.
+    // Returns the file names, and whether they are directories.
+    pub fn read_dir<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<Vec<(OsString, bool)>> {
+        debug!("Listing {} in the pack {}", path.as_ref().display(), self.path.display());
+        let directory = match to_archive_path(path.as_ref()) {
+            Some(ref name) if self.index.directories.contains(name.as_str()) => name.clone(),
+            _ => return Err(pack_error(self.path.as_path(), &format!("{} is not a directory of the pack", path.as_ref().display()))),
+        };
+
+        let file_name = |name: &str| OsString::from(&name[name.rfind('/').map(|index| index + 1).unwrap_or(0)..]);
+        let mut entries: Vec<(OsString, bool)> = self.index.directories
+            .iter()
+            .filter(|name| !name.is_empty() && archive_parent(name.as_str()) == directory.as_str())
+            .map(|name| (file_name(name.as_str()), true))
+            .collect();
+        entries.extend(self.index.entries
+            .iter()
+            .filter(|entry| archive_parent(entry.path()) == directory.as_str())
+            .map(|entry| (file_name(entry.path()), false)));
+        Ok(entries)
+    }
+}
+
+impl Backend for PackArchive {
+    fn display_path(&self, path: &Path) -> PathBuf {
+        self.path.join(path)
+    }
+
+    fn absolute_path(&self, path: &Path) -> FileSystemResult<PathBuf> {
+        fs::canonicalize(self.path.as_path())
+            .map(|archive| archive.join(path))
+            .map_err(|error| FileSystemError::io(Operation::Canonicalize, &[self.path.as_path()], error))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        PackArchive::exists(self, path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        PackArchive::is_dir(self, path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        PackArchive::is_file(self, path)
+    }
+
+    fn metadata(&self, path: &Path) -> FileSystemResult<Metadata> {
+        match self.entry(path) {
+            Some(entry) => Ok(Metadata::new(entry.size(), false, None, true)),
+            None if PackArchive::is_dir(self, path) => Ok(Metadata::new(0, true, None, true)),
+            None => Err(pack_error(self.path.as_path(), &format!("{} could not be found in the pack", path.display()))),
+        }
+    }
+
+    fn open(&self, path: &Path) -> FileSystemResult<Box<dyn ReadSeek>> {
+        match self.open_file(path) {
+            Ok(reader) => Ok(Box::new(reader)),
+            Err(error) => Err(error.with_operation(Operation::Open).with_path(self.path.as_path())),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> FileSystemResult<Vec<DirEntry>> {
+        Ok(PackArchive::read_dir(self, path)?
+            .into_iter()
+            .map(|(file_name, is_dir)| DirEntry::new(path, file_name, is_dir))
+            .collect())
+    }
+}
+
+#[derive(Debug)]
+enum PendingContent {
+    Memory(Vec<u8>),
+    File(PathBuf),
+}
+
+#[derive(Debug)]
+struct PendingEntry {
+    path: String,
+    content: PendingContent,
+    flags: u32,
+}
+
+//Build a pack from files and directories.
+#[derive(Default)]
+pub struct PackWriter {
+    entries: Vec<PendingEntry>,
+    cipher: Option<Arc<dyn PackCipher>>,
+}
+
+impl fmt::Debug for PackWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PackWriter")
+            .field("entries", &self.entries)
+            .field("cipher", &self.cipher.is_some())
+            .finish()
+    }
+}
+
+impl PackWriter {
+    // Create a new writer, without any entry
+    pub fn new() -> PackWriter {
+        debug!("Creating a PackWriter.");
+        Default::default()
+    }
+
+    // The cipher used to encrypt the entries with the PACK_ENCRYPTED flag
+    pub fn set_cipher(&mut self, cipher: Arc<dyn PackCipher>) -> &mut PackWriter {
+        debug!("Setting the cipher of the PackWriter.");
+        self.cipher = Some(cipher);
+        self
+    }
+
+    // Add a file to the pack, at the given path in the pack, from some content in memory
+    pub fn add_bytes(&mut self, pack_path: &str, content: Vec<u8>, flags: u32) -> FileSystemResult<&mut PackWriter> {
+        self.add_entry(pack_path, PendingContent::Memory(content), flags)
+    }
+
+    // Add a file of the disk to the pack, at the given path in the pack
+    pub fn add_file<P: AsRef<Path>>(&mut self, pack_path: &str, file: P, flags: u32) -> FileSystemResult<&mut PackWriter> {
+        self.add_entry(pack_path, PendingContent::File(file.as_ref().to_path_buf()), flags)
+    }
+
+    // Add all the files of a directory tree to the root of the pack.
+    // The symbolic links to directories are skipped, they could lead outside of the tree or loop.
+    pub fn add_directory<P: AsRef<Path>>(&mut self, directory: P, flags: u32) -> FileSystemResult<&mut PackWriter> {
+        debug!("Adding the content of the directory {} to the pack.", directory.as_ref().display());
+        let mut directories = vec![directory.as_ref().to_path_buf()];
+        while let Some(current) = directories.pop() {
+            for entry in fs::read_dir(current.as_path())? {
+                let entry = entry?;
+                let path = entry.path();
+                let file_type = entry.file_type()?;
+                if file_type.is_symlink() && path.is_dir() {
+                    warn!("Skipping {}, it is a symbolic link to a directory.", path.display());
+                    continue;
+                }
+                if file_type.is_dir() {
+                    directories.push(path);
+                    continue;
+                }
+
+                let pack_path = path.strip_prefix(directory.as_ref())
+                    .ok()
+                    .and_then(to_archive_path)
+                    .ok_or_else(|| pack_error(path.as_path(), "the path can't be stored in a pack"))?;
+                self.add_entry(pack_path.as_str(), PendingContent::File(path.clone()), flags)?;
+            }
+        }
+        Ok(self)
+    }
+
+    fn add_entry(&mut self, pack_path: &str, content: PendingContent, flags: u32) -> FileSystemResult<&mut PackWriter> {
+        trace!("Adding {} to the pack, with the flags {:#x}.", pack_path, flags);
+        let path = match to_archive_path(pack_path) {
+            Some(ref path) if !path.is_empty() && path.len() <= usize::from(u16::MAX) => path.clone(),
+            _ => return Err(pack_error(pack_path, "invalid path in the pack")),
+        };
+        if self.entries.iter().any(|entry| entry.path == path) {
+            return Err(pack_error(pack_path, "this path has already been added to the pack"));
+        }
+        if flags & PACK_ENCRYPTED != 0 && self.cipher.is_none() {
+            return Err(pack_error(pack_path, "the entry must be encrypted, but no cipher was given"));
+        }
+
+        self.entries.push(PendingEntry {
+            path,
+            content,
+            flags,
+        });
+        Ok(self)
+    }
+
+    // Write the pack at path
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<()> {
+        debug!("Writing a pack of {} entries at {}", self.entries.len(), path.as_ref().display());
+        let mut pending: Vec<(u64, &PendingEntry)> = self.entries
+            .iter()
+            .map(|entry| (pack_path_hash(entry.path.as_str()), entry))
+            .collect();
+        pending.sort_by(|first, second| compare_entries((first.0, first.1.path.as_str()), (second.0, second.1.path.as_str())));
+
+        //A failed build leaves the previous pack untouched, instead of a truncated one.
+        let mut file = AtomicWriter::new(path.as_ref())?;
+        let mut offset = 0;
+        let mut toc = Vec::new();
+        for (hash, entry) in pending {
+            let content = match entry.content {
+                PendingContent::Memory(ref content) => content.clone(),
+                PendingContent::File(ref path) => fs::read(path)?,
+            };
+            let size = content.len() as u64;
+            let crc32 = crc32fast::hash(content.as_slice());
+            let mut flags = entry.flags;
+            let mut stored = content;
+
+            if flags & PACK_COMPRESSED != 0 {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+                encoder.write_all(stored.as_slice())?;
+                let compressed = encoder.finish()?;
+                if compressed.len() < stored.len() {
+                    stored = compressed;
+                } else {
+                    trace!("{} doesn't benefit from the compression, storing it as is.", entry.path);
+                    flags &= !PACK_COMPRESSED;
+                }
+            }
+            if flags & PACK_ENCRYPTED != 0 {
+                if let Some(ref cipher) = self.cipher {
+                    stored = cipher.encrypt(entry.path.as_str(), stored);
+                }
+            }
+
+            let entry_offset = align(offset.max(HEADER_SIZE as u64));
+            write_padding(&mut file, entry_offset - offset)?;
+            file.write_all(stored.as_slice())?;
+            offset = entry_offset + stored.len() as u64;
+
+            push_u64(&mut toc, hash);
+            push_u64(&mut toc, entry_offset);
+            push_u64(&mut toc, stored.len() as u64);
+            push_u64(&mut toc, size);
+            push_u32(&mut toc, crc32);
+            push_u32(&mut toc, flags);
+            push_u16(&mut toc, entry.path.len() as u16);
+            toc.extend_from_slice(entry.path.as_bytes());
+        }
+
+        if offset < HEADER_SIZE as u64 {
+            write_padding(&mut file, HEADER_SIZE as u64 - offset)?;
+            offset = HEADER_SIZE as u64;
+        }
+        file.write_all(toc.as_slice())?;
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(PACK_MAGIC);
+        push_u32(&mut header, PACK_VERSION);
+        push_u32(&mut header, self.entries.len() as u32);
+        push_u32(&mut header, 0);
+        push_u64(&mut header, offset);
+        push_u64(&mut header, toc.len() as u64);
+        header.resize(HEADER_SIZE, 0);
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(header.as_slice())?;
+        file.commit()
+    }
+}
+
+fn write_padding<W: Write>(writer: &mut W, size: u64) -> FileSystemResult<()> {
+    const ZEROES: [u8; 512] = [0; 512];
+    let mut remaining = size;
+    while remaining > 0 {
+        let length = remaining.min(ZEROES.len() as u64) as usize;
+        writer.write_all(&ZEROES[..length])?;
+        remaining -= length as u64;
+    }
+    Ok(())
+}
+
+fn read_u16(buffer: &[u8], offset: usize) -> u16 {
+    u16::from(buffer[offset]) | u16::from(buffer[offset + 1]) << 8
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    u32::from(read_u16(buffer, offset)) | u32::from(read_u16(buffer, offset + 2)) << 16
+}
+
+fn read_u64(buffer: &[u8], offset: usize) -> u64 {
+    u64::from(read_u32(buffer, offset)) | u64::from(read_u32(buffer, offset + 4)) << 32
+}
+
+fn push_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&[value as u8, (value >> 8) as u8]);
+}
+
+fn push_u32(buffer: &mut Vec<u8>, value: u32) {
+    push_u16(buffer, value as u16);
+    push_u16(buffer, (value >> 16) as u16);
+}
+
+fn push_u64(buffer: &mut Vec<u8>, value: u64) {
+    push_u32(buffer, value as u32);
+    push_u32(buffer, (value >> 32) as u32);
+}
+
+#[cfg(test)]
+mod pack_test {
+    use super::*;
+    use tempfile;
+    use filesystem_error::ErrorKind;
+
+    //Not a real cipher, good enough to check that the data goes through it.
+    struct XorCipher(u8);
+
+    impl PackCipher for XorCipher {
+        fn encrypt(&self, _path: &str, data: Vec<u8>) -> Vec<u8> {
+            data.into_iter().map(|byte| byte ^ self.0).collect()
+        }
+
+        fn decrypt(&self, path: &str, data: Vec<u8>) -> Vec<u8> {
+            self.encrypt(path, data)
+        }
+    }
+
+    fn read(pack: &PackArchive, path: &str) -> Vec<u8> {
+        let mut content = Vec::new();
+        pack.open_file(path).unwrap().read_to_end(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn pack_write_and_read() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        fs::create_dir_all(source.join("textures/characters")).unwrap();
+        fs::write(source.join("textures/characters/hero.png"), "hero").unwrap();
+        fs::write(source.join("readme.txt"), "readme").unwrap();
+
+        let compressible = vec![b'a'; 10_000];
+        let pack_path = temp.path().join("data.mkp");
+        PackWriter::new()
+            .set_cipher(Arc::new(XorCipher(0x5A)))
+            .add_directory(source.as_path(), 0).unwrap()
+            .add_bytes("levels/one.lvl", compressible.clone(), PACK_COMPRESSED).unwrap()
+            .add_bytes("secret/key.txt", b"secret".to_vec(), PACK_COMPRESSED | PACK_ENCRYPTED).unwrap()
+            .write(pack_path.as_path()).unwrap();
+
+        let pack = PackArchive::open_with_cipher(pack_path.as_path(), Arc::new(XorCipher(0x5A))).unwrap();
+        assert_eq!(pack.entries().len(), 4);
+        assert!(pack.entries().iter().all(|entry| entry.offset() % PACK_ALIGNMENT == 0));
+        assert_eq!(read(&pack, "textures/characters/hero.png"), b"hero");
+        assert_eq!(read(&pack, "readme.txt"), b"readme");
+        assert_eq!(read(&pack, "levels/one.lvl"), compressible);
+        assert_eq!(read(&pack, "secret/key.txt"), b"secret");
+
+        let level = pack.entry("levels/one.lvl").unwrap();
+        assert!(level.is_compressed());
+        assert!(level.stored_size() < level.size());
+        //"secret" is too small to be compressed.
+        let secret = pack.entry("secret/key.txt").unwrap();
+        assert!(!secret.is_compressed());
+        assert!(secret.is_encrypted());
+
+        assert!(pack.is_dir("textures/characters"));
+        assert!(!pack.exists("textures/villain.png"));
+        let mut root = pack.read_dir("").unwrap();
+        root.sort();
+        assert_eq!(root, vec![
+            (OsString::from("levels"), true),
+            (OsString::from("readme.txt"), false),
+            (OsString::from("secret"), true),
+            (OsString::from("textures"), true),
+        ]);
+
+        let without_cipher = PackArchive::open(pack_path.as_path()).unwrap();
+        assert!(without_cipher.open_file("secret/key.txt").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pack_add_directory_skips_directory_links() {
+        use std::os::unix::fs::symlink;
+
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        fs::create_dir_all(source.join("levels")).unwrap();
+        fs::write(source.join("levels/one.lvl"), "level").unwrap();
+        symlink(source.as_path(), source.join("levels/loop")).unwrap();
+        symlink(source.join("levels/one.lvl"), source.join("levels/alias.lvl")).unwrap();
+
+        let pack_path = temp.path().join("data.mkp");
+        PackWriter::new()
+            .add_directory(source.as_path(), 0).unwrap()
+            .write(pack_path.as_path()).unwrap();
+        let pack = PackArchive::open(pack_path.as_path()).unwrap();
+        let mut paths: Vec<&str> = pack.entries().iter().map(PackEntry::path).collect();
+        paths.sort();
+        assert_eq!(paths, vec!["levels/alias.lvl", "levels/one.lvl"]);
+    }
+
+    #[test]
+    fn pack_detect_corruption() {
+        let temp = tempfile::tempdir().unwrap();
+        let pack_path = temp.path().join("data.mkp");
+        PackWriter::new()
+            .add_bytes("stored.txt", b"stored".to_vec(), 0).unwrap()
+            .add_bytes("compressed.txt", vec![b'c'; 1000], PACK_COMPRESSED).unwrap()
+            .write(pack_path.as_path()).unwrap();
+
+        let pack = PackArchive::open(pack_path.as_path()).unwrap();
+        assert!(pack.entries().iter().all(|entry| pack.read_entry(entry).is_ok()));
+
+        let stored_offset = pack.entry("stored.txt").unwrap().offset() as usize;
+        let mut bytes = fs::read(pack_path.as_path()).unwrap();
+        bytes[stored_offset] ^= 0xFF;
+        fs::write(pack_path.as_path(), bytes).unwrap();
+
+        let pack = PackArchive::open(pack_path.as_path()).unwrap();
+        assert!(pack.read_entry(pack.entry("stored.txt").unwrap()).is_err());
+        assert!(pack.open_file("stored.txt").is_err());
+        assert!(pack.read_entry(pack.entry("compressed.txt").unwrap()).is_ok());
+
+        //A failed build leaves the previous pack untouched.
+        let previous = fs::read(pack_path.as_path()).unwrap();
+        assert!(PackWriter::new()
+            .add_bytes("stored.txt", b"new".to_vec(), 0).unwrap()
+            .add_file("missing.txt", temp.path().join("missing.txt"), 0).unwrap()
+            .write(pack_path.as_path())
+            .is_err());
+        assert_eq!(fs::read(pack_path.as_path()).unwrap(), previous);
+        assert_eq!(fs::read_dir(temp.path()).unwrap().count(), 1);
+    }
+
+    //Write a pack with a single stored entry, and patch the little endian value at offset.
+    fn corrupted_pack(path: &Path, offset: usize, value: u64) {
+        PackWriter::new()
+            .add_bytes("levels/one.lvl", b"level".to_vec(), 0).unwrap()
+            .write(path).unwrap();
+        let mut bytes = fs::read(path).unwrap();
+        bytes[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+        fs::write(path, bytes).unwrap();
+    }
+
+    fn assert_archive_error(result: FileSystemResult<PackArchive>) {
+        match result {
+            Err(FileSystemError::ArchiveError(_)) => {},
+            result => panic!("The corrupted pack should be rejected: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn pack_corrupted_table_of_contents() {
+        let temp = tempfile::tempdir().unwrap();
+        let pack_path = temp.path().join("corrupted.mkp");
+        //The only entry is at 4096, and the table of contents follows it.
+        let toc_offset = PACK_ALIGNMENT as usize + 5;
+
+        //The size of the table of contents, in the header.
+        corrupted_pack(pack_path.as_path(), 24, 0xFFFF_FFFF_FFFF);
+        assert_archive_error(PackArchive::open(pack_path.as_path()));
+        //The stored size of the entry.
+        corrupted_pack(pack_path.as_path(), toc_offset + 16, 0xFFFF_FFFF_FFFF);
+        assert_archive_error(PackArchive::open(pack_path.as_path()));
+        //Its offset, overflowing once added to the stored size.
+        corrupted_pack(pack_path.as_path(), toc_offset + 8, u64::MAX);
+        assert_archive_error(PackArchive::open(pack_path.as_path()));
+        //Its size, different from the stored size of an uncompressed entry.
+        corrupted_pack(pack_path.as_path(), toc_offset + 24, 0xFFFF_FFFF_FFFF);
+        assert_archive_error(PackArchive::open(pack_path.as_path()));
+        //Its path hash.
+        corrupted_pack(pack_path.as_path(), toc_offset, 42);
+        assert_archive_error(PackArchive::open(pack_path.as_path()));
+
+        //A truncated pack.
+        PackWriter::new()
+            .add_bytes("levels/one.lvl", b"level".to_vec(), 0).unwrap()
+            .write(pack_path.as_path()).unwrap();
+        let bytes = fs::read(pack_path.as_path()).unwrap();
+        fs::write(pack_path.as_path(), &bytes[..toc_offset + 20]).unwrap();
+        assert_archive_error(PackArchive::open(pack_path.as_path()));
+        fs::write(pack_path.as_path(), &bytes[..PACK_ALIGNMENT as usize + 2]).unwrap();
+        assert_archive_error(PackArchive::open(pack_path.as_path()));
+
+        //An empty path, with the right hash.
+        let mut bytes = bytes.clone();
+        bytes[toc_offset..toc_offset + 8].copy_from_slice(&pack_path_hash("").to_le_bytes());
+        bytes[toc_offset + 40..toc_offset + 42].copy_from_slice(&[0, 0]);
+        bytes[24..32].copy_from_slice(&42u64.to_le_bytes());
+        fs::write(pack_path.as_path(), &bytes[..toc_offset + 42]).unwrap();
+        assert_archive_error(PackArchive::open(pack_path.as_path()));
+    }
+
+    #[test]
+    fn pack_invalid() {
+        let temp = tempfile::tempdir().unwrap();
+        let pack_path = temp.path().join("invalid.mkp");
+        fs::write(pack_path.as_path(), "this is not a pack").unwrap();
+        assert!(PackArchive::open(pack_path.as_path()).is_err());
+
+        let missing = temp.path().join("missing.mkp");
+        let error = PackArchive::open(missing.as_path()).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        assert_eq!(error.context().unwrap().operation(), Some(Operation::Open));
+        assert_eq!(error.context().unwrap().paths(), &[missing]);
+
+        let mut writer = PackWriter::new();
+        writer.add_bytes("file.txt", Vec::new(), 0).unwrap();
+        assert!(writer.add_bytes("./file.txt", Vec::new(), 0).is_err());
+        assert!(writer.add_bytes("../file.txt", Vec::new(), 0).is_err());
+        assert!(writer.add_bytes("encrypted.txt", Vec::new(), PACK_ENCRYPTED).is_err());
+
+        let empty_path = temp.path().join("empty.mkp");
+        PackWriter::new().write(empty_path.as_path()).unwrap();
+        let empty = PackArchive::open(empty_path.as_path()).unwrap();
+        assert!(empty.entries().is_empty());
+        assert!(empty.read_dir("").unwrap().is_empty());
+    }
+}
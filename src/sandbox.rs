@@ -0,0 +1,334 @@
+// Copyright 2017-2018 Maskerad Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::fs;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+use filesystem_error::{FileSystemError, FileSystemResult};
+use game_directories::RootDir;
+use filesystem::Filesystem;
+use backend::ReadSeek;
+use dir_entry::{DirEntry, ReadDir};
+use metadata::Metadata;
+
+/*SANDBOX.
+
+The paths given by the mods and their scripts can't be trusted: "../../.ssh/id_rsa" or
+"/etc/passwd" must not leave the directory the mod has been given.
+
+A sandbox is a root directory of a Filesystem, and only accepts paths relative to it. They are
+normalized lexically, the paths going above the root and the absolute paths are rejected, and the
+symbolic links leading outside of the root are rejected too. The paths of the entries listed by a
+sandbox are relative to its root, the real location of the root never leaks.
+
+The operations are the ones of the Filesystem: they go through the mount table, a sandbox of an
+in-memory Filesystem never touches the disk.
+*/
+
+//Normalize the relative path lexically ("a/./b/../c" becomes "a/c").
+//Absolute paths, and paths going above their starting point, are rejected.
+pub fn normalize_relative_path<P: AsRef<Path>>(path: P) -> FileSystemResult<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.as_ref().components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir => {},
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return Err(escape_error(path.as_ref(), "it goes above the root directory"));
+                }
+            },
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(escape_error(path.as_ref(), "it is an absolute path"));
+            },
+        }
+    }
+    Ok(normalized)
+}
+
+fn escape_error(path: &Path, reason: &str) -> FileSystemError {
+    error!("The path {} has been rejected by the sandbox: {} !", path.display(), reason);
+    FileSystemError::SandboxError(format!(
+        "The path {} has been rejected: {}.",
+        path.display(),
+        reason
+    ))
+}
+
+//The deepest part of the path existing on the disk must stay in the root once resolved.
+//It catches the symbolic links anywhere in the path, even a dangling one at its end.
+fn check_symlinks(path: &Path, root: &Path, full_path: &Path) -> FileSystemResult<()> {
+    let canonical_root = match root.canonicalize() {
+        Ok(canonical_root) => canonical_root,
+        //Nothing exists in the root, there isn't any link to follow.
+        Err(_) => return Ok(()),
+    };
+
+    for ancestor in full_path.ancestors() {
+        if ancestor == root || !ancestor.starts_with(root) {
+            break;
+        }
+        if fs::symlink_metadata(ancestor).is_err() {
+            continue;
+        }
+        return match ancestor.canonicalize() {
+            Ok(ref resolved) if resolved.starts_with(canonical_root.as_path()) => Ok(()),
+            Ok(_) => Err(escape_error(path, "a symbolic link leads outside of the root directory")),
+            Err(_) => Err(escape_error(path, "a symbolic link can't be resolved")),
+        };
+    }
+    Ok(())
+}
+
+//A root directory of a Filesystem, and the operations which can be done in it with untrusted paths.
+#[derive(Debug, Clone)]
+pub struct Sandbox<'a> {
+    filesystem: &'a Filesystem,
+    root_dir: RootDir,
+    root: PathBuf,
+}
+
+impl<'a> Sandbox<'a> {
+    //Restrict the operations to the root directory. See Filesystem::sandbox.
+    pub fn new(filesystem: &'a Filesystem, root_dir: RootDir) -> FileSystemResult<Self> {
+        Ok(Sandbox {
+            filesystem,
+            root_dir,
+            root: filesystem.path(root_dir)?,
+        })
+    }
+
+    pub fn root_dir(&self) -> RootDir {
+        self.root_dir
+    }
+
+    pub fn root(&self) -> &Path {
+        self.root.as_path()
+    }
+
+    fn error_context(&self, error: FileSystemError) -> FileSystemError {
+        error.with_root_dir(Some(self.root_dir))
+    }
+
+    //Check the relative path, and return its path in the Filesystem.
+    pub fn path<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<PathBuf> {
+        trace!("Checking the path {} in the sandbox {}", path.as_ref().display(), self.root.display());
+        let relative = normalize_relative_path(path.as_ref())?;
+        let full_path = self.root.join(relative);
+
+        //The links can only be found on the disk, in the physical locations of the root.
+        let roots = self.filesystem.physical_paths(self.root.as_path())?;
+        for physical_path in self.filesystem.physical_paths(full_path.as_path())? {
+            if let Some(root) = roots.iter().find(|root| physical_path.starts_with(root)) {
+                check_symlinks(path.as_ref(), root.as_path(), physical_path.as_path())?;
+            }
+        }
+        Ok(full_path)
+    }
+
+    //Same as path, but the root itself is refused.
+    fn entry_path(&self, path: &Path) -> FileSystemResult<PathBuf> {
+        let full_path = self.path(path)?;
+        if full_path == self.root {
+            return Err(escape_error(path, "it is the root directory itself"));
+        }
+        Ok(full_path)
+    }
+
+    pub fn exists<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<bool> {
+        Ok(self.filesystem.metadata(self.path(path)?).is_ok())
+    }
+
+    pub fn is_dir<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<bool> {
+        Ok(self.filesystem.metadata(self.path(path)?).map(|metadata| metadata.is_dir()).unwrap_or(false))
+    }
+
+    pub fn is_file<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<bool> {
+        Ok(self.filesystem.metadata(self.path(path)?).map(|metadata| metadata.is_file()).unwrap_or(false))
+    }
+
+    //Get the size, the type and the modification time of the file or directory at path.
+    pub fn metadata<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<Metadata> {
+        self.filesystem.metadata(self.path(path)?).map_err(|error| self.error_context(error))
+    }
+
+    //Open file at path to read
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<BufReader<Box<dyn ReadSeek>>> {
+        debug!("Opening file at path {} in the sandbox {}", path.as_ref().display(), self.root.display());
+        let path = self.entry_path(path.as_ref())?;
+        self.filesystem.open(path).map_err(|error| self.error_context(error))
+    }
+
+    //Open file at path for writing, truncates if file already exist
+    pub fn create<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<BufWriter<Box<dyn Write + Send>>> {
+        debug!("Creating/truncating file at path {} in the sandbox {}", path.as_ref().display(), self.root.display());
+        let path = self.entry_path(path.as_ref())?;
+        self.filesystem.create(path).map_err(|error| self.error_context(error))
+    }
+
+    //Open the file at path for appending, creating it if necessary
+    pub fn append<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<BufWriter<Box<dyn Write + Send>>> {
+        debug!("Appending/Creating file at path {} in the sandbox {}", path.as_ref().display(), self.root.display());
+        let path = self.entry_path(path.as_ref())?;
+        self.filesystem.append(path).map_err(|error| self.error_context(error))
+    }
+
+    //create directory at path
+    pub fn mkdir<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<()> {
+        debug!("Creating directory at path {} in the sandbox {}", path.as_ref().display(), self.root.display());
+        let path = self.path(path)?;
+        self.filesystem.mkdir(path).map_err(|error| self.error_context(error))
+    }
+
+    //remove a file, or an empty directory
+    pub fn rm<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<()> {
+        debug!("Removing file/empty dir at path {} in the sandbox {}", path.as_ref().display(), self.root.display());
+        let path = self.entry_path(path.as_ref())?;
+        self.filesystem.rm(path).map_err(|error| self.error_context(error))
+    }
+
+    //remove file or directory and all its contents
+    pub fn rmrf<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<()> {
+        debug!("Removing file/dir at path {} in the sandbox {}", path.as_ref().display(), self.root.display());
+        let path = self.entry_path(path.as_ref())?;
+        self.filesystem.rmrf(path).map_err(|error| self.error_context(error))
+    }
+
+    //Retrieve all file entries in the given directory, with paths relative to the root.
+    pub fn read_dir<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<ReadDir> {
+        debug!("Getting all entries in the directory at path {} in the sandbox {}", path.as_ref().display(), self.root.display());
+        let relative = normalize_relative_path(path.as_ref())?;
+        let entries = self.filesystem
+            .read_dir(self.path(path.as_ref())?)
+            .map_err(|error| self.error_context(error))?
+            .map(|entry| DirEntry::new(relative.as_path(), entry.file_name().to_os_string(), entry.is_dir()))
+            .collect();
+        Ok(ReadDir::new(entries))
+    }
+}
+
+#[cfg(test)]
+mod sandbox_test {
+    use super::*;
+    use std::io::Read;
+    use tempfile;
+    use game_directories::GameDirectories;
+    use filesystem_error::{ErrorKind, Operation};
+
+    //A Filesystem whose WorkingDirectory is root, keeping the user directories in home.
+    fn test_filesystem(home: &Path, root: &Path) -> Filesystem {
+        let directories = GameDirectories::builder("test_sandbox", "Malkaviel")
+            .base_directory(home)
+            .directory(RootDir::WorkingDirectory, root)
+            .build()
+            .expect("Couldn't create GameDirs");
+        Filesystem::from_directories(directories)
+    }
+
+    #[test]
+    fn sandbox_normalize_relative_path() {
+        assert_eq!(normalize_relative_path("scripts/./ai/../main.lua").unwrap(), PathBuf::from("scripts/main.lua"));
+        assert_eq!(normalize_relative_path("scripts/..").unwrap(), PathBuf::new());
+        assert_eq!(normalize_relative_path("").unwrap(), PathBuf::new());
+        assert!(normalize_relative_path("../../etc/passwd").is_err());
+        assert!(normalize_relative_path("scripts/../../outside").is_err());
+        assert!(normalize_relative_path("/etc/passwd").is_err());
+    }
+
+    #[test]
+    fn sandbox_operations() {
+        let temp = tempfile::tempdir().unwrap();
+        let filesystem = test_filesystem(temp.path().join("home").as_path(), temp.path().join("mod").as_path());
+        let sandbox = filesystem.sandbox(RootDir::WorkingDirectory).unwrap();
+        fs::write(temp.path().join("secret.txt"), b"secret").unwrap();
+
+        sandbox.mkdir("scripts/ai").unwrap();
+        sandbox.create("scripts/ai/../main.lua").unwrap().write_all(b"print()").unwrap();
+        sandbox.append("scripts/main.lua").unwrap().write_all(b"\n").unwrap();
+        let mut content = String::new();
+        sandbox.open("scripts/main.lua").unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "print()\n");
+        assert!(sandbox.is_file("./scripts/main.lua").unwrap());
+
+        let entries: Vec<PathBuf> = sandbox.read_dir("scripts").unwrap().map(|entry| entry.path().to_path_buf()).collect();
+        assert_eq!(entries, vec![PathBuf::from("scripts/ai"), PathBuf::from("scripts/main.lua")]);
+
+        for path in &["../secret.txt", "scripts/../../secret.txt", temp.path().join("secret.txt").to_str().unwrap()] {
+            match sandbox.open(path) {
+                Err(FileSystemError::SandboxError(_)) => {},
+                result => panic!("{} escaped the sandbox: {:?}", path, result.map(|_| ())),
+            }
+            assert!(sandbox.create(path).is_err());
+            assert!(sandbox.rmrf(path).is_err());
+        }
+        assert!(sandbox.rmrf("").is_err());
+        assert!(sandbox.rmrf("scripts/..").is_err());
+
+        let error = sandbox.open("scripts/missing.lua").err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        assert_eq!(error.context().unwrap().root_dir(), Some(RootDir::WorkingDirectory));
+
+        sandbox.rm("scripts/main.lua").unwrap();
+        sandbox.rmrf("scripts").unwrap();
+        assert!(!sandbox.exists("scripts").unwrap());
+        assert!(temp.path().join("secret.txt").exists());
+    }
+
+    #[test]
+    fn sandbox_in_memory() {
+        let filesystem = Filesystem::in_memory("test_sandbox", "Malkaviel").unwrap();
+        let sandbox = filesystem.sandbox(RootDir::WorkingDirectory).unwrap();
+        assert_eq!(sandbox.root_dir(), RootDir::WorkingDirectory);
+
+        sandbox.mkdir("scripts").unwrap();
+        sandbox.create("scripts/main.lua").unwrap().write_all(b"print()").unwrap();
+        assert_eq!(sandbox.metadata("scripts/main.lua").unwrap().len(), 7);
+        assert!(sandbox.is_dir("scripts").unwrap());
+        let entries: Vec<PathBuf> = sandbox.read_dir("scripts/ai/..").unwrap().map(|entry| entry.path().to_path_buf()).collect();
+        assert_eq!(entries, vec![PathBuf::from("scripts/main.lua")]);
+        assert!(sandbox.open("../main.lua").is_err());
+
+        //Nothing has been written to the disk.
+        assert!(!sandbox.root().exists());
+        assert!(filesystem.open(sandbox.root().join("scripts/main.lua")).is_ok());
+
+        let error = sandbox.read_dir("textures").err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        assert_eq!(error.context().unwrap().operation(), Some(Operation::ReadDir));
+        assert_eq!(error.context().unwrap().root_dir(), Some(RootDir::WorkingDirectory));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn sandbox_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().join("mod");
+        fs::create_dir_all(root.join("textures")).unwrap();
+        fs::write(temp.path().join("secret.txt"), b"secret").unwrap();
+        symlink(temp.path(), root.join("outside")).unwrap();
+        symlink(temp.path().join("missing.txt"), root.join("dangling.txt")).unwrap();
+        symlink(root.join("textures"), root.join("images")).unwrap();
+
+        let filesystem = test_filesystem(temp.path().join("home").as_path(), root.as_path());
+        let sandbox = filesystem.sandbox(RootDir::WorkingDirectory).unwrap();
+        assert!(sandbox.open("outside/secret.txt").is_err());
+        assert!(sandbox.create("outside/new.txt").is_err());
+        assert!(sandbox.create("dangling.txt").is_err());
+        assert!(!temp.path().join("missing.txt").exists());
+
+        //The links staying in the root are fine, and the links to directories are listed as directories.
+        sandbox.create("images/wall.png").unwrap();
+        assert!(root.join("textures/wall.png").is_file());
+        let directories: Vec<PathBuf> = sandbox.read_dir("").unwrap()
+            .filter(|entry| entry.is_dir())
+            .map(|entry| entry.path().to_path_buf())
+            .collect();
+        assert_eq!(directories, vec![PathBuf::from("images"), PathBuf::from("outside"), PathBuf::from("textures")]);
+    }
+}
@@ -0,0 +1,705 @@
+// Copyright 2017-2018 Maskerad Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::io::{self, BufRead, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use filesystem::Filesystem;
+use file_lock::{FileLock, LockMode};
+use filesystem_error::{ErrorKind, FileSystemError, FileSystemResult};
+use virtual_path::VirtualPath;
+use crc32fast;
+
+/*SAVE SLOTS.
+
+A save slot is a single file, "<slot name>.sav", in the save directory (RootDir::UserSaveRoot by default).
+The file starts with a small text header holding the metadata of the slot, followed by an empty line
+and the data of the game:
+
+maskerad-save 1
+timestamp=1514764800000
+playtime=3600000
+game_version=1.0.2
+thumbnail=thumbnails/slot_1.png
+checksum=8f3e2a1c
+
+<data>
+
+The timestamp is in milliseconds since the UNIX epoch, the playtime in milliseconds, and the thumbnail
+line is only present if the slot has one. The checksum is the CRC-32 of the data. A header missing a line
+or holding an unknown one is corrupted, like data not matching the checksum. Keeping everything
+in one file lets the slot be created, overwritten and deleted atomically: the metadata can't describe
+the data of another save.
+
+When a slot is overwritten, its previous versions are kept in the "backups" subdirectory:
+"backups/<slot name>.1.sav" is the newest, "backups/<slot name>.2.sav" the one before, and so on.
+If a slot is corrupted when it is loaded, the newest valid backup is loaded instead, and a slot whose header
+is corrupted is listed with the metadata of this backup. A corrupted save isn't kept as a backup: it would
+push a valid backup out. A missing slot, or an I/O error, isn't a corruption: no backup is loaded then.
+
+Two instances of the game saving the same slot would mix their backups. A slot on the disk is locked
+while it is saved, loaded or deleted, through "<slot name>.sav.lock" next to it (see FileLock): an exclusive
+lock to save and delete, a shared lock to load. The slots in memory aren't locked.
+*/
+
+pub const SAVE_EXTENSION: &str = "sav";
+pub const BACKUP_DIRECTORY: &str = "backups";
+pub const DEFAULT_BACKUP_COUNT: usize = 3;
+const SAVE_HEADER: &str = "maskerad-save 1";
+const LOCK_EXTENSION: &str = "lock";
+
+fn save_error(slot: &str, reason: &str) -> FileSystemError {
+    error!("Error with the save slot {}: {} !", slot, reason);
+    FileSystemError::SaveError(format!("{}: {}.", slot, reason))
+}
+
+//What the save menus display about a slot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaveSlotMetadata {
+    timestamp: SystemTime,
+    playtime: Duration,
+    thumbnail: Option<PathBuf>,
+    game_version: String,
+}
+
+impl SaveSlotMetadata {
+    //The metadata of a save made now, without playtime nor thumbnail.
+    pub fn new(game_version: &str) -> Self {
+        SaveSlotMetadata {
+            timestamp: SystemTime::now(),
+            playtime: Duration::from_secs(0),
+            thumbnail: None,
+            game_version: String::from(game_version),
+        }
+    }
+
+    //When the game has been saved. Stored with a precision of a millisecond.
+    pub fn set_timestamp(&mut self, timestamp: SystemTime) -> &mut Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    //How long the player has played. Stored with a precision of a millisecond.
+    pub fn set_playtime(&mut self, playtime: Duration) -> &mut Self {
+        self.playtime = playtime;
+        self
+    }
+
+    //The path of the screenshot displayed next to the slot. The file itself is managed by the game.
+    pub fn set_thumbnail<P: Into<PathBuf>>(&mut self, thumbnail: P) -> &mut Self {
+        self.thumbnail = Some(thumbnail.into());
+        self
+    }
+
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    pub fn playtime(&self) -> Duration {
+        self.playtime
+    }
+
+    pub fn thumbnail(&self) -> Option<&Path> {
+        self.thumbnail.as_deref()
+    }
+
+    pub fn game_version(&self) -> &str {
+        self.game_version.as_str()
+    }
+
+    fn to_header(&self, slot: &str, checksum: u32) -> FileSystemResult<String> {
+        let timestamp = self.timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| save_error(slot, "the timestamp is before the UNIX epoch"))?;
+        let mut header = format!(
+            "{}\ntimestamp={}\nplaytime={}\n",
+            SAVE_HEADER,
+            timestamp.as_millis(),
+            self.playtime.as_millis()
+        );
+        header.push_str(format!("game_version={}\n", header_value(slot, self.game_version.as_str())?).as_str());
+        if let Some(ref thumbnail) = self.thumbnail {
+            let thumbnail = thumbnail
+                .to_str()
+                .ok_or_else(|| save_error(slot, "the path of the thumbnail isn't valid UTF-8"))?;
+            header.push_str(format!("thumbnail={}\n", header_value(slot, thumbnail)?).as_str());
+        }
+        header.push_str(format!("checksum={:08x}\n\n", checksum).as_str());
+        Ok(header)
+    }
+
+    //Read the header, leaving the reader at the beginning of the data.
+    //Returns the metadata, and the checksum of the data.
+    fn from_header<R: BufRead>(slot: &str, reader: &mut R) -> FileSystemResult<(SaveSlotMetadata, u32)> {
+        //Bytes which aren't UTF-8 are a damaged header, not an I/O error.
+        let read_line = |reader: &mut R, line: &mut String| reader.read_line(line).map_err(|error| match error.kind() {
+            io::ErrorKind::InvalidData => save_error(slot, "the header is corrupted"),
+            _ => FileSystemError::from(error),
+        });
+        let mut line = String::new();
+        read_line(reader, &mut line)?;
+        if line.trim_end_matches('\n') != SAVE_HEADER {
+            return Err(save_error(slot, "it isn't a save file of a supported version"));
+        }
+
+        let mut timestamp = None;
+        let mut playtime = None;
+        let mut game_version = None;
+        let mut thumbnail = None;
+        let mut checksum = None;
+        loop {
+            line.clear();
+            if read_line(reader, &mut line)? == 0 {
+                return Err(save_error(slot, "the header is truncated"));
+            }
+            let line = line.trim_end_matches('\n');
+            if line.is_empty() {
+                break;
+            }
+            match line.split_once('=') {
+                Some(("timestamp", value)) => timestamp = Some(
+                    UNIX_EPOCH.checked_add(parse_millis(slot, value)?).ok_or_else(|| save_error(slot, "the header is corrupted"))?
+                ),
+                Some(("playtime", value)) => playtime = Some(parse_millis(slot, value)?),
+                Some(("game_version", value)) => game_version = Some(String::from(value)),
+                Some(("thumbnail", value)) => thumbnail = Some(PathBuf::from(value)),
+                Some(("checksum", value)) => checksum = Some(
+                    u32::from_str_radix(value, 16).map_err(|_| save_error(slot, "the header is corrupted"))?
+                ),
+                //A newer version of the engine writes a new header version, this line has been damaged.
+                Some(_) | None => return Err(save_error(slot, "the header is corrupted")),
+            }
+        }
+
+        match (timestamp, playtime, game_version, checksum) {
+            (Some(timestamp), Some(playtime), Some(game_version), Some(checksum)) => Ok((SaveSlotMetadata {
+                timestamp,
+                playtime,
+                thumbnail,
+                game_version,
+            }, checksum)),
+            _ => Err(save_error(slot, "the header is incomplete")),
+        }
+    }
+}
+
+fn header_value<'a>(slot: &str, value: &'a str) -> FileSystemResult<&'a str> {
+    if value.contains(['\n', '\r']) {
+        return Err(save_error(slot, "the metadata can't contain line breaks"));
+    }
+    Ok(value)
+}
+
+fn parse_millis(slot: &str, value: &str) -> FileSystemResult<Duration> {
+    value
+        .parse()
+        .map(Duration::from_millis)
+        .map_err(|_| save_error(slot, "the header is corrupted"))
+}
+
+//A slot found in the save directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaveSlot {
+    name: String,
+    metadata: SaveSlotMetadata,
+    generation: usize,
+}
+
+impl SaveSlot {
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn metadata(&self) -> &SaveSlotMetadata {
+        &self.metadata
+    }
+
+    //0 if the slot is listed with its own metadata, n if its header is corrupted and it is listed
+    //with the metadata of its n-th backup, the one SaveSlots::load restores.
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
+    //Check if the slot is corrupted, and will be restored from a backup when it is loaded.
+    pub fn is_restored(&self) -> bool {
+        self.generation != 0
+    }
+}
+
+//A save loaded from a slot, or from one of its backups.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadedSave {
+    metadata: SaveSlotMetadata,
+    data: Vec<u8>,
+    generation: usize,
+}
+
+impl LoadedSave {
+    pub fn metadata(&self) -> &SaveSlotMetadata {
+        &self.metadata
+    }
+
+    pub fn data(&self) -> &[u8] {
+        self.data.as_slice()
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    //0 if the save of the slot has been loaded, n if it was corrupted and its n-th backup has been loaded instead.
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
+    //Check if the save of the slot was corrupted, and a backup has been loaded instead.
+    pub fn is_restored(&self) -> bool {
+        self.generation != 0
+    }
+}
+
+//The save slots of a directory, accessed through a Filesystem.
+#[derive(Debug)]
+pub struct SaveSlots<'a> {
+    filesystem: &'a Filesystem,
+    directory: PathBuf,
+    backup_count: usize,
+}
+
+impl<'a> SaveSlots<'a> {
+    //Manage the slots stored in directory. See Filesystem::save_slots for the slots of the UserSaveRoot.
+    pub fn new<P: Into<PathBuf>>(filesystem: &'a Filesystem, directory: P) -> Self {
+        SaveSlots {
+            filesystem,
+            directory: directory.into(),
+            backup_count: DEFAULT_BACKUP_COUNT,
+        }
+    }
+
+    //Keep the count previous versions of a slot when it is overwritten. 0 disables the backups.
+    pub fn set_backup_count(&mut self, count: usize) -> &mut Self {
+        self.backup_count = count;
+        self
+    }
+
+    pub fn directory(&self) -> &Path {
+        self.directory.as_path()
+    }
+
+    pub fn backup_count(&self) -> usize {
+        self.backup_count
+    }
+
+    //The slot name is a file name: "slot_1" or "autosave", but not "../slot", "saves\slot" nor ".hidden".
+    fn slot_path(&self, name: &str) -> FileSystemResult<PathBuf> {
+        let mut components = Path::new(name).components();
+        let valid = matches!((components.next(), components.next()), (Some(Component::Normal(_)), None))
+            && !name.starts_with('.')
+            && VirtualPath::new(name).is_ok();
+        if !valid {
+            return Err(save_error(name, "it isn't a valid slot name"));
+        }
+        Ok(self.directory.join(format!("{}.{}", name, SAVE_EXTENSION)))
+    }
+
+    //The generation-th backup of the slot, the newest being 1.
+    fn backup_path(&self, name: &str, generation: usize) -> PathBuf {
+        self.directory
+            .join(BACKUP_DIRECTORY)
+            .join(format!("{}.{}.{}", name, generation, SAVE_EXTENSION))
+    }
+
+    //Lock the slot, waiting as long as necessary. None if the slot isn't on the disk.
+    fn lock(&self, name: &str, path: &Path, mode: LockMode) -> FileSystemResult<Option<FileLock>> {
+        let mut lock_path = path.as_os_str().to_os_string();
+        lock_path.push(format!(".{}", LOCK_EXTENSION));
+        trace!("Taking a {} lock on the save slot {}", mode, name);
+        match self.filesystem.lock(lock_path, mode) {
+            Ok(lock) => Ok(Some(lock)),
+            Err(FileSystemError::MountError(_)) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.filesystem.metadata(path).map(|metadata| metadata.is_file()).unwrap_or(false)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> FileSystemResult<()> {
+        trace!("Copying the save {} to {}", from.display(), to.display());
+        let mut content = Vec::new();
+        self.filesystem.open(from)?.read_to_end(&mut content)?;
+        self.filesystem.write_atomic(to, content.as_slice())
+    }
+
+    //Read the save at path, and check its data against its checksum.
+    fn read_save(&self, name: &str, path: &Path) -> FileSystemResult<(SaveSlotMetadata, Vec<u8>)> {
+        let mut reader = self.filesystem.open(path)?;
+        let (metadata, checksum) = SaveSlotMetadata::from_header(name, &mut reader)?;
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        if checksum != crc32fast::hash(data.as_slice()) {
+            return Err(save_error(name, &format!("the data of {} doesn't match its checksum", path.display())));
+        }
+        Ok((metadata, data))
+    }
+
+    //Shift the backups of the slot by one generation, the current save becoming the newest backup.
+    //The oldest backup is overwritten. A corrupted save is left out.
+    fn rotate_backups(&self, name: &str, path: &Path) -> FileSystemResult<()> {
+        if self.backup_count == 0 || !self.is_file(path) {
+            return Ok(());
+        }
+        if let Err(error) = self.read_save(name, path) {
+            warn!("The save slot {} isn't kept as a backup, it can't be read: {}", name, error);
+            return Ok(());
+        }
+        debug!("Keeping the previous version of the save slot {} as a backup", name);
+        self.filesystem.mkdir(self.directory.join(BACKUP_DIRECTORY))?;
+        for generation in (1..self.backup_count).rev() {
+            let backup = self.backup_path(name, generation);
+            if self.is_file(backup.as_path()) {
+                self.filesystem.rename(backup, self.backup_path(name, generation + 1))?;
+            }
+        }
+        //The current save stays in place until the new one replaces it.
+        self.copy(path, self.backup_path(name, 1).as_path())
+    }
+
+    //Check if a save exists in the slot.
+    pub fn exists(&self, name: &str) -> bool {
+        match self.slot_path(name) {
+            Ok(path) => self.filesystem.metadata(path).map(|metadata| metadata.is_file()).unwrap_or(false),
+            Err(_) => false,
+        }
+    }
+
+    //All the slots, the most recent first. A slot whose header is corrupted is listed with the metadata
+    //of its newest valid backup (see SaveSlot::generation), or skipped if it doesn't have one.
+    pub fn list(&self) -> FileSystemResult<Vec<SaveSlot>> {
+        debug!("Listing the save slots in {}", self.directory.display());
+        if self.filesystem.metadata(self.directory.as_path()).is_err() {
+            //Nothing has been saved yet.
+            return Ok(Vec::new());
+        }
+
+        let mut slots = Vec::new();
+        for entry in self.filesystem.read_dir(self.directory.as_path())? {
+            let path = entry.path();
+            if !entry.is_file() || path.extension().is_none_or(|extension| extension != SAVE_EXTENSION) {
+                continue;
+            }
+            let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(name) if !name.starts_with('.') => name,
+                _ => continue,
+            };
+            let listed = match self.metadata(name) {
+                Ok(metadata) => Ok((metadata, 0)),
+                Err(FileSystemError::SaveError(_)) => self.load(name).map(|loaded| (loaded.metadata, loaded.generation)),
+                Err(error) => Err(error),
+            };
+            match listed {
+                Ok((metadata, generation)) => slots.push(SaveSlot {
+                    name: String::from(name),
+                    metadata,
+                    generation,
+                }),
+                Err(error) => warn!("Skipping the save slot {}: {}", name, error),
+            }
+        }
+
+        slots.sort_by(|first, second| {
+            second.metadata.timestamp
+                .cmp(&first.metadata.timestamp)
+                .then_with(|| first.name.cmp(&second.name))
+        });
+        Ok(slots)
+    }
+
+    //The metadata of the slot, without reading its data.
+    pub fn metadata(&self, name: &str) -> FileSystemResult<SaveSlotMetadata> {
+        let mut reader = self.filesystem.open(self.slot_path(name)?)?;
+        SaveSlotMetadata::from_header(name, &mut reader).map(|(metadata, _)| metadata)
+    }
+
+    //The metadata and the data of the slot. If the save is corrupted, the newest valid backup is loaded:
+    //see LoadedSave::generation. The other errors, like a missing slot, are returned as is.
+    pub fn load(&self, name: &str) -> FileSystemResult<LoadedSave> {
+        debug!("Loading the save slot {}", name);
+        let path = self.slot_path(name)?;
+        let _lock = self.lock(name, path.as_path(), LockMode::Shared)?;
+        let error = match self.read_save(name, path.as_path()) {
+            Ok((metadata, data)) => return Ok(LoadedSave {
+                metadata,
+                data,
+                generation: 0,
+            }),
+            Err(error @ FileSystemError::SaveError(_)) => error,
+            Err(error) => return Err(error),
+        };
+
+        for generation in 1..=self.backup_count {
+            let backup = self.backup_path(name, generation);
+            if !self.is_file(backup.as_path()) {
+                continue;
+            }
+            match self.read_save(name, backup.as_path()) {
+                Ok((metadata, data)) => {
+                    warn!("The save slot {} couldn't be loaded ({}), its backup {} has been loaded instead", name, error, generation);
+                    return Ok(LoadedSave {
+                        metadata,
+                        data,
+                        generation,
+                    });
+                },
+                Err(backup_error) => warn!("The backup {} of the save slot {} is corrupted too: {}", generation, name, backup_error),
+            }
+        }
+        Err(error)
+    }
+
+    //Create the slot, or overwrite it, atomically: a crash leaves the previous save intact.
+    //The previous save is kept as a backup.
+    pub fn save(&self, name: &str, metadata: &SaveSlotMetadata, data: &[u8]) -> FileSystemResult<()> {
+        debug!("Saving the slot {}", name);
+        let path = self.slot_path(name)?;
+        let mut content = metadata.to_header(name, crc32fast::hash(data))?.into_bytes();
+        content.extend_from_slice(data);
+        self.filesystem.mkdir(self.directory.as_path())?;
+        let _lock = self.lock(name, path.as_path(), LockMode::Exclusive)?;
+        self.rotate_backups(name, path.as_path())?;
+        self.filesystem.write_atomic(path, content.as_slice())
+    }
+
+    //Delete the slot, and its backups. Its thumbnail, managed by the game, is left untouched.
+    //The backups of a slot which is already missing are deleted too.
+    pub fn delete(&self, name: &str) -> FileSystemResult<()> {
+        debug!("Deleting the save slot {}", name);
+        let path = self.slot_path(name)?;
+        if self.filesystem.metadata(self.directory.as_path()).is_err() {
+            //Nothing has been saved yet.
+            return Ok(());
+        }
+        //The lock file is left in place: another instance may be waiting on it.
+        let _lock = self.lock(name, path.as_path(), LockMode::Exclusive)?;
+        let deleted = match self.filesystem.rm(path.as_path()) {
+            Err(ref error) if error.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        };
+
+        let backups = self.directory.join(BACKUP_DIRECTORY);
+        if self.filesystem.metadata(backups.as_path()).is_err() {
+            return deleted;
+        }
+        //Every generation is deleted, even the ones above the backup count or after a missing one.
+        let prefix = format!("{}.", name);
+        let suffix = format!(".{}", SAVE_EXTENSION);
+        for entry in self.filesystem.read_dir(backups.as_path())? {
+            let is_backup = entry.path().file_name().and_then(|file_name| file_name.to_str()).is_some_and(|file_name| {
+                file_name
+                    .strip_prefix(prefix.as_str())
+                    .and_then(|file_name| file_name.strip_suffix(suffix.as_str()))
+                    .is_some_and(|generation| generation.parse::<usize>().is_ok())
+            });
+            if entry.is_file() && is_backup {
+                self.filesystem.rm(entry.path())?;
+            }
+        }
+        deleted
+    }
+}
+
+#[cfg(test)]
+mod save_slots_test {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use tempfile;
+    use game_directories::{GameDirectories, RootDir};
+
+    fn metadata(seconds: u64) -> SaveSlotMetadata {
+        let mut metadata = SaveSlotMetadata::new("1.0.2");
+        metadata
+            .set_timestamp(UNIX_EPOCH + Duration::from_secs(seconds))
+            .set_playtime(Duration::from_millis(3_600_500));
+        metadata
+    }
+
+    #[test]
+    fn save_slots_operations() {
+        let fs = Filesystem::in_memory("test_save_slots", "Malkaviel").unwrap();
+        let slots = fs.save_slots().unwrap();
+        assert!(slots.list().unwrap().is_empty());
+
+        let mut with_thumbnail = metadata(2_000);
+        with_thumbnail.set_thumbnail("thumbnails/slot_1.png");
+        slots.save("slot_1", &with_thumbnail, b"level 3\n\nboss").unwrap();
+        slots.save("slot_2", &metadata(1_000), b"level 1").unwrap();
+        slots.save("autosave", &metadata(3_000), b"level 4").unwrap();
+        assert!(slots.exists("slot_1"));
+        assert!(!slots.exists("slot_3"));
+
+        let names: Vec<String> = slots.list().unwrap().iter().map(|slot| String::from(slot.name())).collect();
+        assert_eq!(names, vec!["autosave", "slot_1", "slot_2"]);
+
+        let loaded = slots.load("slot_1").unwrap();
+        assert_eq!(loaded.metadata(), &with_thumbnail);
+        assert_eq!(loaded.metadata().thumbnail(), Some(Path::new("thumbnails/slot_1.png")));
+        assert_eq!(loaded.data(), b"level 3\n\nboss");
+        assert!(!loaded.is_restored());
+
+        //Overwrite, then delete.
+        slots.save("slot_2", &metadata(4_000), b"level 2").unwrap();
+        assert_eq!(slots.list().unwrap()[0].name(), "slot_2");
+        assert_eq!(slots.load("slot_2").unwrap().data(), b"level 2");
+        slots.delete("autosave").unwrap();
+        assert!(!slots.exists("autosave"));
+        assert_eq!(slots.list().unwrap().len(), 2);
+
+        let save_root = fs.construct_path_from_root(RootDir::UserSaveRoot, "slot_1.sav").unwrap();
+        assert_eq!(slots.directory().join("slot_1.sav"), save_root);
+    }
+
+    //Flip a byte at the end of the file, in the data of the save.
+    fn corrupt(fs: &Filesystem, path: &Path) {
+        let mut content = Vec::new();
+        fs.open(path).unwrap().read_to_end(&mut content).unwrap();
+        let last = content.len() - 1;
+        content[last] ^= 0xFF;
+        fs.write_atomic(path, content.as_slice()).unwrap();
+    }
+
+    #[test]
+    fn save_slots_backups() {
+        let fs = Filesystem::in_memory("test_save_slots", "Malkaviel").unwrap();
+        let mut slots = fs.save_slots().unwrap();
+        slots.set_backup_count(2);
+        for level in 1..5 {
+            slots.save("slot_1", &metadata(level), format!("level {}", level).as_bytes()).unwrap();
+        }
+        assert!(fs.metadata(slots.backup_path("slot_1", 2)).is_ok());
+        assert!(fs.metadata(slots.backup_path("slot_1", 3)).is_err());
+        assert_eq!(slots.list().unwrap().len(), 1);
+
+        //The save is corrupted: the newest backup is loaded.
+        corrupt(&fs, slots.slot_path("slot_1").unwrap().as_path());
+        let loaded = slots.load("slot_1").unwrap();
+        assert_eq!(loaded.generation(), 1);
+        assert_eq!(loaded.data(), b"level 3");
+
+        //The newest backup is corrupted too: the next one is loaded.
+        corrupt(&fs, slots.backup_path("slot_1", 1).as_path());
+        let loaded = slots.load("slot_1").unwrap();
+        assert_eq!(loaded.generation(), 2);
+        assert_eq!(loaded.into_data(), b"level 2");
+
+        //The corrupted save isn't kept as a backup.
+        slots.save("slot_1", &metadata(5), b"level 5").unwrap();
+        corrupt(&fs, slots.slot_path("slot_1").unwrap().as_path());
+        slots.save("slot_1", &metadata(6), b"level 6").unwrap();
+        assert_eq!(slots.load("slot_1").unwrap().data(), b"level 6");
+        corrupt(&fs, slots.slot_path("slot_1").unwrap().as_path());
+        assert_eq!(slots.load("slot_1").unwrap().data(), b"level 2");
+
+        //A damaged header is corrupted too.
+        let save = slots.slot_path("slot_1").unwrap();
+        for &(key, damaged) in &[("checksum=", ""), ("checksum=", "unknown=0\n"), ("timestamp=", "timestamp=-1\n")] {
+            slots.save("slot_1", &metadata(5), b"level 5").unwrap();
+            slots.save("slot_1", &metadata(6), b"level 6").unwrap();
+            let mut content = String::new();
+            fs.open(save.as_path()).unwrap().read_to_string(&mut content).unwrap();
+            let content: String = content
+                .split_inclusive('\n')
+                .map(|line| if line.starts_with(key) { damaged } else { line })
+                .collect();
+            fs.write_atomic(save.as_path(), content.as_bytes()).unwrap();
+            let loaded = slots.load("slot_1").unwrap();
+            assert_eq!(loaded.generation(), 1);
+            assert_eq!(loaded.data(), b"level 5");
+
+            //The slot is listed with the metadata of the backup.
+            let listed = slots.list().unwrap();
+            assert_eq!(listed.len(), 1);
+            assert_eq!(listed[0].metadata(), &metadata(5));
+            assert!(listed[0].is_restored());
+        }
+
+        //A missing slot isn't restored from its backups.
+        fs.rm(save.as_path()).unwrap();
+        match slots.load("slot_1") {
+            Err(ref error) if error.kind() == ErrorKind::NotFound => {},
+            result => panic!("A missing save has been loaded: {:?}", result),
+        }
+
+        slots.save("slot_1", &metadata(7), b"level 7").unwrap();
+        corrupt(&fs, save.as_path());
+        corrupt(&fs, slots.backup_path("slot_1", 1).as_path());
+        corrupt(&fs, slots.backup_path("slot_1", 2).as_path());
+        match slots.load("slot_1") {
+            Err(FileSystemError::SaveError(_)) => {},
+            result => panic!("A corrupted save has been loaded: {:?}", result),
+        }
+
+        //The backups of a missing slot are deleted too, they don't belong to another slot.
+        slots.save("slot_10", &metadata(8), b"level 8").unwrap();
+        slots.save("slot_10", &metadata(9), b"level 9").unwrap();
+        fs.rm(save.as_path()).unwrap();
+        slots.delete("slot_1").unwrap();
+        let backups: Vec<PathBuf> = fs.read_dir(slots.directory().join(BACKUP_DIRECTORY)).unwrap().map(|entry| entry.path().to_path_buf()).collect();
+        assert_eq!(backups, vec![slots.backup_path("slot_10", 1)]);
+        slots.delete("slot_1").unwrap();
+    }
+
+    #[test]
+    fn save_slots_locks() {
+        let home = tempfile::tempdir().unwrap();
+        let directories = GameDirectories::builder("test_save_slots", "Malkaviel").base_directory(home.path()).build().unwrap();
+        let fs = Filesystem::from_directories(directories);
+        let slots = fs.save_slots().unwrap();
+        slots.save("slot_1", &metadata(1), b"level 1").unwrap();
+        let lock_path = slots.directory().join("slot_1.sav.lock");
+        assert!(lock_path.is_file());
+        assert_eq!(slots.list().unwrap().len(), 1);
+
+        //The slot isn't saved while another instance holds the lock.
+        let lock = FileLock::lock(lock_path.as_path(), LockMode::Exclusive).unwrap();
+        let saved = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let saver = scope.spawn(|| {
+                slots.save("slot_1", &metadata(2), b"level 2").unwrap();
+                saved.store(true, Ordering::SeqCst);
+            });
+            thread::sleep(Duration::from_millis(200));
+            assert!(!saved.load(Ordering::SeqCst));
+            drop(lock);
+            saver.join().unwrap();
+        });
+        assert_eq!(slots.load("slot_1").unwrap().data(), b"level 2");
+    }
+
+    #[test]
+    fn save_slots_invalid() {
+        let fs = Filesystem::in_memory("test_save_slots", "Malkaviel").unwrap();
+        let slots = fs.save_slots().unwrap();
+        for name in &["", "..", "../slot", "saves/slot", "saves\\slot", "/slot", ".hidden", "slot:1"] {
+            match slots.save(name, &metadata(0), b"") {
+                Err(FileSystemError::SaveError(_)) => {},
+                result => panic!("The slot name {:?} has been accepted: {:?}", name, result),
+            }
+        }
+
+        let mut multiline = metadata(0);
+        multiline.set_thumbnail("thumbnail.png\ntimestamp=0");
+        assert!(slots.save("slot_1", &multiline, b"").is_err());
+
+        //The corrupted slots are skipped.
+        slots.save("slot_1", &metadata(0), b"data").unwrap();
+        fs.write_atomic(slots.directory().join("broken.sav"), b"not a save").unwrap();
+        assert_eq!(slots.list().unwrap().len(), 1);
+        assert!(slots.load("broken").is_err());
+    }
+}
@@ -0,0 +1,379 @@
+// Copyright 2017-2018 Maskerad Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::str::{FromStr, Split};
+use filesystem_error::{FileSystemError, FileSystemResult};
+
+/*VIRTUAL PATHS.
+
+The paths of the assets are written by the developers on every platform, and stored in the
+levels, the packs, the scripts... "Textures\Hero.png" works on Windows, not on Linux.
+
+A virtual path is the same everywhere:
+- separated by forward slashes, without leading, trailing or repeated slashes ("textures/hero.png"),
+- valid UTF-8,
+- normalized: no "." and no ".." (".." is resolved when the path is parsed, and can't go above the root),
+- case-stable: the case is kept as written and the comparisons are case-sensitive, so two paths
+  differing only by their case are never mixed up, even on a case-insensitive filesystem,
+- without the characters Windows forbids in file names.
+
+The empty path is the root. A virtual path can only become an OS path relative to a RootDir
+(see Filesystem::virtual_path_from_root).
+*/
+
+//The characters which can't be used in a file name on, at least, one platform.
+const FORBIDDEN_CHARACTERS: &[char] = &['\\', ':', '*', '?', '"', '<', '>', '|'];
+
+fn virtual_path_error(path: &str, reason: &str) -> FileSystemError {
+    FileSystemError::VirtualPathError(format!(
+        "{:?} isn't a valid virtual path: {}.",
+        path,
+        reason
+    ))
+}
+
+fn check_component(path: &str, component: &str) -> FileSystemResult<()> {
+    if let Some(character) = component.chars().find(|character| character.is_control() || FORBIDDEN_CHARACTERS.contains(character)) {
+        return Err(virtual_path_error(path, format!("it contains the forbidden character {:?}", character).as_str()));
+    }
+    Ok(())
+}
+
+//A borrowed virtual path, like Path for the OS paths.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct VirtualPath(str);
+
+impl VirtualPath {
+    //Borrow the string as a virtual path. The string must already be normalized: "textures/./hero.png"
+    //or "textures\hero.png" are refused (use VirtualPathBuf::new to normalize them).
+    pub fn new(path: &str) -> FileSystemResult<&VirtualPath> {
+        if path.starts_with('/') || path.ends_with('/') {
+            return Err(virtual_path_error(path, "it starts or ends with a slash"));
+        }
+        if !path.is_empty() {
+            for component in path.split('/') {
+                if component.is_empty() || component == "." || component == ".." {
+                    return Err(virtual_path_error(path, "it isn't normalized"));
+                }
+                check_component(path, component)?;
+            }
+        }
+        Ok(VirtualPath::from_normalized(path))
+    }
+
+    fn from_normalized(path: &str) -> &VirtualPath {
+        //VirtualPath is a transparent wrapper around str.
+        unsafe { &*(path as *const str as *const VirtualPath) }
+    }
+
+    //The root, the empty path.
+    pub fn root() -> &'static VirtualPath {
+        VirtualPath::from_normalized("")
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    //The names of the directories and of the file, from the root.
+    pub fn components(&self) -> Components<'_> {
+        Components {
+            split: if self.is_root() { None } else { Some(self.0.split('/')) },
+        }
+    }
+
+    //The path followed by the relative path, normalized.
+    pub fn join(&self, path: &str) -> FileSystemResult<VirtualPathBuf> {
+        let mut joined = self.to_virtual_path_buf();
+        joined.push(path)?;
+        Ok(joined)
+    }
+
+    //The path without its last component, or None for the root.
+    pub fn parent(&self) -> Option<&VirtualPath> {
+        if self.is_root() {
+            return None;
+        }
+        Some(match self.0.rfind('/') {
+            Some(index) => VirtualPath::from_normalized(&self.0[..index]),
+            None => VirtualPath::root(),
+        })
+    }
+
+    //The last component, or None for the root.
+    pub fn file_name(&self) -> Option<&str> {
+        if self.is_root() {
+            return None;
+        }
+        self.0.rsplit('/').next()
+    }
+
+    //The file name without its extension. A leading dot doesn't start an extension: ".config" has no extension.
+    pub fn file_stem(&self) -> Option<&str> {
+        self.file_name().map(|file_name| match split_extension(file_name) {
+            Some((stem, _)) => stem,
+            None => file_name,
+        })
+    }
+
+    //The extension of the file name, without the dot.
+    pub fn extension(&self) -> Option<&str> {
+        self.file_name().and_then(split_extension).map(|(_, extension)| extension)
+    }
+
+    //The path with the extension replaced (or removed, if extension is empty).
+    pub fn with_extension(&self, extension: &str) -> FileSystemResult<VirtualPathBuf> {
+        let file_stem = match self.file_stem() {
+            Some(file_stem) => file_stem,
+            None => return Err(virtual_path_error(self.as_str(), "the root doesn't have an extension")),
+        };
+        let file_name = if extension.is_empty() {
+            String::from(file_stem)
+        } else {
+            format!("{}.{}", file_stem, extension)
+        };
+        let parent = self.parent().unwrap_or_else(|| VirtualPath::root());
+        if file_name.contains('/') {
+            return Err(virtual_path_error(file_name.as_str(), "an extension can't contain a slash"));
+        }
+        parent.join(file_name.as_str())
+    }
+
+    //Check if base is the path, or one of its ancestors. Whole components are compared:
+    //"textures/hero.png" starts with "textures", not with "text".
+    pub fn starts_with(&self, base: &VirtualPath) -> bool {
+        base.is_root() || self.0 == base.0 || (self.0.starts_with(&base.0) && self.0[base.0.len()..].starts_with('/'))
+    }
+
+    //The path of the file in the OS directory root.
+    pub(crate) fn to_path_from(&self, root: &Path) -> PathBuf {
+        let mut path = root.to_path_buf();
+        for component in self.components() {
+            path.push(component);
+        }
+        path
+    }
+
+    pub fn to_virtual_path_buf(&self) -> VirtualPathBuf {
+        VirtualPathBuf(String::from(&self.0))
+    }
+}
+
+fn split_extension(file_name: &str) -> Option<(&str, &str)> {
+    match file_name.rfind('.') {
+        Some(0) | None => None,
+        Some(index) => Some((&file_name[..index], &file_name[index + 1..])),
+    }
+}
+
+impl fmt::Display for VirtualPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "/{}", &self.0)
+    }
+}
+
+impl AsRef<VirtualPath> for VirtualPath {
+    fn as_ref(&self) -> &VirtualPath {
+        self
+    }
+}
+
+impl AsRef<str> for VirtualPath {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl ToOwned for VirtualPath {
+    type Owned = VirtualPathBuf;
+
+    fn to_owned(&self) -> VirtualPathBuf {
+        self.to_virtual_path_buf()
+    }
+}
+
+//The components of a virtual path.
+#[derive(Debug, Clone)]
+pub struct Components<'a> {
+    split: Option<Split<'a, char>>,
+}
+
+impl<'a> Iterator for Components<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        self.split.as_mut().and_then(Iterator::next)
+    }
+}
+
+//An owned virtual path, like PathBuf for the OS paths.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VirtualPathBuf(String);
+
+impl VirtualPathBuf {
+    //Parse and normalize the path: backslashes become slashes, the repeated slashes, "." and ".."
+    //are removed. A leading slash is accepted, every virtual path starts from the root.
+    //The paths going above the root, and the forbidden characters, are refused.
+    pub fn new(path: &str) -> FileSystemResult<VirtualPathBuf> {
+        let mut normalized = VirtualPathBuf::default();
+        normalized.push(path)?;
+        Ok(normalized)
+    }
+
+    //Append the relative path, normalizing it (see new).
+    pub fn push(&mut self, path: &str) -> FileSystemResult<()> {
+        let mut components: Vec<&str> = self.0.split('/').filter(|component| !component.is_empty()).collect();
+        for component in path.split(['/', '\\']) {
+            match component {
+                "" | "." => {},
+                ".." => {
+                    if components.pop().is_none() {
+                        return Err(virtual_path_error(path, "it goes above the root"));
+                    }
+                },
+                component => {
+                    check_component(path, component)?;
+                    components.push(component);
+                },
+            }
+        }
+        self.0 = components.join("/");
+        Ok(())
+    }
+
+    //Remove the last component. Return false for the root.
+    pub fn pop(&mut self) -> bool {
+        let parent_len = match self.parent() {
+            Some(parent) => parent.0.len(),
+            None => return false,
+        };
+        self.0.truncate(parent_len);
+        true
+    }
+
+    pub fn as_virtual_path(&self) -> &VirtualPath {
+        VirtualPath::from_normalized(self.0.as_str())
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+impl Deref for VirtualPathBuf {
+    type Target = VirtualPath;
+
+    fn deref(&self) -> &VirtualPath {
+        self.as_virtual_path()
+    }
+}
+
+impl Borrow<VirtualPath> for VirtualPathBuf {
+    fn borrow(&self) -> &VirtualPath {
+        self.as_virtual_path()
+    }
+}
+
+impl AsRef<VirtualPath> for VirtualPathBuf {
+    fn as_ref(&self) -> &VirtualPath {
+        self.as_virtual_path()
+    }
+}
+
+impl FromStr for VirtualPathBuf {
+    type Err = FileSystemError;
+
+    fn from_str(path: &str) -> FileSystemResult<VirtualPathBuf> {
+        VirtualPathBuf::new(path)
+    }
+}
+
+impl fmt::Display for VirtualPathBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_virtual_path().fmt(f)
+    }
+}
+
+impl<'a> From<&'a VirtualPath> for VirtualPathBuf {
+    fn from(path: &'a VirtualPath) -> VirtualPathBuf {
+        path.to_virtual_path_buf()
+    }
+}
+
+#[cfg(test)]
+mod virtual_path_test {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn virtual_path_normalization() {
+        assert_eq!(VirtualPathBuf::new("textures/hero.png").unwrap().as_str(), "textures/hero.png");
+        assert_eq!(VirtualPathBuf::new("/textures//./characters/../hero.png/").unwrap().as_str(), "textures/hero.png");
+        assert_eq!(VirtualPathBuf::new("Textures\\Hero.png").unwrap().as_str(), "Textures/Hero.png");
+        assert!(VirtualPathBuf::new("textures/..").unwrap().is_root());
+        assert!(VirtualPathBuf::new("../hero.png").is_err());
+        assert!(VirtualPathBuf::new("C:/hero.png").is_err());
+        assert!(VirtualPathBuf::new("hero?.png").is_err());
+        assert!("textures/hero.png".parse::<VirtualPathBuf>().is_ok());
+
+        assert!(VirtualPath::new("textures/hero.png").is_ok());
+        assert!(VirtualPath::new("").unwrap().is_root());
+        for path in &["/textures", "textures/", "textures//hero.png", "textures/./hero.png", "textures\\hero.png", "../hero.png"] {
+            assert!(VirtualPath::new(path).is_err(), "{} has been accepted", path);
+        }
+
+        //The case is kept and compared.
+        let mut paths = HashSet::new();
+        paths.insert(VirtualPathBuf::new("Hero.png").unwrap());
+        paths.insert(VirtualPathBuf::new("hero.png").unwrap());
+        assert_eq!(paths.len(), 2);
+        assert!(paths.contains(VirtualPath::new("Hero.png").unwrap()));
+    }
+
+    #[test]
+    fn virtual_path_helpers() {
+        let path = VirtualPathBuf::new("textures/characters/hero.tar.gz").unwrap();
+        assert_eq!(path.file_name(), Some("hero.tar.gz"));
+        assert_eq!(path.file_stem(), Some("hero.tar"));
+        assert_eq!(path.extension(), Some("gz"));
+        assert_eq!(path.parent().unwrap().as_str(), "textures/characters");
+        assert_eq!(path.parent().unwrap().parent().unwrap().parent(), Some(VirtualPath::root()));
+        assert_eq!(VirtualPath::root().parent(), None);
+        assert_eq!(path.components().collect::<Vec<&str>>(), vec!["textures", "characters", "hero.tar.gz"]);
+        assert_eq!(VirtualPath::root().components().count(), 0);
+        assert_eq!(path.with_extension("zip").unwrap().as_str(), "textures/characters/hero.tar.zip");
+        assert_eq!(VirtualPath::new(".config").unwrap().extension(), None);
+        assert_eq!(VirtualPath::new(".config").unwrap().file_stem(), Some(".config"));
+        assert_eq!(path.to_string(), "/textures/characters/hero.tar.gz");
+
+        let textures = VirtualPath::new("textures").unwrap();
+        assert!(path.starts_with(textures));
+        assert!(path.starts_with(VirtualPath::root()));
+        assert!(!path.starts_with(VirtualPath::new("text").unwrap()));
+        assert_eq!(textures.join("../sounds/./step.ogg").unwrap().as_str(), "sounds/step.ogg");
+        assert!(textures.join("../../step.ogg").is_err());
+
+        let mut buf = textures.to_virtual_path_buf();
+        buf.push("characters\\villain.png").unwrap();
+        assert_eq!(buf.as_str(), "textures/characters/villain.png");
+        assert!(buf.pop());
+        assert!(buf.pop());
+        assert!(buf.pop());
+        assert!(!buf.pop());
+        assert_eq!(buf.to_path_from(Path::new("/game")), PathBuf::from("/game"));
+        assert_eq!(path.to_path_from(Path::new("/game")), Path::new("/game").join("textures").join("characters").join("hero.tar.gz"));
+    }
+}
@@ -0,0 +1,472 @@
+// Copyright 2017-2018 Maskerad Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use notify::{self, Config, Event, EventKind, PollWatcher, RecursiveMode, Watcher};
+use notify::event::{ModifyKind, RenameMode};
+use filesystem_error::{FileSystemError, FileSystemResult};
+
+/*FILE WATCHING.
+
+Editors don't save a file in one operation: they truncate it, write it in several chunks, sometimes
+write a temporary file and rename it... Reloading an asset on every raw notification would reload
+it several times, sometimes while it is half-written.
+
+The raw notifications (inotify on Linux, the native API on the other platforms, or a polling
+fallback if the native API can't be used) are collected on a dedicated thread. Once the watched
+directories stay quiet for the debounce delay, the notifications are merged (one event per path)
+and sent through a channel, to be consumed by the game loop.
+*/
+
+//A change in a watched directory, after debouncing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    Created(PathBuf),
+    Modified(PathBuf),
+    Removed(PathBuf),
+    Renamed {
+        from: PathBuf,
+        to: PathBuf,
+    },
+}
+
+impl WatchEvent {
+    //The path affected by the event. For a rename, the new path.
+    pub fn path(&self) -> &Path {
+        match *self {
+            WatchEvent::Created(ref path) |
+            WatchEvent::Modified(ref path) |
+            WatchEvent::Removed(ref path) => path.as_path(),
+            WatchEvent::Renamed { ref to, .. } => to.as_path(),
+        }
+    }
+}
+
+//How the directories are watched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchOptions {
+    debounce: Duration,
+    recursive: bool,
+    polling: bool,
+    poll_interval: Duration,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        WatchOptions {
+            debounce: Duration::from_millis(100),
+            recursive: true,
+            polling: false,
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+impl WatchOptions {
+    pub fn new() -> Self {
+        WatchOptions::default()
+    }
+
+    //How long the watched directories must stay quiet before the events are sent.
+    pub fn debounce(&mut self, debounce: Duration) -> &mut Self {
+        self.debounce = debounce;
+        self
+    }
+
+    //Watch the subdirectories too.
+    pub fn recursive(&mut self, recursive: bool) -> &mut Self {
+        self.recursive = recursive;
+        self
+    }
+
+    //Scan the directories periodically instead of using the native API (network filesystems...).
+    pub fn polling(&mut self, polling: bool) -> &mut Self {
+        self.polling = polling;
+        self
+    }
+
+    //The delay between two scans, when polling.
+    pub fn poll_interval(&mut self, poll_interval: Duration) -> &mut Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+}
+
+//How the change of a file on the disk is seen through the watched path. With an overlay, the file
+//at a path is the one of the layer with the highest priority containing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Visibility {
+    //The file is seen through the layer of the change, or isn't in any layer.
+    Visible,
+    //A layer with a higher priority provides the file, the change can't be seen.
+    Shadowed,
+    //The layer of the change doesn't provide the file anymore, a layer with a lower priority does.
+    Uncovered,
+}
+
+//Tell how the change of the file at a path on the disk is seen.
+pub(crate) type VisibilityCheck = Box<dyn Fn(&Path) -> Visibility + Send>;
+
+//Watch some directories, and deliver the debounced events through a channel.
+//The directories aren't watched anymore when the FileWatcher is dropped.
+pub struct FileWatcher {
+    //Keep the watcher alive. Dropping it stops the debouncing thread.
+    _watcher: Box<dyn Watcher + Send>,
+    events: Receiver<WatchEvent>,
+    polling: bool,
+}
+
+impl FileWatcher {
+    //Watch the given directories. Each watched directory comes with the path used to report its events:
+    //the events in the directory are reported under this path instead.
+    //The visibility check filters the changes hidden by the layers of an overlay.
+    pub(crate) fn new(directories: Vec<(PathBuf, PathBuf)>, visibility: VisibilityCheck, options: &WatchOptions) -> FileSystemResult<FileWatcher> {
+        let (raw_sender, raw_receiver) = mpsc::channel();
+        let (watcher, polling) = FileWatcher::create_watcher(raw_sender, directories.as_slice(), options)?;
+
+        let (sender, events) = mpsc::channel();
+        let debounce = options.debounce;
+        thread::Builder::new()
+            .name(String::from("maskerad-watcher"))
+            .spawn(move || debounce_events(raw_receiver, sender, directories, visibility, debounce))?;
+
+        Ok(FileWatcher {
+            _watcher: watcher,
+            events,
+            polling,
+        })
+    }
+
+    //Watch the directories with the native API, or by polling if the native API can't be used:
+    //the watcher can't be created, or runs out of watches (ENOSPC with inotify) for the directories.
+    fn create_watcher(raw_sender: Sender<notify::Result<Event>>, directories: &[(PathBuf, PathBuf)], options: &WatchOptions) -> FileSystemResult<(Box<dyn Watcher + Send>, bool)> {
+        let mode = if options.recursive { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+
+        if !options.polling {
+            let watcher = notify::recommended_watcher(raw_sender.clone())
+                .map_err(|error| FileSystemError::WatchError(error.to_string()))
+                .and_then(|watcher| FileWatcher::watch_directories(watcher, directories, mode));
+            match watcher {
+                Ok(watcher) => return Ok((Box::new(watcher), false)),
+                Err(error) => warn!("The native file watching API can't be used ({}), falling back to polling.", error),
+            }
+        }
+
+        let config = Config::default().with_poll_interval(options.poll_interval);
+        let watcher = PollWatcher::new(raw_sender, config).map_err(|error| FileSystemError::WatchError(format!(
+            "The polling watcher could not be created: {}",
+            error
+        )))?;
+        let watcher = FileWatcher::watch_directories(watcher, directories, mode)?;
+        Ok((Box::new(watcher), true))
+    }
+
+    fn watch_directories<W: Watcher>(mut watcher: W, directories: &[(PathBuf, PathBuf)], mode: RecursiveMode) -> FileSystemResult<W> {
+        for (directory, _) in directories.iter() {
+            debug!("Watching the directory {}", directory.display());
+            watcher.watch(directory.as_path(), mode).map_err(|error| FileSystemError::WatchError(format!(
+                "{} could not be watched: {}",
+                directory.display(),
+                error
+            )))?;
+        }
+        Ok(watcher)
+    }
+
+    //The channel delivering the events.
+    pub fn receiver(&self) -> &Receiver<WatchEvent> {
+        &self.events
+    }
+
+    //Get the next event, without blocking.
+    pub fn try_recv(&self) -> Option<WatchEvent> {
+        self.events.try_recv().ok()
+    }
+
+    //Wait for the next event, for timeout at most.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<WatchEvent> {
+        self.events.recv_timeout(timeout).ok()
+    }
+
+    //Check if the directories are scanned periodically, instead of being watched with the native API.
+    pub fn is_polling(&self) -> bool {
+        self.polling
+    }
+}
+
+fn debounce_events(raw_events: Receiver<notify::Result<Event>>, events: Sender<WatchEvent>, directories: Vec<(PathBuf, PathBuf)>, visibility: VisibilityCheck, debounce: Duration) {
+    let mut pending = Vec::new();
+    //A directory which never stays quiet must not delay the events forever.
+    let mut oldest: Option<Instant> = None;
+
+    loop {
+        let disconnected = match raw_events.recv_timeout(debounce) {
+            Ok(Ok(event)) => {
+                for watch_event in translate_event(&event, directories.as_slice(), visibility.as_ref()) {
+                    merge_event(&mut pending, watch_event);
+                }
+                oldest = oldest.or_else(|| Some(Instant::now()));
+                if oldest.is_none_or(|oldest| oldest.elapsed() < debounce * 4) {
+                    continue;
+                }
+                false
+            },
+            Ok(Err(error)) => {
+                warn!("Error while watching the files: {}", error);
+                continue;
+            },
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+
+        oldest = None;
+        for event in pending.drain(..) {
+            trace!("File watching event: {:?}", event);
+            if events.send(event).is_err() {
+                return;
+            }
+        }
+        if disconnected {
+            return;
+        }
+    }
+}
+
+//Express a path on the disk with the path used to report the events of its directory.
+fn reported_path(path: &Path, directories: &[(PathBuf, PathBuf)]) -> Option<PathBuf> {
+    directories.iter().find_map(|(directory, reported)| {
+        path.strip_prefix(directory).ok().map(|relative| {
+            if relative.as_os_str().is_empty() {
+                reported.clone()
+            } else {
+                reported.join(relative)
+            }
+        })
+    })
+}
+
+//The event, as seen through the watched path.
+fn seen(make: fn(PathBuf) -> WatchEvent, path: PathBuf, visibility: Visibility) -> Option<WatchEvent> {
+    match visibility {
+        Visibility::Visible => Some(make(path)),
+        Visibility::Shadowed => None,
+        //The file of the lower layer is seen instead: its content changed.
+        Visibility::Uncovered => Some(WatchEvent::Modified(path)),
+    }
+}
+
+fn translate_event(event: &Event, directories: &[(PathBuf, PathBuf)], visibility: &dyn Fn(&Path) -> Visibility) -> Vec<WatchEvent> {
+    let reported = |physical: &Path| reported_path(physical, directories).map(|path| (path, visibility(physical)));
+
+    let make: fn(PathBuf) -> WatchEvent = match event.kind {
+        EventKind::Create(_) => WatchEvent::Created,
+        EventKind::Remove(_) => WatchEvent::Removed,
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+            //A file moved from or to a directory which isn't watched, or from or to a hidden file,
+            //is only created or removed.
+            let side = |index: usize| event.paths.get(index).and_then(|physical| reported(physical));
+            return match (side(0), side(1)) {
+                (Some((from, Visibility::Visible)), Some((to, Visibility::Visible))) => vec![WatchEvent::Renamed { from, to }],
+                (from, to) => from
+                    .and_then(|(from, visibility)| seen(WatchEvent::Removed, from, visibility))
+                    .into_iter()
+                    .chain(to.and_then(|(to, visibility)| seen(WatchEvent::Created, to, visibility)))
+                    .collect(),
+            };
+        },
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => WatchEvent::Removed,
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => WatchEvent::Created,
+        EventKind::Modify(ModifyKind::Name(_)) => {
+            //We don't know which side of the rename it is, look at the disk.
+            return event.paths.iter()
+                .filter_map(|physical| reported(physical).and_then(|(path, visibility)| {
+                    let make: fn(PathBuf) -> WatchEvent = if physical.exists() { WatchEvent::Created } else { WatchEvent::Removed };
+                    seen(make, path, visibility)
+                }))
+                .collect();
+        },
+        EventKind::Modify(_) => WatchEvent::Modified,
+        EventKind::Access(_) | EventKind::Any | EventKind::Other => return Vec::new(),
+    };
+    event.paths.iter()
+        .filter_map(|physical| reported(physical))
+        .filter_map(|(path, visibility)| seen(make, path, visibility))
+        .collect()
+}
+
+//Add an event to the pending ones, keeping one event per path.
+fn merge_event(pending: &mut Vec<WatchEvent>, event: WatchEvent) {
+    if let WatchEvent::Renamed { ref from, ref to } = event {
+        //The native APIs send the two sides of the rename before the rename itself.
+        let removed = pending.iter().position(|pending| *pending == WatchEvent::Removed(from.clone()));
+        let created = pending.iter().position(|pending| *pending == WatchEvent::Created(to.clone()));
+        match (removed, created) {
+            (Some(removed), Some(created)) => {
+                pending[created] = event.clone();
+                pending.remove(removed);
+            },
+            //The file has been created, then renamed, during the same debounce delay.
+            (None, Some(_)) => {},
+            _ => pending.push(event.clone()),
+        }
+        return;
+    }
+
+    let index = match pending.iter().position(|pending| pending.path() == event.path()) {
+        Some(index) => index,
+        None => {
+            pending.push(event);
+            return;
+        },
+    };
+
+    let merged = match (&pending[index], event) {
+        //Created then removed: nothing happened.
+        (&WatchEvent::Created(_), WatchEvent::Removed(_)) => None,
+        (&WatchEvent::Created(_), _) => return,
+        (&WatchEvent::Modified(_), WatchEvent::Removed(path)) => Some(WatchEvent::Removed(path)),
+        (&WatchEvent::Modified(_), _) => return,
+        //Removed then created: the file has been replaced.
+        (&WatchEvent::Removed(_), WatchEvent::Removed(_)) => return,
+        (&WatchEvent::Removed(_), event) => Some(WatchEvent::Modified(event.path().to_path_buf())),
+        (WatchEvent::Renamed { from, .. }, WatchEvent::Removed(_)) => Some(WatchEvent::Removed(from.clone())),
+        (&WatchEvent::Renamed { .. }, _) => return,
+    };
+
+    match merged {
+        Some(merged) => pending[index] = merged,
+        None => {
+            pending.remove(index);
+        },
+    }
+}
+
+#[cfg(test)]
+mod watcher_test {
+    use super::*;
+
+    fn path(path: &str) -> PathBuf {
+        PathBuf::from(path)
+    }
+
+    fn merge_all(events: Vec<WatchEvent>) -> Vec<WatchEvent> {
+        let mut pending = Vec::new();
+        for event in events {
+            merge_event(&mut pending, event);
+        }
+        pending
+    }
+
+    #[test]
+    fn watcher_merge_events() {
+        //An editor saving a texture.
+        assert_eq!(
+            merge_all(vec![
+                WatchEvent::Modified(path("/assets/wall.png")),
+                WatchEvent::Modified(path("/assets/wall.png")),
+                WatchEvent::Created(path("/assets/floor.png")),
+                WatchEvent::Modified(path("/assets/floor.png")),
+            ]),
+            vec![WatchEvent::Modified(path("/assets/wall.png")), WatchEvent::Created(path("/assets/floor.png"))]
+        );
+
+        //A temporary file, written then removed.
+        assert!(merge_all(vec![
+            WatchEvent::Created(path("/assets/.wall.png.swp")),
+            WatchEvent::Modified(path("/assets/.wall.png.swp")),
+            WatchEvent::Removed(path("/assets/.wall.png.swp")),
+        ]).is_empty());
+
+        //A file replaced by a new one.
+        assert_eq!(
+            merge_all(vec![WatchEvent::Removed(path("/assets/wall.png")), WatchEvent::Created(path("/assets/wall.png"))]),
+            vec![WatchEvent::Modified(path("/assets/wall.png"))]
+        );
+        assert_eq!(
+            merge_all(vec![WatchEvent::Modified(path("/assets/wall.png")), WatchEvent::Removed(path("/assets/wall.png"))]),
+            vec![WatchEvent::Removed(path("/assets/wall.png"))]
+        );
+    }
+
+    #[test]
+    fn watcher_merge_renames() {
+        let renamed = WatchEvent::Renamed {
+            from: path("/assets/wall.png"),
+            to: path("/assets/brick.png"),
+        };
+        assert_eq!(
+            merge_all(vec![
+                WatchEvent::Removed(path("/assets/wall.png")),
+                WatchEvent::Created(path("/assets/brick.png")),
+                renamed.clone(),
+                WatchEvent::Modified(path("/assets/brick.png")),
+            ]),
+            vec![renamed.clone()]
+        );
+        assert_eq!(
+            merge_all(vec![renamed, WatchEvent::Removed(path("/assets/brick.png"))]),
+            vec![WatchEvent::Removed(path("/assets/wall.png"))]
+        );
+
+        //Created, then renamed.
+        assert_eq!(
+            merge_all(vec![
+                WatchEvent::Created(path("/assets/tmp.png")),
+                WatchEvent::Removed(path("/assets/tmp.png")),
+                WatchEvent::Created(path("/assets/wall.png")),
+                WatchEvent::Renamed {
+                    from: path("/assets/tmp.png"),
+                    to: path("/assets/wall.png"),
+                },
+            ]),
+            vec![WatchEvent::Created(path("/assets/wall.png"))]
+        );
+    }
+
+    #[test]
+    fn watcher_translate_renames() {
+        let directories = vec![(path("/home/artist/game/assets"), path("/assets"))];
+        let rename = |mode: RenameMode, paths: &[&str]| {
+            let event = paths.iter().fold(Event::new(EventKind::Modify(ModifyKind::Name(mode))), |event, physical| event.add_path(path(physical)));
+            translate_event(&event, &directories, &|_: &Path| Visibility::Visible)
+        };
+
+        assert_eq!(
+            rename(RenameMode::Both, &["/home/artist/game/assets/wall.png", "/home/artist/game/assets/brick.png"]),
+            vec![WatchEvent::Renamed {
+                from: path("/assets/wall.png"),
+                to: path("/assets/brick.png"),
+            }]
+        );
+        assert_eq!(
+            rename(RenameMode::Both, &["/home/artist/game/assets/wall.png", "/home/artist/trash/wall.png"]),
+            vec![WatchEvent::Removed(path("/assets/wall.png"))]
+        );
+        assert_eq!(
+            rename(RenameMode::Both, &["/home/artist/downloads/wall.png", "/home/artist/game/assets/wall.png"]),
+            vec![WatchEvent::Created(path("/assets/wall.png"))]
+        );
+        assert!(rename(RenameMode::Both, &["/home/artist/downloads/wall.png", "/home/artist/trash/wall.png"]).is_empty());
+
+        //The side of the rename is found on the disk, for the watched paths only.
+        assert_eq!(
+            rename(RenameMode::Any, &["/home/artist/downloads/wall.png", "/home/artist/game/assets/wall.png"]),
+            vec![WatchEvent::Removed(path("/assets/wall.png"))]
+        );
+    }
+
+    #[test]
+    fn watcher_reported_paths() {
+        let directories = vec![(path("/home/artist/game/assets"), path("/assets"))];
+        assert_eq!(reported_path(Path::new("/home/artist/game/assets/textures/wall.png"), &directories), Some(path("/assets/textures/wall.png")));
+        assert_eq!(reported_path(Path::new("/home/artist/game/assets"), &directories), Some(path("/assets")));
+        assert_eq!(reported_path(Path::new("/home/artist/game/assets2/wall.png"), &directories), None);
+    }
+}
@@ -0,0 +1,612 @@
+// Copyright 2017-2018 Maskerad Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use flate2::read::DeflateDecoder;
+use crc32fast;
+use filesystem_error::{FileSystemError, FileSystemResult, Operation};
+use file_reader::{FileReader, FileSlice};
+use backend::{Backend, ReadSeek};
+use dir_entry::DirEntry;
+use metadata::Metadata;
+use mount::{archive_parent, to_archive_path};
+
+/*ZIP ARCHIVES.
+
+Read-only support of the ZIP format, with stored and deflated entries (ZIP64 included).
+The central directory is read once, when the archive is opened. Every file opened afterwards
+gets its own handle on the archive, so an archive can be shared between threads.
+
+Every entry is checked against its CRC-32 when opened. Stored entries are read once to be checked,
+then directly from the archive, deflated entries are decompressed in memory.
+
+The sizes and offsets read from the archive are checked against the size of the archive before
+anything is allocated: a corrupted or crafted archive is rejected, it can't exhaust the memory.
+*/
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0605_4b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x0706_4b50;
+const ZIP64_EXTRA_FIELD: u16 = 0x0001;
+
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+const ZIP64_LOCATOR_SIZE: u64 = 20;
+const LOCAL_HEADER_SIZE: u64 = 30;
+const CENTRAL_HEADER_SIZE: usize = 46;
+const MAX_COMMENT_SIZE: u64 = 0xFFFF;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+const FLAG_ENCRYPTED: u16 = 0x0001;
+
+//Deflate can't compress data more than ~1032 times, a bigger uncompressed size is corrupted.
+const MAX_DEFLATE_RATIO: u64 = 1032;
+
+#[derive(Debug, Clone)]
+struct ZipEntry {
+    method: u16,
+    flags: u16,
+    crc32: u32,
+    compressed_size: u64,
+    uncompressed_size: u64,
+    header_offset: u64,
+}
+
+#[derive(Debug, Default)]
+struct ZipIndex {
+    files: HashMap<String, ZipEntry>,
+    //Every directory, explicit or implied by the files, the root being "".
+    directories: HashSet<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ZipArchive {
+    path: PathBuf,
+    index: Arc<ZipIndex>,
+}
+
+impl PartialEq for ZipArchive {
+    fn eq(&self, other: &ZipArchive) -> bool {
+        self.path == other.path
+    }
+}
+
+fn read_u16(buffer: &[u8], offset: usize) -> u16 {
+    u16::from(buffer[offset]) | u16::from(buffer[offset + 1]) << 8
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    u32::from(read_u16(buffer, offset)) | u32::from(read_u16(buffer, offset + 2)) << 16
+}
+
+fn read_u64(buffer: &[u8], offset: usize) -> u64 {
+    u64::from(read_u32(buffer, offset)) | u64::from(read_u32(buffer, offset + 4)) << 32
+}
+
+fn archive_error<P: AsRef<Path>>(path: P, description: &str) -> FileSystemError {
+    error!("Invalid ZIP archive {}: {} !", path.as_ref().display(), description);
+    FileSystemError::ArchiveError(format!("{}: {}.", path.as_ref().display(), description))
+}
+
+impl ZipArchive {
+    // Open the archive at path and read its central directory
+    pub fn open<P: AsRef<Path>>(path: P) -> FileSystemResult<ZipArchive> {
+        debug!("Opening the ZIP archive {}", path.as_ref().display());
+        let mut file = File::open(path.as_ref()).map_err(|error| FileSystemError::io(Operation::Open, &[path.as_ref()], error))?;
+        let archive_size = file.metadata()
+            .map_err(|error| FileSystemError::io(Operation::Metadata, &[path.as_ref()], error))?
+            .len();
+        let (entry_count, directory_offset, directory_size) = ZipArchive::find_central_directory(path.as_ref(), &mut file)?;
+        if directory_offset.checked_add(directory_size).is_none_or(|end| end > archive_size) {
+            return Err(archive_error(path.as_ref(), "the central directory is outside of the archive"));
+        }
+
+        trace!("Reading the {} entries of the central directory.", entry_count);
+        let mut directory = vec![0; directory_size as usize];
+        file.seek(SeekFrom::Start(directory_offset))?;
+        file.read_exact(directory.as_mut_slice())?;
+
+        let mut index = ZipIndex::default();
+        index.directories.insert(String::new());
+        let mut offset = 0;
+        for _ in 0..entry_count {
+            if offset + CENTRAL_HEADER_SIZE > directory.len() || read_u32(&directory, offset) != CENTRAL_HEADER_SIGNATURE {
+                return Err(archive_error(path.as_ref(), "corrupted central directory"));
+            }
+
+            let header = &directory[offset..];
+            let name_length = read_u16(header, 28) as usize;
+            let extra_length = read_u16(header, 30) as usize;
+            let comment_length = read_u16(header, 32) as usize;
+            if CENTRAL_HEADER_SIZE + name_length + extra_length > header.len() {
+                return Err(archive_error(path.as_ref(), "corrupted central directory"));
+            }
+
+            let mut entry = ZipEntry {
+                method: read_u16(header, 10),
+                flags: read_u16(header, 8),
+                crc32: read_u32(header, 16),
+                compressed_size: u64::from(read_u32(header, 20)),
+                uncompressed_size: u64::from(read_u32(header, 24)),
+                header_offset: u64::from(read_u32(header, 42)),
+            };
+            let name = String::from_utf8_lossy(&header[CENTRAL_HEADER_SIZE..CENTRAL_HEADER_SIZE + name_length]).into_owned();
+            let extra = &header[CENTRAL_HEADER_SIZE + name_length..CENTRAL_HEADER_SIZE + name_length + extra_length];
+            ZipArchive::read_zip64_extra_field(&mut entry, extra);
+            offset += CENTRAL_HEADER_SIZE + name_length + extra_length + comment_length;
+            ZipArchive::check_entry(path.as_ref(), name.as_str(), &entry, archive_size)?;
+
+            let is_directory = name.ends_with('/');
+            let name = match to_archive_path(name.replace('\\', "/")) {
+                Some(name) => name,
+                None => {
+                    warn!("Ignoring the entry {} of the ZIP archive {}, its path is invalid.", name, path.as_ref().display());
+                    continue;
+                },
+            };
+
+            let mut parent = if is_directory { name.as_str() } else { archive_parent(name.as_str()) };
+            while index.directories.insert(parent.to_string()) {
+                parent = archive_parent(parent);
+            }
+            if !is_directory {
+                index.files.insert(name, entry);
+            }
+        }
+
+        debug!("The ZIP archive {} contains {} files.", path.as_ref().display(), index.files.len());
+        Ok(ZipArchive {
+            path: path.as_ref().to_path_buf(),
+            index: Arc::new(index),
+        })
+    }
+
+    //Find the end of central directory record, and return the number of entries, the offset
+    //and the size of the central directory.
+    fn find_central_directory(path: &Path, file: &mut File) -> FileSystemResult<(u64, u64, u64)> {
+        let file_size = file.seek(SeekFrom::End(0))?;
+        if file_size < END_OF_CENTRAL_DIRECTORY_SIZE as u64 {
+            return Err(archive_error(path, "the file is too small to be a ZIP archive"));
+        }
+
+        let tail_size = ::std::cmp::min(file_size, END_OF_CENTRAL_DIRECTORY_SIZE as u64 + MAX_COMMENT_SIZE);
+        let tail_start = file_size - tail_size;
+        let mut tail = vec![0; tail_size as usize];
+        file.seek(SeekFrom::Start(tail_start))?;
+        file.read_exact(tail.as_mut_slice())?;
+
+        let record = (0..tail.len() - END_OF_CENTRAL_DIRECTORY_SIZE + 1)
+            .rev()
+            .find(|&offset| read_u32(&tail, offset) == END_OF_CENTRAL_DIRECTORY_SIGNATURE)
+            .ok_or_else(|| archive_error(path, "the end of central directory record could not be found"))?;
+
+        let entry_count = u64::from(read_u16(&tail, record + 10));
+        let directory_size = u64::from(read_u32(&tail, record + 12));
+        let directory_offset = u64::from(read_u32(&tail, record + 16));
+        if entry_count != 0xFFFF && directory_size != 0xFFFF_FFFF && directory_offset != 0xFFFF_FFFF {
+            return Ok((entry_count, directory_offset, directory_size));
+        }
+
+        trace!("The archive {} is a ZIP64 archive.", path.display());
+        let record_position = tail_start + record as u64;
+        if record_position < ZIP64_LOCATOR_SIZE {
+            return Err(archive_error(path, "the ZIP64 end of central directory locator is missing"));
+        }
+        let mut locator = [0; ZIP64_LOCATOR_SIZE as usize];
+        file.seek(SeekFrom::Start(record_position - ZIP64_LOCATOR_SIZE))?;
+        file.read_exact(&mut locator)?;
+        if read_u32(&locator, 0) != ZIP64_LOCATOR_SIGNATURE {
+            return Err(archive_error(path, "the ZIP64 end of central directory locator is missing"));
+        }
+
+        let mut record64 = [0; 56];
+        file.seek(SeekFrom::Start(read_u64(&locator, 8)))?;
+        file.read_exact(&mut record64)?;
+        if read_u32(&record64, 0) != ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE {
+            return Err(archive_error(path, "corrupted ZIP64 end of central directory record"));
+        }
+        Ok((read_u64(&record64, 32), read_u64(&record64, 48), read_u64(&record64, 40)))
+    }
+
+    //Check the sizes and the offset of the entry, before they are used to read it.
+    fn check_entry(path: &Path, name: &str, entry: &ZipEntry, archive_size: u64) -> FileSystemResult<()> {
+        let data_end = entry.header_offset
+            .checked_add(LOCAL_HEADER_SIZE)
+            .and_then(|end| end.checked_add(entry.compressed_size));
+        if data_end.is_none_or(|end| end > archive_size) {
+            return Err(archive_error(path, &format!("the entry {} is outside of the archive", name)));
+        }
+        if entry.method == METHOD_STORED && entry.uncompressed_size != entry.compressed_size {
+            return Err(archive_error(path, &format!("the sizes of the stored entry {} don't match", name)));
+        }
+        if entry.uncompressed_size > entry.compressed_size.saturating_mul(MAX_DEFLATE_RATIO) {
+            return Err(archive_error(path, &format!("the uncompressed size of {} is corrupted", name)));
+        }
+        Ok(())
+    }
+
+    //The real sizes and offset of big entries are stored in the ZIP64 extra field.
+    fn read_zip64_extra_field(entry: &mut ZipEntry, mut extra: &[u8]) {
+        while extra.len() >= 4 {
+            let id = read_u16(extra, 0);
+            let size = read_u16(extra, 2) as usize;
+            if extra.len() < 4 + size {
+                return;
+            }
+
+            if id == ZIP64_EXTRA_FIELD {
+                let mut field = &extra[4..4 + size];
+                {
+                    let mut next_value = |value: &mut u64, placeholder: u64| {
+                        if *value == placeholder && field.len() >= 8 {
+                            *value = read_u64(field, 0);
+                            field = &field[8..];
+                        }
+                    };
+                    next_value(&mut entry.uncompressed_size, 0xFFFF_FFFF);
+                    next_value(&mut entry.compressed_size, 0xFFFF_FFFF);
+                    next_value(&mut entry.header_offset, 0xFFFF_FFFF);
+                }
+                return;
+            }
+            extra = &extra[4 + size..];
+        }
+    }
+
+    // The path of the archive on the disk
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    // Check if a file or directory exists at the given path, relative to the root of the archive
+    pub fn exists<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.is_file(path.as_ref()) || self.is_dir(path.as_ref())
+    }
+
+    pub fn is_file<P: AsRef<Path>>(&self, path: P) -> bool {
+        match to_archive_path(path) {
+            Some(name) => self.index.files.contains_key(name.as_str()),
+            None => false,
+        }
+    }
+
+    pub fn is_dir<P: AsRef<Path>>(&self, path: P) -> bool {
+        match to_archive_path(path) {
+            Some(name) => self.index.directories.contains(name.as_str()),
+            None => false,
+        }
+    }
+
+    // The uncompressed size of the file at path
+    pub fn file_size<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<u64> {
+        Ok(self.entry(path.as_ref())?.uncompressed_size)
+    }
+
+    fn entry(&self, path: &Path) -> FileSystemResult<&ZipEntry> {
+        to_archive_path(path)
+            .and_then(|name| self.index.files.get(name.as_str()))
+            .ok_or_else(|| archive_error(self.path.as_path(), &format!("{} could not be found in the archive", path.display())))
+    }
+
+    // Open the file at path, relative to the root of the archive
+    pub fn open_file<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<FileReader> {
+        debug!("Opening {} in the ZIP archive {}", path.as_ref().display(), self.path.display());
+        let entry = self.entry(path.as_ref())?;
+        if entry.flags & FLAG_ENCRYPTED != 0 {
+            return Err(archive_error(self.path.as_path(), &format!("{} is encrypted", path.as_ref().display())));
+        }
+
+        let mut file = File::open(self.path.as_path())
+            .map_err(|error| FileSystemError::io(Operation::Open, &[self.path.as_path()], error))?;
+        let mut local_header = [0; LOCAL_HEADER_SIZE as usize];
+        file.seek(SeekFrom::Start(entry.header_offset))
+            .and_then(|_| file.read_exact(&mut local_header))
+            .map_err(|error| FileSystemError::io(Operation::Read, &[self.path.as_path()], error))?;
+        if read_u32(&local_header, 0) != LOCAL_HEADER_SIGNATURE {
+            return Err(archive_error(self.path.as_path(), &format!("the local header of {} is corrupted", path.as_ref().display())));
+        }
+        let data_offset = entry.header_offset
+            + LOCAL_HEADER_SIZE
+            + u64::from(read_u16(&local_header, 26))
+            + u64::from(read_u16(&local_header, 28));
+        let archive_size = file.metadata()
+            .map_err(|error| FileSystemError::io(Operation::Metadata, &[self.path.as_path()], error))?
+            .len();
+        if data_offset.checked_add(entry.compressed_size).is_none_or(|end| end > archive_size) {
+            return Err(archive_error(self.path.as_path(), &format!("{} is outside of the archive", path.as_ref().display())));
+        }
+        let crc_error = || archive_error(self.path.as_path(), &format!("the CRC-32 of {} doesn't match", path.as_ref().display()));
+
+        match entry.method {
+            METHOD_STORED => {
+                trace!("{} is stored, reading it from the archive.", path.as_ref().display());
+                let mut slice = FileSlice::new(file, data_offset, entry.compressed_size)?;
+                if slice.crc32()? != entry.crc32 {
+                    return Err(crc_error());
+                }
+                Ok(FileReader::Slice(slice))
+            },
+            METHOD_DEFLATED => {
+                trace!("{} is deflated, decompressing it in memory.", path.as_ref().display());
+                let compressed = FileSlice::new(file, data_offset, entry.compressed_size)?;
+                let mut content = Vec::new();
+                usize::try_from(entry.uncompressed_size)
+                    .ok()
+                    .and_then(|size| content.try_reserve_exact(size).ok())
+                    .ok_or_else(|| archive_error(self.path.as_path(), &format!("{} is too big to be loaded in memory", path.as_ref().display())))?;
+                DeflateDecoder::new(compressed).take(entry.uncompressed_size).read_to_end(&mut content)?;
+                if content.len() as u64 != entry.uncompressed_size || crc32fast::hash(content.as_slice()) != entry.crc32 {
+                    return Err(crc_error());
+                }
+                Ok(FileReader::Memory(Cursor::new(content)))
+            },
+            method => Err(archive_error(
+                self.path.as_path(),
+                &format!("{} uses the unsupported compression method {}", path.as_ref().display(), method),
+            )),
+        }
+    }
+
+    // List the files and directories in the directory at path, relative to the root of the archive.
+    // Returns the file names, and whether they are directories.
+    pub fn read_dir<P: AsRef<Path>>(&self, path: P) -> FileSystemResult<Vec<(OsString, bool)>> {
+        debug!("Listing {} in the ZIP archive {}", path.as_ref().display(), self.path.display());
+        let directory = match to_archive_path(path.as_ref()) {
+            Some(ref name) if self.index.directories.contains(name.as_str()) => name.clone(),
+            _ => return Err(archive_error(self.path.as_path(), &format!("{} is not a directory of the archive", path.as_ref().display()))),
+        };
+
+        let children = |name: &&String| !name.is_empty() && archive_parent(name.as_str()) == directory.as_str();
+        let file_name = |name: &String| OsString::from(&name[name.rfind('/').map(|index| index + 1).unwrap_or(0)..]);
+        let mut entries: Vec<(OsString, bool)> = self.index.directories
+            .iter()
+            .filter(&children)
+            .map(|name| (file_name(name), true))
+            .collect();
+        entries.extend(self.index.files
+            .keys()
+            .filter(&children)
+            .map(|name| (file_name(name), false)));
+        Ok(entries)
+    }
+}
+
+impl Backend for ZipArchive {
+    fn display_path(&self, path: &Path) -> PathBuf {
+        self.path.join(path)
+    }
+
+    fn absolute_path(&self, path: &Path) -> FileSystemResult<PathBuf> {
+        fs::canonicalize(self.path.as_path())
+            .map(|archive| archive.join(path))
+            .map_err(|error| FileSystemError::io(Operation::Canonicalize, &[self.path.as_path()], error))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        ZipArchive::exists(self, path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        ZipArchive::is_dir(self, path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        ZipArchive::is_file(self, path)
+    }
+
+    fn metadata(&self, path: &Path) -> FileSystemResult<Metadata> {
+        if ZipArchive::is_dir(self, path) {
+            return Ok(Metadata::new(0, true, None, true));
+        }
+        Ok(Metadata::new(self.file_size(path)?, false, None, true))
+    }
+
+    fn open(&self, path: &Path) -> FileSystemResult<Box<dyn ReadSeek>> {
+        match self.open_file(path) {
+            Ok(reader) => Ok(Box::new(reader)),
+            Err(error) => Err(error.with_operation(Operation::Open).with_path(self.path.as_path())),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> FileSystemResult<Vec<DirEntry>> {
+        Ok(ZipArchive::read_dir(self, path)?
+            .into_iter()
+            .map(|(file_name, is_dir)| DirEntry::new(path, file_name, is_dir))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+pub mod zip_archive_test {
+    use super::*;
+    use std::io::{self, Write};
+    use filesystem_error::ErrorKind;
+    use flate2::Compression;
+    use flate2::write::DeflateEncoder;
+    use tempfile;
+
+    fn push_u16(buffer: &mut Vec<u8>, value: u16) {
+        buffer.extend_from_slice(&[value as u8, (value >> 8) as u8]);
+    }
+
+    fn push_u32(buffer: &mut Vec<u8>, value: u32) {
+        push_u16(buffer, value as u16);
+        push_u16(buffer, (value >> 16) as u16);
+    }
+
+    //Write a ZIP archive with the given (name, content, deflate) entries.
+    pub fn write_zip<P: AsRef<Path>>(path: P, entries: &[(&str, &[u8], bool)]) {
+        let mut archive = Vec::new();
+        let mut directory = Vec::new();
+
+        for &(name, content, deflate) in entries {
+            let data = if deflate {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(content).unwrap();
+                encoder.finish().unwrap()
+            } else {
+                content.to_vec()
+            };
+            let method = if deflate { METHOD_DEFLATED } else { METHOD_STORED };
+            let crc = crc32fast::hash(content);
+            let offset = archive.len() as u32;
+
+            push_u32(&mut archive, LOCAL_HEADER_SIGNATURE);
+            for &value in &[20, 0, method, 0, 0] {
+                push_u16(&mut archive, value);
+            }
+            for &value in &[crc, data.len() as u32, content.len() as u32] {
+                push_u32(&mut archive, value);
+            }
+            push_u16(&mut archive, name.len() as u16);
+            push_u16(&mut archive, 0);
+            archive.extend_from_slice(name.as_bytes());
+            archive.extend_from_slice(data.as_slice());
+
+            push_u32(&mut directory, CENTRAL_HEADER_SIGNATURE);
+            for &value in &[20, 20, 0, method, 0, 0] {
+                push_u16(&mut directory, value);
+            }
+            for &value in &[crc, data.len() as u32, content.len() as u32] {
+                push_u32(&mut directory, value);
+            }
+            for &value in &[name.len() as u16, 0, 0, 0, 0] {
+                push_u16(&mut directory, value);
+            }
+            push_u32(&mut directory, 0);
+            push_u32(&mut directory, offset);
+            directory.extend_from_slice(name.as_bytes());
+        }
+
+        let directory_offset = archive.len() as u32;
+        archive.extend_from_slice(directory.as_slice());
+        push_u32(&mut archive, END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        for &value in &[0, 0, entries.len() as u16, entries.len() as u16] {
+            push_u16(&mut archive, value);
+        }
+        push_u32(&mut archive, directory.len() as u32);
+        push_u32(&mut archive, directory_offset);
+        push_u16(&mut archive, 0);
+
+        File::create(path).unwrap().write_all(archive.as_slice()).unwrap();
+    }
+
+    #[test]
+    fn zip_archive_read_entries() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("data.zip");
+        write_zip(path.as_path(), &[
+            ("textures/", b"", false),
+            ("textures/hero.png", b"stored hero", false),
+            ("sounds/music/theme.ogg", b"deflated theme deflated theme deflated theme", true),
+            ("readme.txt", b"", true),
+        ]);
+
+        let archive = ZipArchive::open(path.as_path()).unwrap();
+        assert!(archive.is_file("textures/hero.png"));
+        assert!(archive.is_dir("textures"));
+        assert!(archive.is_dir("sounds/music"));
+        assert!(archive.is_dir(""));
+        assert!(!archive.exists("textures/villain.png"));
+        assert_eq!(archive.file_size("sounds/music/theme.ogg").unwrap(), 44);
+
+        let mut content = String::new();
+        let mut reader = archive.open_file("textures/hero.png").unwrap();
+        reader.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "stored hero");
+
+        //The stored entries are slices of the archive: their positions must stay in the archive.
+        assert_eq!(reader.seek(SeekFrom::Start(u64::MAX)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(reader.stream_position().unwrap(), 11);
+        assert_eq!(reader.seek(SeekFrom::Start(7)).unwrap(), 7);
+        content.clear();
+        reader.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hero");
+
+        let mut reader = archive.open_file("sounds/music/theme.ogg").unwrap();
+        reader.seek(SeekFrom::Start(9)).unwrap();
+        content.clear();
+        reader.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "theme deflated theme deflated theme");
+
+        content.clear();
+        archive.open_file("readme.txt").unwrap().read_to_string(&mut content).unwrap();
+        assert!(content.is_empty());
+
+        let mut root = archive.read_dir("").unwrap();
+        root.sort();
+        assert_eq!(root, vec![
+            (OsString::from("readme.txt"), false),
+            (OsString::from("sounds"), true),
+            (OsString::from("textures"), true),
+        ]);
+        assert!(archive.read_dir("readme.txt").is_err());
+        assert!(archive.open_file("textures").is_err());
+    }
+
+    #[test]
+    fn zip_archive_invalid() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("invalid.zip");
+        File::create(path.as_path()).unwrap().write_all(b"this is not a zip archive").unwrap();
+        assert!(ZipArchive::open(path.as_path()).is_err());
+
+        let missing = temp.path().join("missing.zip");
+        let error = ZipArchive::open(missing.as_path()).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        assert_eq!(error.context().unwrap().operation(), Some(Operation::Open));
+        assert_eq!(error.context().unwrap().paths(), &[missing]);
+    }
+
+    //Write an archive with a single stored entry, and patch the little endian value at offset.
+    //Negative offsets start from the end of the archive.
+    fn corrupted_zip(path: &Path, offset: isize, value: u32) {
+        write_zip(path, &[("textures/hero.png", b"stored hero", false)]);
+        let mut archive = fs::read(path).unwrap();
+        let offset = if offset < 0 { archive.len() - offset.unsigned_abs() } else { offset as usize };
+        archive[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        fs::write(path, archive).unwrap();
+    }
+
+    fn assert_archive_error<T: ::std::fmt::Debug>(result: FileSystemResult<T>) {
+        match result {
+            Err(FileSystemError::ArchiveError(_)) => {},
+            result => panic!("The corrupted archive should be rejected: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn zip_archive_corrupted() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("corrupted.zip");
+        //The entry is a 30 bytes local header, a 17 bytes name and 11 bytes of data, the central directory follows.
+        let directory_offset = 30 + 17 + 11;
+
+        //The size of the central directory, in the end of central directory record.
+        corrupted_zip(path.as_path(), -10, 0xFFFF_FFF0);
+        assert_archive_error(ZipArchive::open(path.as_path()));
+        //The uncompressed size of the entry, in the central directory.
+        corrupted_zip(path.as_path(), directory_offset + 24, 0xFFFF_FFF0);
+        assert_archive_error(ZipArchive::open(path.as_path()));
+        //The compressed size of the entry.
+        corrupted_zip(path.as_path(), directory_offset + 20, 0xFFFF_FFF0);
+        assert_archive_error(ZipArchive::open(path.as_path()));
+
+        //The data of a stored entry is checked against its CRC-32.
+        corrupted_zip(path.as_path(), 30 + 17, u32::from_le_bytes(*b"STOR"));
+        let archive = ZipArchive::open(path.as_path()).unwrap();
+        assert_archive_error(archive.open_file("textures/hero.png"));
+    }
+}
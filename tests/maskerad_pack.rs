@@ -0,0 +1,104 @@
+// Copyright 2017-2018 Maskerad Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+extern crate tempfile;
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+//Run maskerad-pack with the arguments.
+fn maskerad_pack(arguments: &[&Path]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_maskerad-pack"))
+        .args(arguments)
+        .output()
+        .expect("Couldn't run maskerad-pack")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(output.stdout.as_slice()).into_owned()
+}
+
+#[test]
+fn maskerad_pack_round_trip() {
+    let temp = tempfile::tempdir().unwrap();
+    let source = temp.path().join("source");
+    fs::create_dir_all(source.join("levels")).unwrap();
+    fs::write(source.join("levels/one.lvl"), vec![b'a'; 10_000]).unwrap();
+    fs::write(source.join("readme.txt"), "readme").unwrap();
+    let pack = temp.path().join("data.mkp");
+
+    let output = maskerad_pack(&[Path::new("create"), source.as_path(), pack.as_path(), Path::new("--compress")]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("with 2 files"));
+
+    let output = maskerad_pack(&[Path::new("list"), pack.as_path()]);
+    assert!(output.status.success());
+    let listing = stdout(&output);
+    assert!(listing.contains("levels/one.lvl"));
+    assert!(listing.contains("readme.txt"));
+
+    let output = maskerad_pack(&[Path::new("verify"), pack.as_path()]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("2 files checked, 0 corrupted, 0 skipped."));
+
+    let extracted = temp.path().join("extracted");
+    let output = maskerad_pack(&[Path::new("extract"), pack.as_path(), extracted.as_path()]);
+    assert!(output.status.success());
+    assert_eq!(fs::read(extracted.join("levels/one.lvl")).unwrap(), vec![b'a'; 10_000]);
+    assert_eq!(fs::read(extracted.join("readme.txt")).unwrap(), b"readme");
+
+    let single = temp.path().join("single");
+    let output = maskerad_pack(&[Path::new("extract"), pack.as_path(), single.as_path(), Path::new("readme.txt")]);
+    assert!(output.status.success());
+    assert!(single.join("readme.txt").is_file());
+    assert!(!single.join("levels").exists());
+
+    let output = maskerad_pack(&[Path::new("extract"), pack.as_path(), single.as_path(), Path::new("missing.txt")]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(maskerad_pack(&[Path::new("unknown")]).status.code(), Some(2));
+}
+
+#[test]
+fn maskerad_pack_corrupted() {
+    let temp = tempfile::tempdir().unwrap();
+    let source = temp.path().join("source");
+    fs::create_dir_all(source.as_path()).unwrap();
+    fs::write(source.join("readme.txt"), "readme").unwrap();
+    let pack = temp.path().join("data.mkp");
+    assert!(maskerad_pack(&[Path::new("create"), source.as_path(), pack.as_path()]).status.success());
+    let original = fs::read(pack.as_path()).unwrap();
+
+    //The content of the entry, at 4096.
+    let mut bytes = original.clone();
+    bytes[4096] ^= 0xFF;
+    fs::write(pack.as_path(), bytes).unwrap();
+    let output = maskerad_pack(&[Path::new("verify"), pack.as_path()]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).contains("CORRUPTED readme.txt"));
+
+    //The stored size of the entry, in the table of contents following the entry.
+    let mut bytes = original.clone();
+    let stored_size = 4096 + 6 + 16;
+    bytes[stored_size..stored_size + 8].copy_from_slice(&0xFFFF_FFFF_FFFFu64.to_le_bytes());
+    fs::write(pack.as_path(), bytes).unwrap();
+    for command in &["verify", "list"] {
+        let output = maskerad_pack(&[Path::new(command), pack.as_path()]);
+        assert_eq!(output.status.code(), Some(1));
+        assert!(String::from_utf8_lossy(output.stderr.as_slice()).contains("outside of the pack"));
+    }
+
+    //The size of the table of contents, in the header.
+    let mut bytes = original.clone();
+    bytes[24..32].copy_from_slice(&0xFFFF_FFFF_FFFFu64.to_le_bytes());
+    fs::write(pack.as_path(), bytes).unwrap();
+    assert_eq!(maskerad_pack(&[Path::new("list"), pack.as_path()]).status.code(), Some(1));
+
+    //A truncated pack.
+    fs::write(pack.as_path(), &original[..100]).unwrap();
+    assert_eq!(maskerad_pack(&[Path::new("verify"), pack.as_path()]).status.code(), Some(1));
+}